@@ -0,0 +1,117 @@
+//! スレッド方式とイベントループ方式のスループット比較。
+//!
+//! Compares the thread-per-connection server with the event loop.
+//! Run with `cargo bench -p example_server --bench io_modes`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use example_server::http::{Handler, Request, Response};
+use example_server::{event_loop, server, ThreadPool};
+
+const REQUESTS_PER_CLIENT: usize = 500;
+const IDLE_CONNECTIONS: usize = 256;
+
+fn main() {
+    let handler: Handler = Arc::new(|_: &Request| Response::new(200).with_body("hello"));
+
+    let threaded = TcpListener::bind("127.0.0.1:0").unwrap();
+    let threaded_addr = threaded.local_addr().unwrap();
+    let threaded_handler = Arc::clone(&handler);
+    thread::spawn(move || {
+        let pool = Arc::new(ThreadPool::new(4));
+        server::serve(threaded, pool, threaded_handler);
+    });
+
+    let evented = TcpListener::bind("127.0.0.1:0").unwrap();
+    let evented_addr = evented.local_addr().unwrap();
+    thread::spawn(move || {
+        let pool = Arc::new(ThreadPool::new(4));
+        event_loop::serve(evented, pool, handler, 2).unwrap();
+    });
+
+    println!("{:<40} {:>12}", "scenario", "req/s");
+    for &clients in &[8, 64] {
+        let rate = load(threaded_addr, clients, false);
+        println!(
+            "{:<40} {:>12.0}",
+            format!("threaded, {} clients", clients),
+            rate
+        );
+        let rate = load(evented_addr, clients, true);
+        println!(
+            "{:<40} {:>12.0}",
+            format!("event loop, {} clients", clients),
+            rate
+        );
+    }
+
+    // 待機中の接続はスレッド方式ではワーカーを占有して止まるため、イベントループのみ計測する
+    let idle: Vec<TcpStream> = (0..IDLE_CONNECTIONS)
+        .map(|_| TcpStream::connect(evented_addr).unwrap())
+        .collect();
+    let rate = load(evented_addr, 8, true);
+    println!(
+        "{:<40} {:>12.0}",
+        format!("event loop, 8 clients + {} idle", idle.len()),
+        rate
+    );
+}
+
+/// `clients`本のクライアントを並列に走らせ、秒間リクエスト数を返す。
+fn load(addr: SocketAddr, clients: usize, keep_alive: bool) -> f64 {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            thread::spawn(move || {
+                if keep_alive {
+                    let stream = TcpStream::connect(addr).unwrap();
+                    let mut reader = BufReader::new(stream);
+                    for _ in 0..REQUESTS_PER_CLIENT {
+                        reader
+                            .get_mut()
+                            .write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n")
+                            .unwrap();
+                        read_response(&mut reader);
+                    }
+                } else {
+                    for _ in 0..REQUESTS_PER_CLIENT {
+                        let stream = TcpStream::connect(addr).unwrap();
+                        let mut reader = BufReader::new(stream);
+                        reader
+                            .get_mut()
+                            .write_all(
+                                b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n",
+                            )
+                            .unwrap();
+                        read_response(&mut reader);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed().max(Duration::from_millis(1));
+    (clients * REQUESTS_PER_CLIENT) as f64 / elapsed.as_secs_f64()
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length: ") {
+            length = v.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+}
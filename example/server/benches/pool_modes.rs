@@ -0,0 +1,91 @@
+//! 共有キュー方式とワークスティーリング方式のスループット比較。
+//!
+//! Compares the shared-queue pool with the work-stealing pool on many tiny
+//! jobs. Run with `cargo bench -p example_server --bench pool_modes`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use example_server::ThreadPool;
+
+const JOBS: usize = 1_000_000;
+const FAN_OUT: usize = 1_000;
+
+fn main() {
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("{} workers", workers);
+    println!("{:<36} {:>14}", "scenario", "jobs/s");
+    for &stealing in &[false, true] {
+        let mode = if stealing {
+            "work-stealing"
+        } else {
+            "shared queue"
+        };
+
+        let pool = build(workers, stealing);
+        let rate = from_outside(&pool);
+        println!(
+            "{:<36} {:>14.0}",
+            format!("{}, submitted outside", mode),
+            rate
+        );
+
+        let pool = Arc::new(build(workers, stealing));
+        let rate = from_workers(&pool);
+        println!(
+            "{:<36} {:>14.0}",
+            format!("{}, submitted by workers", mode),
+            rate
+        );
+    }
+}
+
+fn build(workers: usize, stealing: bool) -> ThreadPool {
+    ThreadPool::builder()
+        .min_workers(workers)
+        .max_workers(workers)
+        .work_stealing(stealing)
+        .build()
+}
+
+/// メインスレッドから小さなジョブを大量に投入する。
+fn from_outside(pool: &ThreadPool) -> f64 {
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        let done = Arc::clone(&done);
+        pool.execute(move || {
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    wait(&done, JOBS);
+    JOBS as f64 / start.elapsed().as_secs_f64()
+}
+
+/// 親ジョブがワーカーの中から子ジョブを投入する。
+fn from_workers(pool: &Arc<ThreadPool>) -> f64 {
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..JOBS / FAN_OUT {
+        let inner_pool = Arc::clone(pool);
+        let done = Arc::clone(&done);
+        pool.execute(move || {
+            for _ in 0..FAN_OUT {
+                let done = Arc::clone(&done);
+                inner_pool.execute(move || {
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+    }
+    wait(&done, JOBS);
+    JOBS as f64 / start.elapsed().as_secs_f64()
+}
+
+fn wait(done: &AtomicUsize, target: usize) {
+    while done.load(Ordering::Relaxed) < target {
+        thread::yield_now();
+    }
+}
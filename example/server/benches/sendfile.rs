@@ -0,0 +1,113 @@
+//! 大きなファイルの配信で、`sendfile`とバッファ経由のコピーを比べる。
+//!
+//! Compares serving a large static file with `sendfile` against reading it
+//! into memory first, on both I/O modes.
+//! Run with `cargo bench -p example_server --bench sendfile`.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use example_server::http::{Handler, Request, Response};
+use example_server::static_files::StaticFiles;
+use example_server::{event_loop, server, ThreadPool};
+
+const FILE_SIZE: usize = 32 * 1024 * 1024;
+const CLIENTS: usize = 4;
+const DOWNLOADS_PER_CLIENT: usize = 8;
+
+fn main() {
+    let root = env::temp_dir().join(format!("sendfile-bench-{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("big.bin"), vec![0x5a; FILE_SIZE]).unwrap();
+
+    println!("{:<36} {:>12}", "scenario", "MiB/s");
+    for &zero_copy in &[false, true] {
+        let mode = if zero_copy { "sendfile" } else { "buffered" };
+        let addr = start(&root, zero_copy, false);
+        println!(
+            "{:<36} {:>12.0}",
+            format!("threaded, {}", mode),
+            load(addr, false)
+        );
+        let addr = start(&root, zero_copy, true);
+        println!(
+            "{:<36} {:>12.0}",
+            format!("event loop, {}", mode),
+            load(addr, true)
+        );
+    }
+    fs::remove_dir_all(root).unwrap();
+}
+
+fn start(root: &Path, zero_copy: bool, evented: bool) -> SocketAddr {
+    let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+    let threshold = if zero_copy { Some(0) } else { None };
+    let handler = StaticFiles::new()
+        .mount("/", root)
+        .sendfile_threshold(threshold)
+        .wrap(not_found);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        if evented {
+            let pool = Arc::new(ThreadPool::new(4));
+            event_loop::serve(listener, pool, handler, 2).unwrap();
+        } else {
+            let pool = Arc::new(ThreadPool::new(4));
+            server::serve(listener, pool, handler);
+        }
+    });
+    addr
+}
+
+/// 並列にダウンロードし、MiB/sを返す。
+fn load(addr: SocketAddr, keep_alive: bool) -> f64 {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut reader = None;
+                for _ in 0..DOWNLOADS_PER_CLIENT {
+                    if !keep_alive || reader.is_none() {
+                        reader = Some(BufReader::new(TcpStream::connect(addr).unwrap()));
+                    }
+                    let reader = reader.as_mut().unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"GET /big.bin HTTP/1.1\r\nHost: bench\r\n\r\n")
+                        .unwrap();
+                    assert_eq!(read_response(reader), FILE_SIZE);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed().max(Duration::from_millis(1));
+    let bytes = (CLIENTS * DOWNLOADS_PER_CLIENT * FILE_SIZE) as f64;
+    bytes / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+/// ボディは捨てながら読み、その長さを返す。
+fn read_response(reader: &mut BufReader<TcpStream>) -> usize {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length: ") {
+            length = v.trim().parse().unwrap();
+        }
+    }
+    io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink()).unwrap() as usize
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# CGIの動作確認用: 受け取った主な環境変数を返す
+printf 'Content-Type: text/plain; charset=utf-8\r\n\r\n'
+echo "method: $REQUEST_METHOD"
+echo "script: $SCRIPT_NAME"
+echo "path info: $PATH_INFO"
+echo "query: $QUERY_STRING"
+echo "remote: $REMOTE_ADDR"
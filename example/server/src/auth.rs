@@ -0,0 +1,360 @@
+//! 経路ごとのBasic認証とBearerトークン認証。
+//!
+//! Basic認証はhtpasswd形式のファイル(bcryptまたはargon2のハッシュ)で、
+//! Bearer認証は固定のトークンで検証します。秘密の比較はすべて一定時間で
+//! 行います。
+//!
+//! Route-level Basic and bearer-token authentication. Basic credentials are
+//! checked against an htpasswd-style file of bcrypt or argon2 hashes, bearer
+//! tokens against a fixed list. Secrets are always compared in constant time.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::password_hash::PasswordHash;
+use argon2::{Argon2, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::http::{path_has_prefix, Handler, Request, Response};
+
+/// htpasswd形式のユーザー一覧。1行に`ユーザー名:ハッシュ`を書きます。
+///
+/// Users from an htpasswd-style file: one `user:hash` per line, where the
+/// hash is bcrypt (`$2y$`, `$2b$`, `$2a$`) or argon2 (`$argon2id$`, ...).
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    /// 存在しないユーザーでも同じ時間をかけて照合するためのハッシュ
+    decoy: Option<String>,
+}
+
+impl Htpasswd {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Htpasswd> {
+        Htpasswd::parse(&fs::read_to_string(path)?)
+    }
+
+    /// 空行と`#`で始まる行は無視します。対応していないハッシュ形式はエラーです。
+    ///
+    /// Blank lines and `#` comments are skipped. Unsupported hash schemes
+    /// (MD5, SHA-1, crypt) are rejected rather than silently never matching.
+    pub fn parse(text: &str) -> io::Result<Htpasswd> {
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("htpasswd line {}: {}", number + 1, msg),
+                )
+            };
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected user:hash"))?;
+            if !is_bcrypt(hash) && PasswordHash::new(hash).is_err() {
+                return Err(invalid("unsupported hash, use bcrypt or argon2"));
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        let decoy = users.values().next().cloned();
+        Ok(Htpasswd { users, decoy })
+    }
+
+    /// ユーザー名とパスワードを照合する。
+    ///
+    /// Check a user name and password.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => verify_hash(hash, password),
+            None => {
+                // ユーザーの有無が応答時間から分からないよう、照合だけは行う
+                if let Some(decoy) = &self.decoy {
+                    verify_hash(decoy, password);
+                }
+                false
+            }
+        }
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2y$", "$2b$", "$2a$"].iter().any(|p| hash.starts_with(p))
+}
+
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if is_bcrypt(hash) {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// 処理時間から何も漏らさずにバイト列を比べる。長さも漏れないよう、
+/// 同じ長さのダイジェストどうしを比べます。
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(&b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 経路ごとの認証。`wrap`でハンドラに被せて使います。
+///
+/// Authentication for a set of routes, applied by wrapping a handler with
+/// `wrap`. With no routes added, every path is protected.
+pub struct Auth {
+    realm: String,
+    htpasswd: Option<Htpasswd>,
+    tokens: Vec<String>,
+    routes: Vec<String>,
+}
+
+enum Failure {
+    Missing,
+    InvalidToken,
+    InvalidCredentials,
+}
+
+impl Auth {
+    pub fn new(realm: &str) -> Auth {
+        Auth {
+            realm: realm.to_string(),
+            htpasswd: None,
+            tokens: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Basic認証を`htpasswd`のユーザーで受け付ける。
+    ///
+    /// Accept Basic credentials for the users in `htpasswd`.
+    pub fn basic(mut self, htpasswd: Htpasswd) -> Auth {
+        self.htpasswd = Some(htpasswd);
+        self
+    }
+
+    /// `Authorization: Bearer <token>`で受け付けるトークンを追加する。
+    ///
+    /// Accept `Authorization: Bearer <token>` with this token.
+    pub fn bearer_token(mut self, token: &str) -> Auth {
+        self.tokens.push(token.to_string());
+        self
+    }
+
+    /// `prefix`配下のパスを保護する。
+    ///
+    /// Protect paths under `prefix`.
+    pub fn route(mut self, prefix: &str) -> Auth {
+        self.routes.push(prefix.to_string());
+        self
+    }
+
+    /// 認証できないリクエストには`401 Unauthorized`を返すハンドラにする。
+    /// 資格情報を1つも設定していなければ、保護した経路は`403 Forbidden`です。
+    ///
+    /// Wrap `handler` so unauthenticated requests to protected routes get
+    /// `401 Unauthorized` with a `WWW-Authenticate` challenge. With neither
+    /// `basic` nor `bearer_token` configured, nobody could ever pass, so
+    /// protected routes answer `403 Forbidden` instead.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let auth = Arc::new(self);
+        let configured = auth.htpasswd.is_some() || !auth.tokens.is_empty();
+        if !configured {
+            eprintln!(
+                "auth: no credentials for realm {:?}, its routes will answer 403",
+                auth.realm
+            );
+        }
+        Arc::new(move |request: &Request| {
+            if !auth.protects(&request.path) {
+                return handler(request);
+            }
+            if !configured {
+                return Response::new(403)
+                    .with_header("Content-Type", "text/plain; charset=utf-8")
+                    .with_body("Forbidden\n");
+            }
+            match auth.check(request) {
+                Ok(()) => handler(request),
+                Err(failure) => auth.challenge(failure),
+            }
+        })
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|p| path_has_prefix(path, p))
+    }
+
+    fn check(&self, request: &Request) -> Result<(), Failure> {
+        let header = request.header("Authorization").ok_or(Failure::Missing)?;
+        let (scheme, credentials) = header.split_once(' ').unwrap_or((header, ""));
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") && !self.tokens.is_empty() {
+            // 一致した時点で打ち切らず、すべてのトークンと比べる
+            let matched = self.tokens.iter().fold(false, |matched, token| {
+                matched | constant_time_eq(token.as_bytes(), credentials.as_bytes())
+            });
+            return if matched {
+                Ok(())
+            } else {
+                Err(Failure::InvalidToken)
+            };
+        }
+        if scheme.eq_ignore_ascii_case("Basic") {
+            if let Some(htpasswd) = &self.htpasswd {
+                let decoded = STANDARD
+                    .decode(credentials)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or(Failure::InvalidCredentials)?;
+                let (user, password) =
+                    decoded.split_once(':').ok_or(Failure::InvalidCredentials)?;
+                return if htpasswd.verify(user, password) {
+                    Ok(())
+                } else {
+                    Err(Failure::InvalidCredentials)
+                };
+            }
+        }
+        Err(Failure::Missing)
+    }
+
+    fn challenge(&self, failure: Failure) -> Response {
+        let mut response = Response::new(401)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body("Unauthorized\n");
+        if self.htpasswd.is_some() {
+            response = response.with_header(
+                "WWW-Authenticate",
+                &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            );
+        }
+        if !self.tokens.is_empty() {
+            let challenge = match failure {
+                Failure::InvalidToken => {
+                    format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm)
+                }
+                Failure::Missing | Failure::InvalidCredentials => {
+                    format!("Bearer realm=\"{}\"", self.realm)
+                }
+            };
+            response = response.with_header("WWW-Authenticate", &challenge);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(value) = authorization {
+            raw.push_str(&format!("Authorization: {}\r\n", value));
+        }
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn htpasswd_accepts_bcrypt_and_argon2() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let text = format!(
+            "# users\nalice:{}\n\nbob:{}\n",
+            bcrypt::hash("secret", 4).unwrap(),
+            argon
+        );
+        let htpasswd = Htpasswd::parse(&text).unwrap();
+        assert!(htpasswd.verify("alice", "secret"));
+        assert!(!htpasswd.verify("alice", "hunter2"));
+        assert!(htpasswd.verify("bob", "hunter2"));
+        assert!(!htpasswd.verify("carol", "secret"));
+
+        let err = Htpasswd::parse("old:$apr1$abc$def").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn protected_routes_require_credentials() {
+        let htpasswd =
+            Htpasswd::parse(&format!("alice:{}", bcrypt::hash("secret", 4).unwrap())).unwrap();
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200));
+        let handler = Auth::new("admin")
+            .basic(htpasswd)
+            .bearer_token("t0ken")
+            .route("/admin")
+            .wrap(handler);
+
+        assert_eq!(handler(&request("/", None)).status, 200);
+        let denied = handler(&request("/admin/users", None));
+        assert_eq!(denied.status, 401);
+        let challenges: Vec<_> = denied
+            .headers
+            .iter()
+            .filter(|(n, _)| n == "WWW-Authenticate")
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(
+            challenges,
+            vec![
+                "Basic realm=\"admin\", charset=\"UTF-8\"",
+                "Bearer realm=\"admin\""
+            ]
+        );
+
+        let ok = basic("alice", "secret");
+        assert_eq!(handler(&request("/admin", Some(&ok))).status, 200);
+        let wrong = basic("alice", "nope");
+        assert_eq!(handler(&request("/admin", Some(&wrong))).status, 401);
+        assert_eq!(handler(&request("/admin", Some("Basic !!!"))).status, 401);
+        assert_eq!(
+            handler(&request("/admin", Some("Bearer t0ken"))).status,
+            200
+        );
+        let bad_token = handler(&request("/admin", Some("Bearer t0kem")));
+        assert_eq!(bad_token.status, 401);
+        assert!(bad_token
+            .headers
+            .iter()
+            .any(|(_, v)| v.contains("error=\"invalid_token\"")));
+    }
+
+    #[test]
+    fn routes_without_credentials_are_forbidden() {
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200));
+        let handler = Auth::new("admin").route("/admin").wrap(handler);
+        assert_eq!(handler(&request("/", None)).status, 200);
+        let denied = handler(&request("/admin", Some("Bearer anything")));
+        assert_eq!(denied.status, 403);
+        assert!(denied.header("WWW-Authenticate").is_none());
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
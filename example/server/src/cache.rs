@@ -0,0 +1,254 @@
+//! ファイル内容のメモリキャッシュ。
+//!
+//! 合計サイズに上限を設け、超えたら最も長く使われていないものから捨てます
+//! (LRU)。取り出すたびに更新時刻とサイズを確かめるので、ディスク上で
+//! 書き換えられたファイルが古いまま返ることはありません。
+//!
+//! An in-memory cache of file contents, bounded by total size with
+//! least-recently-used eviction. Every lookup revalidates the file's
+//! modification time and length, so edited files are never served stale.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// キャッシュの統計。
+///
+/// Cache statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// キャッシュから返した回数。 / Lookups served from memory.
+    pub hits: u64,
+    /// ディスクから読んだ回数。 / Lookups that read the file.
+    pub misses: u64,
+    /// 容量を空けるために捨てた回数。 / Entries evicted to make room.
+    pub evictions: u64,
+    /// 保持しているファイル数。 / Files currently held.
+    pub entries: usize,
+    /// 保持している合計バイト数。 / Bytes currently held.
+    pub bytes: usize,
+}
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    modified: Option<SystemTime>,
+    /// 最後に使った順番。`order`のキー
+    tick: u64,
+}
+
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// 使った順番 → パス。先頭が最も古い
+    order: BTreeMap<u64, PathBuf>,
+    next_tick: u64,
+    stats: CacheStats,
+}
+
+impl State {
+    fn touch(&mut self, path: &Path) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let entry = self.entries.get_mut(path).unwrap();
+        self.order.remove(&entry.tick);
+        entry.tick = tick;
+        self.order.insert(tick, path.to_path_buf());
+    }
+
+    fn remove(&mut self, path: &Path) -> bool {
+        match self.entries.remove(path) {
+            Some(entry) => {
+                self.order.remove(&entry.tick);
+                self.stats.bytes -= entry.data.len();
+                self.stats.entries -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// パスをキーにしたLRUのファイルキャッシュ。スレッド間で共有できます。
+///
+/// An LRU file cache keyed by path, safe to share between threads.
+pub struct FileCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl FileCache {
+    /// 合計`capacity`バイトまで保持する。これより大きいファイルは保持しません。
+    ///
+    /// Hold up to `capacity` bytes in total. Larger files are read but never
+    /// cached.
+    pub fn new(capacity: usize) -> FileCache {
+        FileCache {
+            capacity,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// ファイルの内容を返す。キャッシュが古ければ読み直します。
+    ///
+    /// Return the file's contents, rereading it if the cached copy is stale.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> io::Result<Arc<Vec<u8>>> {
+        let path = path.as_ref();
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.invalidate(path);
+                return Err(e);
+            }
+        };
+        let modified = metadata.modified().ok();
+        {
+            let mut state = self.state.lock().unwrap();
+            let fresh = match state.entries.get(path) {
+                Some(entry) => {
+                    entry.modified.is_some()
+                        && entry.modified == modified
+                        && entry.data.len() as u64 == metadata.len()
+                }
+                None => false,
+            };
+            if fresh {
+                state.touch(path);
+                state.stats.hits += 1;
+                return Ok(Arc::clone(&state.entries[path].data));
+            }
+            state.stats.misses += 1;
+        }
+
+        // 読み込みはロックの外で行う
+        let data = Arc::new(fs::read(path)?);
+        let mut state = self.state.lock().unwrap();
+        state.remove(path);
+        if data.len() <= self.capacity {
+            while state.stats.bytes + data.len() > self.capacity {
+                let (_, oldest) = state.order.pop_first().unwrap();
+                let entry = state.entries.remove(&oldest).unwrap();
+                state.stats.bytes -= entry.data.len();
+                state.stats.entries -= 1;
+                state.stats.evictions += 1;
+            }
+            state.entries.insert(
+                path.to_path_buf(),
+                Entry {
+                    data: Arc::clone(&data),
+                    modified,
+                    tick: 0,
+                },
+            );
+            state.stats.bytes += data.len();
+            state.stats.entries += 1;
+            state.touch(path);
+        }
+        Ok(data)
+    }
+
+    /// `path`のキャッシュを捨てる。捨てたものがあれば`true`を返します。
+    ///
+    /// Drop `path` from the cache, returning whether it was held.
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.state.lock().unwrap().remove(path.as_ref())
+    }
+
+    /// すべて捨てる。統計の回数はそのまま残ります。
+    ///
+    /// Drop everything. Hit and miss counters are kept.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.order.clear();
+        state.stats.entries = 0;
+        state.stats.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::process;
+    use std::time::Duration;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("file-cache-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hits_misses_and_binary_contents() {
+        let dir = dir("hits");
+        let png = dir.join("a.png");
+        fs::write(&png, [0x89, b'P', b'N', b'G', 0xff, 0x00]).unwrap();
+        let cache = FileCache::new(1024);
+
+        assert_eq!(
+            *cache.get(&png).unwrap(),
+            [0x89, b'P', b'N', b'G', 0xff, 0x00]
+        );
+        assert_eq!(
+            *cache.get(&png).unwrap(),
+            [0x89, b'P', b'N', b'G', 0xff, 0x00]
+        );
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.entries, stats.bytes), (1, 6));
+
+        // 同じ長さで書き換えても更新時刻で気付く
+        fs::write(&png, b"abcdef").unwrap();
+        File::options()
+            .write(true)
+            .open(&png)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(*cache.get(&png).unwrap(), b"abcdef");
+        assert_eq!(cache.stats().misses, 2);
+
+        fs::remove_file(&png).unwrap();
+        assert!(cache.get(&png).is_err());
+        assert_eq!(cache.stats().entries, 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = dir("lru");
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join(name), [0; 40]).unwrap();
+        }
+        fs::write(dir.join("big"), [0; 200]).unwrap();
+        let cache = FileCache::new(100);
+
+        cache.get(dir.join("a")).unwrap();
+        cache.get(dir.join("b")).unwrap();
+        cache.get(dir.join("a")).unwrap();
+        // 容量を超えるので、最も古いbが捨てられる
+        cache.get(dir.join("c")).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 80, 1));
+        assert!(!cache.invalidate(dir.join("b")));
+        assert!(cache.invalidate(dir.join("a")));
+
+        // 容量より大きいファイルは読めるが保持しない
+        assert_eq!(cache.get(dir.join("big")).unwrap().len(), 200);
+        assert_eq!(cache.stats().entries, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
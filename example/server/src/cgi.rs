@@ -0,0 +1,436 @@
+//! CGIスクリプトの実行。
+//!
+//! スクリプトはリクエストごとに子プロセスとして起動し、RFC 3875の環境変数と
+//! 標準入力のボディを渡します。出力はヘッダを解釈した後、届いた分から
+//! そのままクライアントへ流します。
+//!
+//! CGI scripts. Each request runs the script as a child process with the
+//! RFC 3875 environment and the body on stdin; its output is relayed to the
+//! client as it arrives, after the CGI header block has been translated.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::http::{self, Handler, Request, Response};
+use crate::listener::Stream;
+use crate::vhost;
+
+/// CGIのヘッダ部の上限バイト数。
+const MAX_HEAD: usize = 8 * 1024;
+
+/// ディレクトリ内の実行ファイルをCGIスクリプトとして実行するハンドラ。
+///
+/// Runs executables in mounted directories as CGI scripts.
+pub struct Cgi {
+    mounts: Vec<(String, PathBuf)>,
+    timeout: Duration,
+}
+
+impl Default for Cgi {
+    fn default() -> Cgi {
+        Cgi {
+            mounts: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Cgi {
+    pub fn new() -> Cgi {
+        Cgi::default()
+    }
+
+    /// `prefix`配下のURLを`dir`のスクリプトに対応させる。`/cgi-bin/env/a/b`は
+    /// `dir/env`を実行し、`PATH_INFO`は`/a/b`になります。
+    ///
+    /// Map URLs under `prefix` to scripts in `dir`: `/cgi-bin/env/a/b` runs
+    /// `dir/env` with `PATH_INFO` set to `/a/b`.
+    pub fn mount<P: AsRef<Path>>(mut self, prefix: &str, dir: P) -> Cgi {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.mounts.push((prefix, dir.as_ref().to_path_buf()));
+        self
+    }
+
+    /// スクリプトが終わるまでの上限。過ぎたら強制終了します。既定は30秒です。
+    ///
+    /// How long a script may run before it is killed. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// スクリプトに対応するリクエストを実行し、それ以外は`handler`に渡す。
+    /// 実行権限の無いファイルには`403 Forbidden`を返します。
+    ///
+    /// Run requests that map to a script and pass the rest to `handler`.
+    /// Files without execute permission get `403 Forbidden`.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let cgi = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let (script, script_name, path_info) = match cgi.find(&request.path) {
+                Some(found) => found,
+                None => return handler(request),
+            };
+            let executable = fs::metadata(&script)
+                .map(|m| m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if !executable {
+                return Response::new(403);
+            }
+            let mut env = environment(request, &script_name, &path_info);
+            env.push((
+                "SCRIPT_FILENAME".to_string(),
+                script.to_string_lossy().into_owned(),
+            ));
+            let body = request.body.clone();
+            let head_only = request.method == "HEAD";
+            let timeout = cgi.timeout;
+            Response::upgrade(move |mut stream| {
+                run(&script, env, body, timeout, head_only, &mut stream);
+            })
+        })
+    }
+
+    /// `path`に対応するスクリプトのパス、`SCRIPT_NAME`、`PATH_INFO`を返す。
+    fn find(&self, path: &str) -> Option<(PathBuf, String, String)> {
+        let (prefix, dir) = self
+            .mounts
+            .iter()
+            .filter(|(prefix, _)| http::path_has_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())?;
+        let rest = path[prefix.len()..].trim_start_matches('/');
+        let (name, path_info) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        if name.is_empty() || name.starts_with('.') || name.contains(['\\', '\0']) {
+            return None;
+        }
+        // 作業ディレクトリをスクリプトの場所に移すので、絶対パスにしておく
+        let script = dir.join(name).canonicalize().ok()?;
+        if !script.is_file() {
+            return None;
+        }
+        Some((
+            script,
+            format!("{}/{}", prefix, name),
+            path_info.to_string(),
+        ))
+    }
+}
+
+/// スクリプトを実行し、出力を`stream`へ流す。
+fn run(
+    script: &Path,
+    env: Vec<(String, String)>,
+    body: Vec<u8>,
+    timeout: Duration,
+    head_only: bool,
+    stream: &mut Stream,
+) {
+    let mut command = Command::new(script);
+    command
+        .env_clear()
+        .envs(env)
+        .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        // スクリプトが起動した子もまとめて止められるよう、専用のプロセスグループにする
+        .process_group(0);
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("cgi: cannot run {}: {}", script.display(), e);
+            let _ = gateway_error(500).write_to(stream);
+            return;
+        }
+    };
+
+    // ボディを読まずに出力するスクリプトでも詰まらないよう、別スレッドで書く
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || {
+        let _ = stdin.write_all(&body);
+    });
+    let stdout = child.stdout.take().unwrap();
+    let group = child.id() as libc::pid_t;
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (done, finished) = mpsc::channel::<()>();
+    let watchdog = {
+        let timed_out = Arc::clone(&timed_out);
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+                timed_out.store(true, Ordering::SeqCst);
+                // 孫が標準出力を持ったままだと読み終わらないので、グループごと止める
+                unsafe { libc::kill(-group, libc::SIGKILL) };
+            }
+            let _ = child.wait();
+        })
+    };
+    relay(BufReader::new(stdout), stream, head_only, timeout, || {
+        timed_out.load(Ordering::SeqCst)
+    });
+    drop(done);
+    let _ = watchdog.join();
+}
+
+/// リクエストからCGIの標準の環境変数を作る。`SCRIPT_FILENAME`のような
+/// 実行方法に依存するものは呼び出し側で足します。
+pub(crate) fn environment(
+    request: &Request,
+    script_name: &str,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let host = request.header("Host").unwrap_or("localhost");
+    let server_port = host
+        .rsplit_once(':')
+        .map(|(_, port)| port)
+        .filter(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        .unwrap_or("80");
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "example_server".to_string()),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", vhost::host_name(host)),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.clone()),
+        ("REQUEST_URI", request_uri(request)),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        // php-cgiはこれが無いと実行を拒む
+        ("REDIRECT_STATUS", "200".to_string()),
+    ];
+    if let Some(peer) = request.peer {
+        env.push(("REMOTE_ADDR", peer.ip().to_string()));
+        env.push(("REMOTE_PORT", peer.port().to_string()));
+    }
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        env.push(("CONTENT_LENGTH", request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+    let mut env: Vec<(String, String)> = env
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for (name, value) in &request.headers {
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        // CONTENT_*は上で渡した。HTTP_PROXYは子のHTTPクライアントのプロキシ設定に
+        // 化けるので渡さない(httpoxy)
+        if matches!(
+            name.as_str(),
+            "HTTP_CONTENT_TYPE" | "HTTP_CONTENT_LENGTH" | "HTTP_PROXY"
+        ) || env.iter().any(|(n, _)| *n == name)
+        {
+            continue;
+        }
+        env.push((name, value.clone()));
+    }
+    env
+}
+
+fn request_uri(request: &Request) -> String {
+    match &request.query {
+        Some(query) => format!("{}?{}", request.path, query),
+        None => request.path.clone(),
+    }
+}
+
+/// CGI形式の出力を読み、HTTPレスポンスとして`stream`へ流す。ヘッダ部を
+/// 読めなければ、`timed_out()`に応じて504か502を返します。受け取らない
+/// クライアントへの書き込みは`timeout`で諦めます。
+pub(crate) fn relay<R: BufRead>(
+    mut output: R,
+    stream: &mut Stream,
+    head_only: bool,
+    timeout: Duration,
+    timed_out: impl Fn() -> bool,
+) {
+    if let Err(e) = stream.set_write_timeout(Some(timeout)) {
+        eprintln!("cgi: cannot set write timeout: {}", e);
+    }
+    let head = match read_head(&mut output) {
+        Ok(head) => head,
+        Err(e) => {
+            let status = if timed_out() || e.kind() == io::ErrorKind::TimedOut {
+                504
+            } else {
+                502
+            };
+            eprintln!("cgi: bad response head: {}", e);
+            let _ = gateway_error(status).write_to(stream);
+            return;
+        }
+    };
+    // 長さが分からないので、接続を閉じてボディの終わりを示す
+    let head = head.with_header("Connection", "close");
+    let sent = head.write_head(stream).and_then(|()| {
+        if head_only {
+            Ok(0)
+        } else {
+            io::copy(&mut output, stream)
+        }
+    });
+    if let Err(e) = sent {
+        eprintln!("cgi: relaying output failed: {}", e);
+    }
+    if timed_out() {
+        eprintln!("cgi: script timed out while streaming");
+    }
+}
+
+/// `Status:`や`Location:`を解釈して、ヘッダ部をレスポンスにする。
+fn read_head<R: BufRead>(output: &mut R) -> io::Result<Response> {
+    let mut response = Response::new(200);
+    let mut status = None;
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        let n = output.by_ref().take(MAX_HEAD as u64).read_line(&mut line)?;
+        read += n;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if read > MAX_HEAD {
+            return Err(invalid("response head too large"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header line"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split(' ').next().unwrap_or("");
+            status = Some(code.parse().map_err(|_| invalid("invalid Status"))?);
+        } else {
+            response = response.with_header(name.trim(), value);
+        }
+    }
+    response.status = match status {
+        Some(status) => status,
+        // ステータスの無いリダイレクトは302
+        None if response.header("Location").is_some() => 302,
+        None => 200,
+    };
+    Ok(response)
+}
+
+pub(crate) fn gateway_error(status: u16) -> Response {
+    Response::new(status)
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_header("Connection", "close")
+        .with_body(format!("{}\n", http::reason_phrase(status)))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::process;
+
+    fn script(name: &str, source: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cgi-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, source).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    /// ハンドラの応答を受け取ったソケットへ引き渡し、クライアント側で読んだ内容を返す。
+    fn exchange(handler: &Handler, raw: &str) -> String {
+        let request = Request::parse(raw.as_bytes()).unwrap().unwrap().0;
+        let mut response = handler(&request);
+        let upgrade = response.upgrade.take().expect("not an upgrade");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        upgrade(server.into());
+        let mut got = String::new();
+        client.read_to_string(&mut got).unwrap();
+        got
+    }
+
+    fn not_found() -> Handler {
+        Arc::new(|_: &Request| Response::new(404))
+    }
+
+    #[test]
+    fn runs_scripts_with_cgi_environment() {
+        let dir = script(
+            "echo",
+            "#!/bin/sh\n\
+             printf 'Status: 201 Created\\r\\nContent-Type: text/plain\\r\\n\\r\\n'\n\
+             echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING $HTTP_X_TOKEN $CONTENT_LENGTH\"\n\
+             cat\n",
+        );
+        fs::write(dir.join("plain"), "not executable").unwrap();
+        let handler = Cgi::new().mount("/cgi-bin", &dir).wrap(not_found());
+
+        let got = exchange(
+            &handler,
+            "POST /cgi-bin/echo/a/b?x=1 HTTP/1.1\r\nX-Token: t\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        assert!(got.starts_with("HTTP/1.1 201 Created\r\n"), "{}", got);
+        assert!(got.contains("Content-Type: text/plain\r\n"));
+        assert!(got.ends_with("\r\n\r\nPOST /cgi-bin/echo /a/b x=1 t 4\nbody"));
+
+        let request = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            Request::parse(raw.as_bytes()).unwrap().unwrap().0
+        };
+        assert_eq!(handler(&request("/cgi-bin/plain")).status, 403);
+        assert_eq!(handler(&request("/cgi-bin/missing")).status, 404);
+        assert_eq!(handler(&request("/cgi-bin/../echo")).status, 404);
+    }
+
+    #[test]
+    fn kills_scripts_after_the_timeout() {
+        let dir = script("slow", "#!/bin/sh\nsleep 5\n");
+        let handler = Cgi::new()
+            .mount("/cgi-bin", &dir)
+            .timeout(Duration::from_millis(100))
+            .wrap(not_found());
+        let started = std::time::Instant::now();
+        let got = exchange(&handler, "GET /cgi-bin/slow HTTP/1.1\r\n\r\n");
+        assert!(
+            got.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            got
+        );
+        // シェルが起動した`sleep`も止まるので、5秒を待たない
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn translates_location_without_status() {
+        let mut output = &b"Location: /elsewhere\nX-A: 1\n\nrest"[..];
+        let head = read_head(&mut output).unwrap();
+        assert_eq!(head.status, 302);
+        assert_eq!(head.header("Location"), Some("/elsewhere"));
+        assert_eq!(output, b"rest");
+    }
+}
@@ -0,0 +1,219 @@
+//! クッキーの読み取りと`Set-Cookie`の組み立て。
+//!
+//! Cookies: reading them from requests and building `Set-Cookie` headers.
+
+use std::fmt;
+use std::time::Duration;
+
+/// `SameSite`属性。
+///
+/// The `SameSite` attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// `Secure`も付けないとブラウザに拒否されます。
+    ///
+    /// Browsers reject this without `Secure`.
+    None,
+}
+
+/// レスポンスで設定するクッキー。
+///
+/// A cookie to set on a response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// 属性の無いクッキーを作る。
+    ///
+    /// # パニック
+    ///
+    /// 名前がトークンでないか、値にクッキーで使えない文字があるとパニックします。
+    ///
+    /// Create a cookie without attributes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a token or `value` contains characters that
+    /// cookies cannot carry.
+    pub fn new(name: &str, value: &str) -> Cookie {
+        assert!(
+            !name.is_empty() && name.bytes().all(is_token),
+            "invalid cookie name: {:?}",
+            name
+        );
+        assert!(
+            value.bytes().all(is_cookie_octet),
+            "invalid cookie value: {:?}",
+            value
+        );
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// ブラウザにクッキーを消させる。`path`と`domain`は設定したときと
+    /// 同じにしてください。
+    ///
+    /// A cookie that makes the browser delete `name`. Set the same `path`
+    /// and `domain` it was created with.
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// 有効期間。指定しなければブラウザを閉じるまでのセッションクッキーです。
+    ///
+    /// How long the cookie lives. Without it the cookie lasts until the
+    /// browser is closed.
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// HTTPSの接続でだけ送らせる。
+    ///
+    /// Only send the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// JavaScriptから読めないようにする。
+    ///
+    /// Hide the cookie from JavaScript.
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+/// `Set-Cookie`ヘッダの値として書く。
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `Cookie`ヘッダの値から`name`の値を探す。値を囲む`"`は外します。
+///
+/// Find `name` in a `Cookie` header value, without surrounding quotes.
+pub fn find<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (n, value) = pair.trim().split_once('=')?;
+        if n.trim() != name {
+            return None;
+        }
+        let value = value.trim();
+        Some(
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value),
+        )
+    })
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// RFC 6265の`cookie-octet`。
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_cookies_by_name() {
+        let header = "theme=dark; sid=\"abc.def\";lang=ja";
+        assert_eq!(find(header, "theme"), Some("dark"));
+        assert_eq!(find(header, "sid"), Some("abc.def"));
+        assert_eq!(find(header, "lang"), Some("ja"));
+        assert_eq!(find(header, "the"), None);
+    }
+
+    #[test]
+    fn formats_set_cookie() {
+        let cookie = Cookie::new("sid", "abc")
+            .path("/")
+            .max_age(Duration::from_secs(3600))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            cookie.to_string(),
+            "sid=abc; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(Cookie::removal("sid").to_string(), "sid=; Max-Age=0");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid cookie value")]
+    fn rejects_separators_in_values() {
+        Cookie::new("sid", "a;b");
+    }
+}
@@ -0,0 +1,432 @@
+//! 経路ごとに設定できるCORS(オリジン間リソース共有)。
+//!
+//! Cross-origin resource sharing, configured per route. Preflight `OPTIONS`
+//! requests are answered here without reaching the wrapped handler.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http::{path_has_prefix, Handler, Request, Response};
+
+#[derive(Debug, Clone)]
+enum Origin {
+    Any,
+    Exact(String),
+    /// `https://*.example.com`の`https://`と`.example.com`
+    Subdomains(String, String),
+}
+
+impl Origin {
+    fn parse(pattern: &str) -> Origin {
+        if pattern == "*" {
+            return Origin::Any;
+        }
+        match pattern.split_once("://*.") {
+            Some((scheme, domain)) => {
+                Origin::Subdomains(format!("{}://", scheme), format!(".{}", domain))
+            }
+            None => Origin::Exact(pattern.to_string()),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Origin::Any => true,
+            Origin::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            Origin::Subdomains(scheme, domain) => {
+                let origin = origin.to_ascii_lowercase();
+                match origin.strip_prefix(scheme.as_str()) {
+                    Some(host) => host.len() > domain.len() && host.ends_with(domain.as_str()),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// 1経路分のCORSの許可内容。
+///
+/// What a route allows cross-origin callers to do.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    origins: Vec<Origin>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            origins: Vec::new(),
+            methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            headers: Vec::new(),
+            expose: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    /// 許可するオリジンを追加する。`https://game.example`のような完全一致、
+    /// `https://*.example.com`のようなサブドメインのワイルドカード、
+    /// すべてを許す`*`が使えます。
+    ///
+    /// # パニック
+    ///
+    /// `allow_credentials`と`*`を組み合わせるとパニックします。
+    ///
+    /// Allow an origin: an exact origin such as `https://game.example`, any
+    /// subdomain with `https://*.example.com`, or every origin with `*`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `*` is combined with `allow_credentials`.
+    pub fn allow_origin(mut self, pattern: &str) -> Policy {
+        let origin = Origin::parse(pattern);
+        assert!(
+            !(self.credentials && matches!(origin, Origin::Any)),
+            "CORS: `*` cannot be allowed together with credentials"
+        );
+        self.origins.push(origin);
+        self
+    }
+
+    /// 許可するメソッド。既定は`GET`、`HEAD`、`POST`です。
+    ///
+    /// Methods allowed cross-origin; defaults to `GET`, `HEAD` and `POST`.
+    pub fn allow_methods(mut self, methods: &[&str]) -> Policy {
+        self.methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+        self
+    }
+
+    /// プリフライトで許可するリクエストヘッダ。
+    ///
+    /// Request headers a preflight may ask for.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Policy {
+        self.headers = headers.iter().map(|h| h.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// スクリプトから読めるようにするレスポンスヘッダ。
+    ///
+    /// Response headers scripts may read.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Policy {
+        self.expose = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Cookieや認証情報付きのリクエストを許可する。どのサイトからでも
+    /// 利用者の資格情報で呼べてしまうので、`*`とは組み合わせられません。
+    ///
+    /// # パニック
+    ///
+    /// `*`を許可しているとパニックします。
+    ///
+    /// Allow credentialed requests. This cannot be combined with `*`, which
+    /// would let any site call in with the user's credentials.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `*` is allowed.
+    pub fn allow_credentials(mut self) -> Policy {
+        assert!(
+            !self.origins.iter().any(|o| matches!(o, Origin::Any)),
+            "CORS: credentials cannot be allowed together with `*`"
+        );
+        self.credentials = true;
+        self
+    }
+
+    /// プリフライトの結果をブラウザがキャッシュしてよい時間。
+    ///
+    /// How long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> Policy {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o.matches(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .all(|h| self.headers.contains(&h))
+    }
+
+    /// 許可したオリジンに返す`Access-Control-Allow-Origin`の値。
+    fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        let only_any = self.origins.iter().all(|o| matches!(o, Origin::Any));
+        if only_any {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    fn preflight(&self, origin: &str, request: &Request) -> Response {
+        let method = request
+            .header("Access-Control-Request-Method")
+            .unwrap_or("");
+        let headers = request
+            .header("Access-Control-Request-Headers")
+            .unwrap_or("");
+        if !self.allows_origin(origin)
+            || !self.allows_method(method)
+            || !self.allows_headers(headers)
+        {
+            return Response::new(403).with_header("Vary", "Origin");
+        }
+        let mut response = Response::new(204)
+            .with_header(
+                "Access-Control-Allow-Origin",
+                self.allow_origin_value(origin),
+            )
+            .with_header("Access-Control-Allow-Methods", &self.methods.join(", "))
+            .with_header("Vary", "Origin");
+        if !self.headers.is_empty() {
+            response =
+                response.with_header("Access-Control-Allow-Headers", &self.headers.join(", "));
+        }
+        if self.credentials {
+            response = response.with_header("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = self.max_age {
+            response =
+                response.with_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        response
+    }
+
+    fn annotate(&self, origin: &str, mut response: Response) -> Response {
+        response = response.with_header("Vary", "Origin");
+        if !self.allows_origin(origin) {
+            return response;
+        }
+        response = response.with_header(
+            "Access-Control-Allow-Origin",
+            self.allow_origin_value(origin),
+        );
+        if self.credentials {
+            response = response.with_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose.is_empty() {
+            response =
+                response.with_header("Access-Control-Expose-Headers", &self.expose.join(", "));
+        }
+        response
+    }
+}
+
+/// 経路ごとのCORS設定。`wrap`でハンドラに被せて使います。
+///
+/// Per-route CORS policies, applied by wrapping a handler with `wrap`.
+#[derive(Default)]
+pub struct Cors {
+    routes: Vec<(String, Policy)>,
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors::default()
+    }
+
+    /// `prefix`配下のパスに`policy`を適用する。複数の経路に一致する場合は
+    /// 最も長い接頭辞のものを使います。
+    ///
+    /// Apply `policy` to paths under `prefix`. When several routes match,
+    /// the longest prefix wins.
+    pub fn route(mut self, prefix: &str, policy: Policy) -> Cors {
+        self.routes.push((prefix.to_string(), policy));
+        self
+    }
+
+    /// プリフライトに応答し、許可したオリジンへのレスポンスにCORSヘッダを付ける
+    /// ハンドラにする。
+    ///
+    /// Wrap `handler`: preflights are answered directly, and responses to
+    /// allowed origins get the CORS headers. Every response on a route with
+    /// a policy carries `Vary: Origin`, so caches never serve one origin's
+    /// reply to another.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let cors = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let (origin, policy) = match (request.header("Origin"), cors.policy(&request.path)) {
+                (Some(origin), Some(policy)) => (origin, policy),
+                // Originの無い応答もキャッシュでほかのオリジンに渡らないようにする
+                (None, Some(_)) => return handler(request).with_header("Vary", "Origin"),
+                (_, None) => return handler(request),
+            };
+            if request.method == "OPTIONS"
+                && request.header("Access-Control-Request-Method").is_some()
+            {
+                return policy.preflight(origin, request);
+            }
+            policy.annotate(origin, handler(request))
+        })
+    }
+
+    fn policy(&self, path: &str) -> Option<&Policy> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path_has_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", method, path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn handler() -> Handler {
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200).with_body("ok"));
+        Cors::new()
+            .route(
+                "/api",
+                Policy::new()
+                    .allow_origin("http://localhost:8080")
+                    .allow_origin("https://*.example.com")
+                    .allow_methods(&["GET", "POST", "DELETE"])
+                    .allow_headers(&["Content-Type", "Authorization"])
+                    .expose_headers(&["RateLimit-Remaining"])
+                    .allow_credentials()
+                    .max_age(Duration::from_secs(600)),
+            )
+            .route("/public", Policy::new().allow_origin("*"))
+            .wrap(handler)
+    }
+
+    #[test]
+    fn origins_exact_and_wildcard() {
+        assert!(Origin::parse("https://*.example.com").matches("https://a.b.example.com"));
+        assert!(!Origin::parse("https://*.example.com").matches("https://example.com"));
+        assert!(!Origin::parse("https://*.example.com").matches("http://a.example.com"));
+        assert!(!Origin::parse("https://*.example.com").matches("https://evilexample.com"));
+        assert!(Origin::parse("http://localhost:8080").matches("http://localhost:8080"));
+        assert!(!Origin::parse("http://localhost:8080").matches("http://localhost:8081"));
+    }
+
+    #[test]
+    fn preflight_is_answered_without_the_handler() {
+        let handler = handler();
+        let ok = handler(&request(
+            "OPTIONS",
+            "/api/runs",
+            &[
+                ("Origin", "https://game.example.com"),
+                ("Access-Control-Request-Method", "DELETE"),
+                (
+                    "Access-Control-Request-Headers",
+                    "content-type, authorization",
+                ),
+            ],
+        ));
+        assert_eq!(ok.status, 204);
+        assert_eq!(
+            ok.header("Access-Control-Allow-Origin"),
+            Some("https://game.example.com")
+        );
+        assert_eq!(
+            ok.header("Access-Control-Allow-Methods"),
+            Some("GET, POST, DELETE")
+        );
+        assert_eq!(ok.header("Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(ok.header("Access-Control-Max-Age"), Some("600"));
+
+        for headers in [
+            [
+                ("Origin", "https://evil.test"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+            [
+                ("Origin", "http://localhost:8080"),
+                ("Access-Control-Request-Method", "PUT"),
+            ],
+        ] {
+            let denied = handler(&request("OPTIONS", "/api/runs", &headers));
+            assert_eq!(denied.status, 403);
+            assert!(denied.header("Access-Control-Allow-Origin").is_none());
+        }
+        let extra_header = handler(&request(
+            "OPTIONS",
+            "/api/runs",
+            &[
+                ("Origin", "http://localhost:8080"),
+                ("Access-Control-Request-Method", "GET"),
+                ("Access-Control-Request-Headers", "X-Secret"),
+            ],
+        ));
+        assert_eq!(extra_header.status, 403);
+    }
+
+    #[test]
+    fn actual_requests_get_cors_headers() {
+        let handler = handler();
+        let allowed = handler(&request(
+            "GET",
+            "/api/runs",
+            &[("Origin", "http://localhost:8080")],
+        ));
+        assert_eq!(allowed.body, b"ok");
+        assert_eq!(
+            allowed.header("Access-Control-Allow-Origin"),
+            Some("http://localhost:8080")
+        );
+        assert_eq!(
+            allowed.header("Access-Control-Expose-Headers"),
+            Some("RateLimit-Remaining")
+        );
+
+        let other = handler(&request(
+            "GET",
+            "/api/runs",
+            &[("Origin", "https://evil.test")],
+        ));
+        assert_eq!(other.status, 200);
+        assert!(other.header("Access-Control-Allow-Origin").is_none());
+
+        let public = handler(&request(
+            "GET",
+            "/public/x",
+            &[("Origin", "https://evil.test")],
+        ));
+        assert_eq!(public.header("Access-Control-Allow-Origin"), Some("*"));
+        assert!(public.header("Access-Control-Allow-Credentials").is_none());
+
+        let elsewhere = handler(&request("GET", "/", &[("Origin", "http://localhost:8080")]));
+        assert!(elsewhere.header("Vary").is_none());
+        let same_origin = handler(&request("GET", "/api/runs", &[]));
+        assert_eq!(same_origin.header("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    #[should_panic(expected = "credentials")]
+    fn credentials_with_any_origin_panic() {
+        Policy::new().allow_origin("*").allow_credentials();
+    }
+}
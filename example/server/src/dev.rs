@@ -0,0 +1,334 @@
+//! 開発モード: ファイルの監視とブラウザの自動リロード。
+//!
+//! `Watcher`がドキュメントルートを定期的に走査して変更を検出し、登録された
+//! コールバック(キャッシュの破棄など)を呼びます。`LiveReload`は配信する
+//! HTMLに小さなスクリプトを差し込み、変更があればSSEでリロードを指示します。
+//!
+//! Development mode: file watching and live reload. A `Watcher` rescans the
+//! document roots on an interval and calls its listeners (cache
+//! invalidation, for example) with whatever changed. `LiveReload` injects a
+//! small script into served HTML pages and tells them over SSE to reload.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::http::{Handler, Request, Response};
+use crate::sse::{Event, EventStream};
+use crate::{ScheduledTask, ThreadPool};
+
+/// ファイルの更新時刻とサイズ。どちらかが変われば変更とみなす。
+type Stamp = (Option<SystemTime>, u64);
+
+type Listener = Box<dyn Fn(&[PathBuf]) + Send + Sync>;
+
+/// ファイルとディレクトリを走査して変更を検出する。OSの通知機構は使わず、
+/// 前回の走査結果と比べるだけなので、どの環境でも同じように動きます。
+///
+/// Detects changes under a set of files and directories by rescanning and
+/// comparing with the previous scan. No OS notification API is involved, so
+/// it behaves the same everywhere, at the cost of a scan per poll.
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    snapshot: Mutex<HashMap<PathBuf, Stamp>>,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+impl Watcher {
+    /// `roots`を監視する。作った時点の状態が比較の基準になります。
+    ///
+    /// Watch `roots`; their current state is the baseline for changes.
+    pub fn new<P: Into<PathBuf>, I: IntoIterator<Item = P>>(roots: I) -> Watcher {
+        let roots: Vec<PathBuf> = roots.into_iter().map(Into::into).collect();
+        let snapshot = scan(&roots);
+        Watcher {
+            roots,
+            snapshot: Mutex::new(snapshot),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 変更があるたびに、追加・変更・削除されたパスの一覧で`f`を呼ぶ。
+    ///
+    /// Call `f` with the added, modified and removed paths after each poll
+    /// that finds changes.
+    pub fn on_change<F>(&self, f: F)
+    where
+        F: Fn(&[PathBuf]) + Send + Sync + 'static,
+    {
+        self.listeners.lock().unwrap().push(Box::new(f));
+    }
+
+    /// 一度走査し、変更されたパスを返す。変更があればコールバックも呼びます。
+    ///
+    /// Rescan once and return the changed paths, calling the listeners if
+    /// there were any.
+    pub fn poll(&self) -> Vec<PathBuf> {
+        let current = scan(&self.roots);
+        let mut snapshot = self.snapshot.lock().unwrap();
+        let mut changed: Vec<PathBuf> = current
+            .iter()
+            .filter(|(path, stamp)| snapshot.get(*path) != Some(stamp))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.extend(
+            snapshot
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .cloned(),
+        );
+        *snapshot = current;
+        drop(snapshot);
+
+        if !changed.is_empty() {
+            changed.sort();
+            for listener in self.listeners.lock().unwrap().iter() {
+                listener(&changed);
+            }
+        }
+        changed
+    }
+
+    /// プールのタイマーで`interval`ごとに`poll`する。返されたハンドルで止められます。
+    ///
+    /// Poll every `interval` on the pool's timer. Cancel the returned handle
+    /// to stop watching.
+    pub fn start(self: &Arc<Self>, pool: &ThreadPool, interval: Duration) -> ScheduledTask {
+        let watcher = Arc::clone(self);
+        pool.schedule_repeating(interval, interval, move || {
+            watcher.poll();
+        })
+    }
+}
+
+fn scan(roots: &[PathBuf]) -> HashMap<PathBuf, Stamp> {
+    let mut files = HashMap::new();
+    for root in roots {
+        visit(root, &mut files);
+    }
+    files
+}
+
+fn visit(path: &Path, files: &mut HashMap<PathBuf, Stamp>) {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        // 走査中に消えたファイルは無かったことにする
+        Err(_) => return,
+    };
+    if metadata.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                // 循環しないよう、ディレクトリへのシンボリックリンクは辿らない
+                let linked = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+                if linked && path.is_dir() {
+                    continue;
+                }
+                visit(&path, files);
+            }
+        }
+    } else {
+        files.insert(
+            path.to_path_buf(),
+            (metadata.modified().ok(), metadata.len()),
+        );
+    }
+}
+
+/// HTMLの`</body>`の直前(無ければ末尾)に`snippet`を差し込む。
+///
+/// Insert `snippet` before the last `</body>`, or at the end if there is none.
+pub fn inject(html: &[u8], snippet: &str) -> Vec<u8> {
+    let lower = html.to_ascii_lowercase();
+    let at = lower
+        .windows(7)
+        .rposition(|w| w == b"</body>")
+        .unwrap_or(html.len());
+    let mut out = Vec::with_capacity(html.len() + snippet.len());
+    out.extend_from_slice(&html[..at]);
+    out.extend_from_slice(snippet.as_bytes());
+    out.extend_from_slice(&html[at..]);
+    out
+}
+
+/// ファイルが変わったらブラウザをリロードさせる。
+///
+/// Reloads browsers when watched files change.
+pub struct LiveReload {
+    events: Arc<EventStream>,
+    path: String,
+}
+
+impl LiveReload {
+    /// `watcher`が変更を検出するたびに`reload`イベントを配信する。
+    ///
+    /// Broadcast a `reload` event whenever `watcher` sees a change.
+    pub fn new(watcher: &Watcher) -> LiveReload {
+        // 取りこぼしたリロードは再接続時に1回分だけ届ける
+        let events = Arc::new(EventStream::new(1, Duration::from_secs(15)));
+        let sender = Arc::clone(&events);
+        watcher.on_change(move |changed| {
+            let names: Vec<_> = changed.iter().map(|p| p.display().to_string()).collect();
+            sender.send(Event::new(names.join("\n")).event("reload"));
+        });
+        LiveReload {
+            events,
+            path: "/__livereload".to_string(),
+        }
+    }
+
+    /// 差し込むスクリプト。
+    ///
+    /// The script injected into HTML pages.
+    pub fn snippet(&self) -> String {
+        format!(
+            "<script>new EventSource(\"{}\").addEventListener(\"reload\",\
+             function(){{location.reload()}});</script>",
+            self.path
+        )
+    }
+
+    /// HTMLのレスポンスにスクリプトを差し込み、リロード通知のSSEを配信する
+    /// ハンドラにする。
+    ///
+    /// Wrap `handler`: HTML responses get the reload script, and the
+    /// script's event stream is served alongside.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let reload = Arc::new(self);
+        let snippet = reload.snippet();
+        Arc::new(move |request: &Request| {
+            if request.method == "GET" && request.path == reload.path {
+                let events = Arc::clone(&reload.events);
+                let last_event_id = request.header("Last-Event-ID").map(str::to_string);
+                return Response::upgrade(move |stream| {
+                    if let Err(e) = events.subscribe(stream, last_event_id.as_deref()) {
+                        eprintln!("live reload subscribe failed: {}", e);
+                    }
+                });
+            }
+            let mut response = handler(request);
+            let is_html = response
+                .header("Content-Type")
+                .is_some_and(|t| t.starts_with("text/html"));
+            // 範囲の応答に差し込むと`Content-Range`と食い違う
+            if !is_html || response.status == 206 || response.upgrade.is_some() {
+                return response;
+            }
+            // HEADではボディが空なので、長さだけ合わせる
+            if request.method != "HEAD" {
+                if let Err(e) = response.buffer_file() {
+                    eprintln!("live reload read failed: {}", e);
+                    return Response::new(500);
+                }
+                response.body = inject(&response.body, &snippet);
+            }
+            let length = response
+                .header("Content-Length")
+                .and_then(|v| v.parse::<usize>().ok());
+            if let Some(length) = length {
+                response
+                    .headers
+                    .retain(|(n, _)| !n.eq_ignore_ascii_case("Content-Length"));
+                response =
+                    response.with_header("Content-Length", &(length + snippet.len()).to_string());
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::process;
+
+    #[test]
+    fn watcher_reports_changes() {
+        let root = env::temp_dir().join(format!("dev-watch-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.html"), "a").unwrap();
+        fs::write(root.join("sub/b.png"), "b").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let watcher = Watcher::new([&root]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        watcher.on_change(move |changed| sink.lock().unwrap().extend_from_slice(changed));
+        assert!(watcher.poll().is_empty());
+
+        // 同じサイズで書き換えても、更新時刻の違いで気付く
+        fs::write(root.join("a.html"), "A").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        File::options()
+            .write(true)
+            .open(root.join("a.html"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        fs::remove_file(root.join("sub/b.png")).unwrap();
+        fs::write(root.join("sub/c.js"), "c").unwrap();
+        let changed = watcher.poll();
+        assert_eq!(
+            changed,
+            vec![
+                root.join("a.html"),
+                root.join("sub/b.png"),
+                root.join("sub/c.js")
+            ]
+        );
+        assert_eq!(*seen.lock().unwrap(), changed);
+        assert!(watcher.poll().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn inject_before_closing_body() {
+        assert_eq!(
+            inject(b"<p>x</p></BODY></html>", "<s/>"),
+            b"<p>x</p><s/></BODY></html>"
+        );
+        assert_eq!(inject(b"<p>x</p>", "<s/>"), b"<p>x</p><s/>");
+    }
+
+    #[test]
+    fn html_responses_get_the_snippet() {
+        let handler: Handler = Arc::new(|request: &Request| {
+            let body: &[u8] = if request.path == "/page" {
+                b"<body></body>"
+            } else {
+                b"{}"
+            };
+            let kind = if request.path == "/page" {
+                "text/html; charset=utf-8"
+            } else {
+                "application/json"
+            };
+            Response::new(200)
+                .with_header("Content-Type", kind)
+                .with_header("Content-Length", &body.len().to_string())
+                .with_body(body)
+        });
+        let reload = LiveReload::new(&Watcher::new(Vec::<PathBuf>::new()));
+        let snippet = reload.snippet();
+        let handler = reload.wrap(handler);
+        let request = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            Request::parse(raw.as_bytes()).unwrap().unwrap().0
+        };
+
+        let page = handler(&request("/page"));
+        let expected = format!("<body>{}</body>", snippet);
+        assert_eq!(page.body, expected.as_bytes());
+        assert_eq!(
+            page.header("Content-Length"),
+            Some(expected.len().to_string().as_str())
+        );
+        assert_eq!(handler(&request("/data")).body, b"{}");
+        assert!(handler(&request("/__livereload")).upgrade.is_some());
+    }
+}
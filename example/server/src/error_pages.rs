@@ -0,0 +1,141 @@
+//! ステータスコードごとのエラーページ。
+//!
+//! ハンドラがボディの無いエラー(4xx/5xx)を返したら、そのステータスに
+//! 設定したテンプレートでボディを作ります。変数は`status`、`reason`、
+//! `method`、`path`です。
+//!
+//! Error pages per status code. When the wrapped handler answers with an
+//! error status and no body, the body is rendered from the template set for
+//! that status, with `status`, `reason`, `method` and `path` as variables.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::http::{reason_phrase, Handler, Request, Response};
+use crate::template::{Context, Templates};
+
+pub struct ErrorPages {
+    templates: Arc<Templates>,
+    pages: HashMap<u16, String>,
+    fallback: Option<String>,
+}
+
+impl ErrorPages {
+    pub fn new(templates: Arc<Templates>) -> ErrorPages {
+        ErrorPages {
+            templates,
+            pages: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// `status`のページを`template`で描く。
+    ///
+    /// Render `status` responses with `template`.
+    pub fn page(mut self, status: u16, template: &str) -> ErrorPages {
+        self.pages.insert(status, template.to_string());
+        self
+    }
+
+    /// 個別のページが無いエラーに使うテンプレート。
+    ///
+    /// The template for error statuses without a page of their own.
+    pub fn fallback(mut self, template: &str) -> ErrorPages {
+        self.fallback = Some(template.to_string());
+        self
+    }
+
+    pub fn wrap(self, handler: Handler) -> Handler {
+        Arc::new(move |request: &Request| {
+            let response = handler(request);
+            self.render(request, response)
+        })
+    }
+
+    fn render(&self, request: &Request, mut response: Response) -> Response {
+        // ハンドラが自分でボディを用意したなら、そのまま返す
+        let bare = response.body.is_empty()
+            && response.file.is_none()
+            && response.shared.is_none()
+            && response.upgrade.is_none();
+        if response.status < 400 || !bare {
+            return response;
+        }
+        let template = match self.pages.get(&response.status).or(self.fallback.as_ref()) {
+            Some(template) => template,
+            None => return response,
+        };
+        let context = Context::new()
+            .with("status", response.status)
+            .with("reason", reason_phrase(response.status))
+            .with("method", request.method.as_str())
+            .with("path", request.path.as_str());
+        match self.templates.render(template, &context) {
+            Ok(html) => {
+                // `Allow`や`Retry-After`などハンドラのヘッダは残す
+                response
+                    .headers
+                    .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+                let response = response.with_header("Content-Type", "text/html; charset=utf-8");
+                // HEADにはボディを付けず、長さだけ知らせる
+                if request.method == "HEAD" {
+                    response.with_header("Content-Length", &html.len().to_string())
+                } else {
+                    response.with_body(html)
+                }
+            }
+            Err(e) => {
+                eprintln!("error page: {}", e);
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn fills_bare_error_responses() {
+        let dir = env::temp_dir().join(format!("error-pages-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "no {{ path }}").unwrap();
+        fs::write(dir.join("error.html"), "{{ status }} {{ reason }}").unwrap();
+        let handler: Handler = Arc::new(|request: &Request| match request.path.as_str() {
+            "/teapot" => Response::new(418).with_body("short and stout"),
+            "/broken" => Response::new(503).with_header("Retry-After", "5"),
+            "/" => Response::new(200),
+            _ => Response::new(404),
+        });
+        let handler = ErrorPages::new(Arc::new(Templates::new(&dir)))
+            .page(404, "404.html")
+            .fallback("error.html")
+            .wrap(handler);
+        let get = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            handler(&Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        let response = get("/<missing>");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"no /&lt;missing&gt;");
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let response = get("/broken");
+        assert_eq!(response.body, b"503 Service Unavailable");
+        assert_eq!(response.header("Retry-After"), Some("5"));
+        assert_eq!(get("/teapot").body, b"short and stout");
+        assert!(get("/").body.is_empty());
+        let raw = b"HEAD /gone HTTP/1.1\r\n\r\n";
+        let head = handler(&Request::parse(raw).unwrap().unwrap().0);
+        assert!(head.body.is_empty());
+        assert_eq!(head.header("Content-Length"), Some("8"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
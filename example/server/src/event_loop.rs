@@ -0,0 +1,1116 @@
+//! mio(epoll)によるイベント駆動のI/O方式。
+//!
+//! 少数のI/Oスレッドが多数の接続を多重化し、ハンドラの実行だけを
+//! ThreadPoolに任せます。キープアライブ中の待ち接続はワーカーを占有しません。
+//! 待機が長すぎる接続は閉じ、期限までに送り終えないリクエストには408を返します。
+//!
+//! Event-driven serving on mio. A few I/O threads multiplex every open
+//! connection; only handler calls are dispatched to the ThreadPool, so idle
+//! keep-alive connections never hold a worker. Connections idle for too long
+//! are closed, and requests not fully sent in time get a 408.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use crate::http::{self, Handler, Request, Response};
+use crate::listener::{Listener, Stream};
+use crate::sendfile::FileBody;
+use crate::server;
+use crate::shutdown::{InFlight, Shutdown};
+use crate::{CancellationToken, JobOptions, Priority, ThreadPool};
+
+/// リスナーは`Token(1)`から順に、接続はその後の番号を使う。
+const WAKER: Token = Token(0);
+
+/// キープアライブの接続が次のリクエストを待つ時間。
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 1接続が読み込んでおくバイト数の上限。ヘッダとボディが上限いっぱいの
+/// リクエストが1つ入る大きさです。
+const MAX_INPUT: usize = http::MAX_BODY + 16 * 1024;
+
+/// `io_threads`本のI/Oスレッドで接続を受け付け、終了しない。
+///
+/// # パニック
+///
+/// `io_threads`が0ならパニックします。
+///
+/// Serve `listener` on `io_threads` I/O threads, dispatching handlers to
+/// `pool`. Only returns if an I/O thread fails.
+///
+/// # Panics
+///
+/// Panics if `io_threads` is zero.
+pub fn serve(
+    listener: net::TcpListener,
+    pool: Arc<ThreadPool>,
+    handler: Handler,
+    io_threads: usize,
+) -> io::Result<()> {
+    serve_with_priority(listener, pool, handler, io_threads, |_| Priority::Normal)
+}
+
+/// `serve`と同じだが、リクエストごとにプールの優先度レーンを選ぶ。
+/// 応答を待つ間に接続が切れたら、未着手のハンドラは取り消されます。
+/// 送信側だけを閉じたクライアントには応答を返します。
+///
+/// Like `serve`, but `priority` picks the pool lane for each request. In
+/// both variants a queued handler is cancelled if its connection fails or
+/// hangs up before a worker picks it up; a client that only shut down its
+/// sending side still gets its response.
+pub fn serve_with_priority(
+    listener: net::TcpListener,
+    pool: Arc<ThreadPool>,
+    handler: Handler,
+    io_threads: usize,
+    priority: fn(&Request) -> Priority,
+) -> io::Result<()> {
+    let shutdown = Shutdown::new(Duration::ZERO);
+    let listeners = vec![listener.into()];
+    serve_until(
+        listeners,
+        pool,
+        handler,
+        io_threads,
+        priority,
+        |_| false,
+        &shutdown,
+    )
+    .map(|_| ())
+}
+
+/// `serve_with_priority`と同じだが、すべての`listeners`で受け付け、
+/// `shutdown`の合図で受け付けをやめる。
+/// 待機中のキープアライブ接続はすぐに閉じ、処理中の接続には
+/// `Connection: close`を付けて応答してから閉じます。猶予時間までに
+/// すべて閉じられたかを返します。
+/// `stream_body`が選んだリクエストは、ボディを読まずに接続ごとプールへ渡し、
+/// ハンドラが`Request::take_body_reader`で読みます。応答の後は接続を閉じます。
+/// 停止の猶予中は、こうして渡した接続やアップグレードした接続の処理も待ちます。
+///
+/// Like `serve_with_priority`, but accepts on every listener and stops
+/// once `shutdown` is triggered. Idle keep-alive connections are closed right away; busy ones
+/// get their response with `Connection: close` and are closed after it.
+/// Returns whether every connection was closed within the drain timeout.
+/// Requests `stream_body` selects are handed to the pool with their
+/// connection before the body is read; the handler reads it with
+/// `Request::take_body_reader`, and the connection is closed after the
+/// response. Draining also waits for these and for upgraded connections.
+pub fn serve_until(
+    listeners: Vec<Listener>,
+    pool: Arc<ThreadPool>,
+    handler: Handler,
+    io_threads: usize,
+    priority: fn(&Request) -> Priority,
+    stream_body: fn(&Request) -> bool,
+    shutdown: &Shutdown,
+) -> io::Result<bool> {
+    assert!(io_threads > 0);
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+
+    // I/Oスレッドの手を離れて、プールで接続ごと処理している数
+    let handed_over = Arc::new(InFlight::new());
+    let mut threads = Vec::with_capacity(io_threads);
+    for id in 0..io_threads {
+        // 各スレッドが同じリスニングソケットを自分のPollに登録し、acceptを取り合う
+        let acceptors = listeners
+            .iter()
+            .map(|listener| listener.try_clone().map(Acceptor::from_std))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut io = IoThread::new(
+            acceptors,
+            Arc::clone(&pool),
+            Arc::clone(&handler),
+            priority,
+            stream_body,
+            Arc::clone(&handed_over),
+            shutdown.clone(),
+        )?;
+        let waker = Arc::clone(&io.waker);
+        shutdown.on_trigger(move || {
+            let _ = waker.wake();
+        });
+        let thread = thread::Builder::new()
+            .name(format!("io-{}", id))
+            .spawn(move || io.run())?;
+        threads.push(thread);
+    }
+    drop(listeners);
+    let mut drained = true;
+    for thread in threads {
+        match thread.join() {
+            Ok(result) => drained &= result?,
+            Err(_) => {
+                eprintln!("an I/O thread panicked");
+                drained = false;
+            }
+        }
+    }
+    if let Some(deadline) = shutdown.deadline() {
+        drained &= handed_over.wait_idle(deadline);
+    }
+    Ok(drained)
+}
+
+/// mioに登録するリスニングソケット。
+enum Acceptor {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Acceptor {
+    fn from_std(listener: Listener) -> Acceptor {
+        match listener {
+            Listener::Tcp(listener) => Acceptor::Tcp(TcpListener::from_std(listener)),
+            Listener::Unix(listener) => Acceptor::Unix(UnixListener::from_std(listener)),
+        }
+    }
+
+    fn accept(&self) -> io::Result<(Socket, Option<SocketAddr>)> {
+        match self {
+            Acceptor::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, peer)| (Socket::Tcp(stream), Some(peer))),
+            Acceptor::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| (Socket::Unix(stream), None)),
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Acceptor::Tcp(listener) => listener,
+            Acceptor::Unix(listener) => listener,
+        }
+    }
+}
+
+/// mioに登録する接続のソケット。
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Socket::Tcp(stream) => stream,
+            Socket::Unix(stream) => stream,
+        }
+    }
+}
+
+impl Source for Socket {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interest)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interest)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
+
+impl From<Socket> for Stream {
+    fn from(socket: Socket) -> Stream {
+        match socket {
+            Socket::Tcp(stream) => Stream::Tcp(stream.into()),
+            Socket::Unix(stream) => Stream::Unix(stream.into()),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+struct Connection {
+    stream: Socket,
+    /// Unixドメインソケットでは`None`
+    peer: Option<SocketAddr>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    /// `output`の後に送る共有のバイト列と、送り終えたバイト数。
+    shared: Option<(Arc<Vec<u8>>, usize)>,
+    /// その後に`sendfile`で送るファイル。
+    file: Option<FileBody>,
+    /// プールで処理中のリクエストがあれば、その取り消しトークン。
+    in_flight: Option<CancellationToken>,
+    keep_alive: bool,
+    eof: bool,
+    /// `input`が上限に達して読み残しがあるかもしれない。
+    full: bool,
+    /// 最後に読み書きできた時刻。
+    last_activity: Instant,
+    /// 読みかけのリクエストを送り終えるべき期限。
+    request_deadline: Option<Instant>,
+}
+
+impl Connection {
+    /// WouldBlockになるか`input`が上限に達するまで読み込む。
+    /// エラーなら`false`を返す。
+    fn fill(&mut self) -> bool {
+        let mut chunk = [0; 4096];
+        loop {
+            // 処理中に送られ続けても、読み込むのは上限まで
+            self.full = self.input.len() >= MAX_INPUT;
+            if self.full {
+                return true;
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    return true;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&chunk[..n]);
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// 書き込めるだけ書き込む。エラーなら`false`を返す。
+    fn flush(&mut self) -> bool {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.written += n;
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        self.output.clear();
+        self.written = 0;
+        if let Some((shared, written)) = &mut self.shared {
+            while *written < shared.len() {
+                match self.stream.write(&shared[*written..]) {
+                    Ok(0) => return false,
+                    Ok(n) => {
+                        *written += n;
+                        self.last_activity = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return false,
+                }
+            }
+            self.shared = None;
+        }
+        if let Some(file) = &mut self.file {
+            while !file.is_done() {
+                match file.send_to(&mut self.stream) {
+                    Ok(_) => self.last_activity = Instant::now(),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return false,
+                }
+            }
+            self.file = None;
+        }
+        true
+    }
+
+    /// 送信中のデータがあるか。
+    fn sending(&self) -> bool {
+        !self.output.is_empty() || self.shared.is_some() || self.file.is_some()
+    }
+
+    /// この時刻を過ぎたら閉じる。ハンドラの処理中は期限を設けない。
+    fn deadline(&self, idle_timeout: Duration) -> Option<Instant> {
+        if self.in_flight.is_some() {
+            None
+        } else if self.sending() {
+            Some(self.last_activity + server::WRITE_TIMEOUT)
+        } else if let Some(deadline) = self.request_deadline {
+            Some(deadline)
+        } else {
+            Some(self.last_activity + idle_timeout)
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        if !self.sending() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        }
+    }
+}
+
+struct IoThread {
+    poll: Poll,
+    acceptors: Vec<Acceptor>,
+    waker: Arc<Waker>,
+    done_sender: mpsc::Sender<(Token, Response)>,
+    done_receiver: mpsc::Receiver<(Token, Response)>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    pool: Arc<ThreadPool>,
+    handler: Handler,
+    priority: fn(&Request) -> Priority,
+    stream_body: fn(&Request) -> bool,
+    /// プールに接続ごと渡した処理。停止の猶予中はこれも待つ。
+    handed_over: Arc<InFlight>,
+    shutdown: Shutdown,
+    /// 停止の合図を受けて接続を閉じている途中か。
+    draining: bool,
+    /// 待機中の接続を閉じるまでの時間。
+    idle_timeout: Duration,
+    /// リクエストを読み始めてから送り終えるまでの期限。
+    request_timeout: Duration,
+}
+
+impl IoThread {
+    fn new(
+        mut acceptors: Vec<Acceptor>,
+        pool: Arc<ThreadPool>,
+        handler: Handler,
+        priority: fn(&Request) -> Priority,
+        stream_body: fn(&Request) -> bool,
+        handed_over: Arc<InFlight>,
+        shutdown: Shutdown,
+    ) -> io::Result<IoThread> {
+        let poll = Poll::new()?;
+        for (i, acceptor) in acceptors.iter_mut().enumerate() {
+            poll.registry()
+                .register(acceptor.source(), Token(1 + i), Interest::READABLE)?;
+        }
+        let next_token = 1 + acceptors.len();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (done_sender, done_receiver) = mpsc::channel();
+        Ok(IoThread {
+            poll,
+            acceptors,
+            waker,
+            done_sender,
+            done_receiver,
+            connections: HashMap::new(),
+            next_token,
+            pool,
+            handler,
+            priority,
+            stream_body,
+            handed_over,
+            shutdown,
+            draining: false,
+            idle_timeout: IDLE_TIMEOUT,
+            request_timeout: server::READ_TIMEOUT,
+        })
+    }
+
+    /// 停止の合図の後、接続がすべて閉じたら`true`、猶予時間を過ぎたら
+    /// `false`を返す。
+    fn run(&mut self) -> io::Result<bool> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            // 期限を過ぎた接続を閉じ、次の期限まで待つ
+            let mut next = self.expire();
+            if self.draining {
+                if self.connections.is_empty() {
+                    return Ok(true);
+                }
+                let deadline = self.shutdown.deadline().unwrap();
+                if Instant::now() >= deadline {
+                    return Ok(false);
+                }
+                next = Some(next.map_or(deadline, |next| next.min(deadline)));
+            }
+            let timeout = next.map(|next| next.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        self.finish_responses();
+                        if !self.draining && self.shutdown.is_triggered() {
+                            self.drain();
+                        }
+                    }
+                    Token(i) if i <= self.acceptors.len() => self.accept(i - 1),
+                    token => {
+                        let alive = match self.connections.get_mut(&token) {
+                            Some(conn) => {
+                                // 読み取り側だけ閉じたのはhalf-closeなので、まだ応答できる
+                                let hung_up = event.is_error()
+                                    || (event.is_read_closed() && event.is_write_closed());
+                                !hung_up
+                                    && (!event.is_readable() || conn.fill())
+                                    && (!event.is_writable() || conn.flush())
+                            }
+                            None => continue,
+                        };
+                        if alive {
+                            self.advance(token);
+                        } else {
+                            self.close(token);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 期限を過ぎた接続を閉じ、残りの接続の最も近い期限を返す。リクエストを
+    /// 送り終えなかった接続には408を返してから閉じる。
+    fn expire(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut next: Option<Instant> = None;
+        let mut expired = Vec::new();
+        for (&token, conn) in &self.connections {
+            match conn.deadline(self.idle_timeout) {
+                Some(deadline) if deadline <= now => expired.push(token),
+                Some(deadline) => next = Some(next.map_or(deadline, |next| next.min(deadline))),
+                None => {}
+            }
+        }
+        for token in expired {
+            let conn = self.connections.get_mut(&token).unwrap();
+            if conn.request_deadline.is_some() && !conn.sending() {
+                let response = Response::new(408).with_header("Connection", "close");
+                response.write_to(&mut conn.output).unwrap();
+                // 書けるだけ書き、残りは待たない
+                conn.flush();
+            }
+            self.close(token);
+        }
+        next
+    }
+
+    /// 受け付けをやめ、待機中の接続を閉じる。処理中や送信中の接続は
+    /// 今の応答を最後に閉じるようにする。
+    fn drain(&mut self) {
+        self.draining = true;
+        for acceptor in &mut self.acceptors {
+            let _ = self.poll.registry().deregister(acceptor.source());
+        }
+        let idle: Vec<Token> = self
+            .connections
+            .iter_mut()
+            .filter_map(|(&token, conn)| {
+                conn.keep_alive = false;
+                let busy = conn.in_flight.is_some() || conn.sending();
+                (!busy).then_some(token)
+            })
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            match self.acceptors[index].accept() {
+                Ok((mut stream, peer)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    if let Err(e) =
+                        self.poll
+                            .registry()
+                            .register(&mut stream, token, Interest::READABLE)
+                    {
+                        eprintln!("register failed: {}", e);
+                        continue;
+                    }
+                    self.connections.insert(
+                        token,
+                        Connection {
+                            stream,
+                            input: Vec::new(),
+                            output: Vec::new(),
+                            written: 0,
+                            shared: None,
+                            file: None,
+                            peer,
+                            in_flight: None,
+                            keep_alive: true,
+                            eof: false,
+                            full: false,
+                            last_activity: Instant::now(),
+                            request_deadline: None,
+                        },
+                    );
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// 接続の状態を進める: 書き込み待ちが無ければ次のリクエストを解析して
+    /// プールに投げ、閉じるべき接続は閉じる。
+    fn advance(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if conn.sending() || conn.in_flight.is_some() {
+            let interest = conn.interest();
+            if self
+                .poll
+                .registry()
+                .reregister(&mut conn.stream, token, interest)
+                .is_err()
+            {
+                self.close(token);
+            }
+            return;
+        }
+        if !conn.keep_alive {
+            self.close(token);
+            return;
+        }
+        // 上限で読むのをやめていたら、エッジトリガーでは通知が来ないので続きを読む
+        if conn.full && !conn.fill() {
+            self.close(token);
+            return;
+        }
+
+        match Request::parse_with(&conn.input, self.stream_body) {
+            Ok(Some((mut request, used))) => {
+                conn.input.drain(..used);
+                conn.request_deadline = None;
+                request.peer = conn.peer;
+                if (self.stream_body)(&request) {
+                    self.hand_over(token, request);
+                    return;
+                }
+                conn.keep_alive = request.keep_alive();
+                let cancel = CancellationToken::new();
+                conn.in_flight = Some(cancel.clone());
+                self.dispatch(token, request, cancel);
+            }
+            Ok(None) if conn.eof => self.close(token),
+            // 読み始めたリクエストは、少しずつ送られても期限までに揃わなければ断る
+            Ok(None) if conn.input.is_empty() => {}
+            Ok(None) => {
+                let timeout = self.request_timeout;
+                conn.request_deadline
+                    .get_or_insert_with(|| Instant::now() + timeout);
+            }
+            Err(e) => {
+                conn.keep_alive = false;
+                Response::rejecting(&e).write_to(&mut conn.output).unwrap();
+                if conn.flush() {
+                    self.advance(token);
+                } else {
+                    self.close(token);
+                }
+            }
+        }
+    }
+
+    /// ボディを読ませるリクエストは、接続ごとプールに渡してブロッキングで
+    /// 処理する。アップグレードと同じく、もうこのスレッドでは扱わない。
+    fn hand_over(&mut self, token: Token, mut request: Request) {
+        let mut conn = self.connections.remove(&token).unwrap();
+        let _ = self.poll.registry().deregister(&mut conn.stream);
+        let stream: Stream = conn.stream.into();
+        let reader = stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_read_timeout(Some(server::READ_TIMEOUT)))
+            .and_then(|()| stream.try_clone());
+        let reader = match reader {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("cannot hand over connection: {}", e);
+                return;
+            }
+        };
+        // ヘッダ部と一緒に読み込んでいたボディの先頭から読ませる
+        request.set_body_reader(conn.input, reader);
+        let options = JobOptions::new().priority((self.priority)(&request));
+        let handler = Arc::clone(&self.handler);
+        let guard = self.handed_over.enter();
+        self.pool.execute_with(options, move || {
+            server::respond(stream, &handler, &request);
+            drop(guard);
+        });
+    }
+
+    fn dispatch(&self, token: Token, request: Request, cancel: CancellationToken) {
+        let options = JobOptions::new()
+            .priority((self.priority)(&request))
+            .cancel_token(&cancel);
+        let handler = Arc::clone(&self.handler);
+        let done = self.done_sender.clone();
+        let waker = Arc::clone(&self.waker);
+        self.pool.execute_with(options, move || {
+            // ハンドラがパニックしても、接続が応答を待ち続けないよう500を返す
+            let response = panic::catch_unwind(AssertUnwindSafe(|| handler(&request)))
+                .unwrap_or_else(|_| {
+                    eprintln!("handler panicked on {} {}", request.method, request.path);
+                    Response::new(500)
+                });
+            if done.send((token, response)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    /// プールで完了したレスポンスを接続の送信バッファへ移す。
+    fn finish_responses(&mut self) {
+        while let Ok((token, mut response)) = self.done_receiver.try_recv() {
+            let conn = match self.connections.get_mut(&token) {
+                Some(conn) => conn,
+                // 応答を待つ間にクライアントが切断した
+                None => continue,
+            };
+            conn.in_flight = None;
+            conn.last_activity = Instant::now();
+
+            if let Some(upgrade) = response.upgrade.take() {
+                let mut conn = self.connections.remove(&token).unwrap();
+                let _ = self.poll.registry().deregister(&mut conn.stream);
+                let stream: Stream = conn.stream.into();
+                if stream.set_nonblocking(false).is_ok() {
+                    let guard = self.handed_over.enter();
+                    self.pool.execute(move || {
+                        upgrade(stream);
+                        drop(guard);
+                    });
+                }
+                continue;
+            }
+
+            let connection = if conn.keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            };
+            let mut response = response.with_header("Connection", connection);
+            conn.shared = response.shared.take().map(|shared| (shared, 0));
+            conn.file = response.file.take();
+            response.write_to(&mut conn.output).unwrap();
+            if conn.flush() {
+                self.advance(token);
+            } else {
+                self.close(token);
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut conn) = self.connections.remove(&token) {
+            if let Some(cancel) = conn.in_flight {
+                cancel.cancel();
+            }
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Address;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::net::TcpStream;
+
+    fn start(handler: Handler) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = Arc::new(ThreadPool::new(2));
+        thread::spawn(move || serve(listener, pool, handler, 1));
+        addr
+    }
+
+    fn read_response(reader: &mut BufReader<TcpStream>) -> (String, String) {
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(v) = line.strip_prefix("Content-Length: ") {
+                length = v.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn idle_and_slow_connections_are_closed() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener: Listener = listener.into();
+        listener.set_nonblocking(true).unwrap();
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200));
+        let mut io = IoThread::new(
+            vec![Acceptor::from_std(listener)],
+            Arc::new(ThreadPool::new(1)),
+            handler,
+            |_| Priority::Normal,
+            |_| false,
+            Arc::new(InFlight::new()),
+            Shutdown::new(Duration::ZERO),
+        )
+        .unwrap();
+        io.idle_timeout = Duration::from_millis(100);
+        io.request_timeout = Duration::from_millis(300);
+        thread::spawn(move || io.run());
+
+        // 何も送らない接続は、待機の時間で閉じられる
+        let started = Instant::now();
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let mut got = Vec::new();
+        idle.read_to_end(&mut got).unwrap();
+        assert!(got.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 少しずつ送り続けても、リクエストの期限を過ぎたら408で閉じられる
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let mut writer = slow.try_clone().unwrap();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                if writer.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut got = String::new();
+        let _ = slow.read_to_string(&mut got);
+        assert!(
+            got.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            got
+        );
+    }
+
+    #[test]
+    fn keep_alive_serves_several_requests() {
+        let addr = start(Arc::new(|request: &Request| {
+            Response::new(200).with_body(request.path.clone())
+        }));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // 2つ目は1つ目の応答を待たずに送る(パイプライン)
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        assert_eq!(
+            read_response(&mut reader),
+            ("HTTP/1.1 200 OK\r\n".to_string(), "/a".to_string())
+        );
+        assert_eq!(read_response(&mut reader).1, "/b");
+    }
+
+    #[test]
+    fn handler_panics_become_500s() {
+        let addr = start(Arc::new(|request: &Request| {
+            if request.path == "/boom" {
+                panic!("boom");
+            }
+            Response::new(200).with_body(request.path.clone())
+        }));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /boom HTTP/1.1\r\n\r\nGET /after HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        assert_eq!(
+            read_response(&mut reader).0,
+            "HTTP/1.1 500 Internal Server Error\r\n"
+        );
+        assert_eq!(read_response(&mut reader).1, "/after");
+    }
+
+    #[test]
+    fn half_closed_clients_get_their_response() {
+        let addr = start(Arc::new(|_: &Request| {
+            thread::sleep(Duration::from_millis(100));
+            Response::new(200).with_body("late")
+        }));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        stream.shutdown(net::Shutdown::Write).unwrap();
+        let mut reader = BufReader::new(stream);
+        assert_eq!(read_response(&mut reader).1, "late");
+    }
+
+    #[test]
+    fn file_bodies_keep_the_connection_alive() {
+        let path = std::env::temp_dir().join(format!("event-loop-file-{}", std::process::id()));
+        let data = "x".repeat(1 << 20);
+        std::fs::write(&path, &data).unwrap();
+        let file_path = path.clone();
+        let addr = start(Arc::new(move |_: &Request| {
+            let file = std::fs::File::open(&file_path).unwrap();
+            Response::new(200).with_file(file, 1 << 20)
+        }));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        assert_eq!(read_response(&mut reader).1, data);
+        assert_eq!(read_response(&mut reader).1, data);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn upgrade_hands_over_blocking_stream() {
+        let addr = start(Arc::new(|_: &Request| {
+            Response::upgrade(|mut stream| {
+                stream.write_all(b"taken over").unwrap();
+            })
+        }));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut got = String::new();
+        stream.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "taken over");
+    }
+
+    #[test]
+    fn streamed_bodies_are_read_by_the_handler() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler: Handler = Arc::new(|request: &Request| {
+            let mut body = request.take_body_reader().unwrap();
+            let read = io::copy(&mut body, &mut io::sink()).unwrap();
+            Response::new(200).with_body(read.to_string())
+        });
+        thread::spawn(move || {
+            serve_until(
+                vec![listener.into()],
+                Arc::new(ThreadPool::new(2)),
+                handler,
+                1,
+                |_| Priority::Normal,
+                |request| request.method == "PUT",
+                &Shutdown::new(Duration::ZERO),
+            )
+        });
+
+        // 読み込みの上限より大きなボディでも、ハンドラが読む分には構わない
+        let length = 2 * MAX_INPUT;
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = format!("PUT /f HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&vec![b'x'; length]).unwrap();
+        let mut reader = BufReader::new(stream);
+        let (status, body) = read_response(&mut reader);
+        assert!(status.starts_with("HTTP/1.1 200"));
+        assert_eq!(body, length.to_string());
+    }
+
+    #[test]
+    fn serves_every_listener() {
+        let path = std::env::temp_dir().join(format!("event-loop-{}.sock", std::process::id()));
+        let unix = Address::Unix(path.clone());
+        let listeners = vec![
+            Listener::bind(&"127.0.0.1:0".parse().unwrap(), 0).unwrap(),
+            Listener::bind(&"[::1]:0".parse().unwrap(), 0).unwrap(),
+            Listener::bind(&unix, 0o600).unwrap(),
+        ];
+        let addresses: Vec<Address> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let pool = Arc::new(ThreadPool::new(2));
+        let handler: Handler = Arc::new(|request: &Request| {
+            let peer = request
+                .peer
+                .map_or("unix".to_string(), |p| p.ip().to_string());
+            Response::new(200).with_body(peer)
+        });
+        thread::spawn(move || {
+            serve_until(
+                listeners,
+                pool,
+                handler,
+                1,
+                |_| Priority::Normal,
+                |_| false,
+                &Shutdown::new(Duration::ZERO),
+            )
+        });
+
+        let peers: Vec<String> = addresses
+            .iter()
+            .map(|address| {
+                let mut stream = address.connect().unwrap();
+                stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+                let mut got = String::new();
+                stream.read_to_string(&mut got).unwrap();
+                got.rsplit("\r\n").next().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(peers, ["127.0.0.1", "::1", "unix"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_and_finishes_busy_connections() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new(Duration::from_secs(5));
+        let (started, wait_started) = mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let handler: Handler = Arc::new(move |request: &Request| {
+            if request.path == "/slow" {
+                started.lock().unwrap().send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            }
+            Response::new(200).with_body(request.path.clone())
+        });
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                let pool = Arc::new(ThreadPool::new(2));
+                let listeners = vec![listener.into()];
+                serve_until(
+                    listeners,
+                    pool,
+                    handler,
+                    1,
+                    |_| Priority::Normal,
+                    |_| false,
+                    &shutdown,
+                )
+            })
+        };
+
+        // 応答を受け取ってキープアライブで待機している接続
+        let mut idle = BufReader::new(TcpStream::connect(addr).unwrap());
+        idle.get_mut()
+            .write_all(b"GET /a HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut idle).1, "/a");
+        // ハンドラの実行中に停止の合図を受ける接続
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        wait_started.recv().unwrap();
+        shutdown.trigger();
+
+        let mut rest = Vec::new();
+        idle.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        let mut got = String::new();
+        busy.read_to_string(&mut got).unwrap();
+        assert!(got.contains("Connection: close\r\n"));
+        assert!(got.ends_with("/slow"));
+        assert!(server.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn shutdown_waits_for_handed_over_requests() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new(Duration::from_secs(5));
+        let (started, wait_started) = mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let finished = Arc::new(AtomicBool::new(false));
+        let handler: Handler = {
+            let finished = Arc::clone(&finished);
+            Arc::new(move |_: &Request| {
+                started.lock().unwrap().send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                finished.store(true, Ordering::SeqCst);
+                Response::new(204)
+            })
+        };
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                serve_until(
+                    vec![listener.into()],
+                    Arc::new(ThreadPool::new(2)),
+                    handler,
+                    1,
+                    |_| Priority::Normal,
+                    |_| true,
+                    &shutdown,
+                )
+            })
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"PUT /f HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        wait_started.recv().unwrap();
+        shutdown.trigger();
+        // I/Oスレッドの手を離れた接続でも、終わるまで待ってから戻る
+        assert!(server.join().unwrap().unwrap());
+        assert!(finished.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,444 @@
+//! FastCGIのクライアント。
+//!
+//! php-fpmのような常駐するFastCGIレスポンダへ、TCPかUnixドメインソケットで
+//! リクエストを転送します。1リクエストごとに接続し、応答の`FCGI_STDOUT`を
+//! 届いた分からクライアントへ流します。
+//!
+//! A FastCGI client. Requests are forwarded to a long-running responder such
+//! as php-fpm over TCP or a Unix socket, one connection per request, and the
+//! responder's stdout is relayed to the client as it arrives.
+
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cgi;
+use crate::http::{self, Handler, Request, Response};
+use crate::listener::{Address, Stream};
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// 1接続に1リクエストしか流さないので、IDは常に1。
+const REQUEST_ID: u16 = 1;
+/// 1レコードに載せられる最大のバイト数。
+const MAX_CONTENT: usize = 0xffff;
+
+/// FastCGIレスポンダへ転送するハンドラ。
+///
+/// Forwards requests under mounted prefixes to a FastCGI responder.
+pub struct FastCgi {
+    address: Address,
+    mounts: Vec<(String, PathBuf)>,
+    timeout: Duration,
+}
+
+impl FastCgi {
+    /// `address`のレスポンダへ転送する。`unix:`のアドレスも使えます。
+    ///
+    /// Forward to the responder at `address`, which may be a Unix socket.
+    pub fn new(address: Address) -> FastCgi {
+        FastCgi {
+            address,
+            mounts: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// `prefix`配下のリクエストを転送する。`SCRIPT_FILENAME`は`root`に
+    /// `prefix`以降のパスをつないだものになります。
+    ///
+    /// Forward requests under `prefix`. `SCRIPT_FILENAME` is the rest of the
+    /// path joined onto `root`, which is also sent as `DOCUMENT_ROOT`.
+    pub fn mount<P: AsRef<Path>>(mut self, prefix: &str, root: P) -> FastCgi {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.mounts.push((prefix, root.as_ref().to_path_buf()));
+        self
+    }
+
+    /// 接続から応答を受け取り終えるまでの上限。過ぎたら接続を切ります。
+    /// 既定は30秒です。
+    ///
+    /// How long the responder has to send its whole response before the
+    /// connection is dropped. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> FastCgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 配下のリクエストを転送し、それ以外は`handler`に渡す。
+    ///
+    /// Forward requests under a mount and pass the rest to `handler`.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let fastcgi = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let (prefix, root) = match fastcgi
+                .mounts
+                .iter()
+                .filter(|(prefix, _)| http::path_has_prefix(&request.path, prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+            {
+                Some(mount) => mount,
+                None => return handler(request),
+            };
+            let rest = request.path[prefix.len()..].trim_start_matches('/');
+            if rest.split('/').any(|segment| segment == "..") {
+                return Response::new(400);
+            }
+            let mut params = cgi::environment(request, &request.path, "");
+            params.push((
+                "SCRIPT_FILENAME".to_string(),
+                root.join(rest).to_string_lossy().into_owned(),
+            ));
+            params.push((
+                "DOCUMENT_ROOT".to_string(),
+                root.to_string_lossy().into_owned(),
+            ));
+            let body = request.body.clone();
+            let head_only = request.method == "HEAD";
+            let fastcgi = Arc::clone(&fastcgi);
+            Response::upgrade(move |mut stream| {
+                fastcgi.forward(&params, &body, head_only, &mut stream);
+            })
+        })
+    }
+
+    fn forward(
+        &self,
+        params: &[(String, String)],
+        body: &[u8],
+        head_only: bool,
+        stream: &mut Stream,
+    ) {
+        let deadline = Instant::now() + self.timeout;
+        let mut responder = match self.address.connect_timeout(self.timeout) {
+            Ok(responder) => responder,
+            Err(e) => {
+                eprintln!("fastcgi: cannot connect to {}: {}", self.address, e);
+                let _ = cgi::gateway_error(502).write_to(stream);
+                return;
+            }
+        };
+        if let Err(e) = responder
+            .set_write_timeout(Some(self.timeout))
+            .and_then(|()| send_request(&mut responder, params, body))
+            .map_err(timed_out)
+        {
+            eprintln!("fastcgi: sending request failed: {}", e);
+            let status = if e.kind() == io::ErrorKind::TimedOut {
+                504
+            } else {
+                502
+            };
+            let _ = cgi::gateway_error(status).write_to(stream);
+            return;
+        }
+        let stdout = Stdout {
+            responder,
+            deadline,
+            remaining: 0,
+            padding: 0,
+            ended: false,
+        };
+        cgi::relay(
+            BufReader::new(stdout),
+            stream,
+            head_only,
+            self.timeout,
+            || Instant::now() >= deadline,
+        );
+    }
+}
+
+/// `FCGI_BEGIN_REQUEST`、`FCGI_PARAMS`、`FCGI_STDIN`を送る。
+fn send_request<W: Write>(
+    writer: &mut W,
+    params: &[(String, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut out = Vec::new();
+    let mut begin = [0; 8];
+    begin[..2].copy_from_slice(&RESPONDER.to_be_bytes());
+    write_record(&mut out, BEGIN_REQUEST, &begin);
+
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    write_stream(&mut out, PARAMS, &encoded);
+    write_stream(&mut out, STDIN, body);
+    writer.write_all(&out)?;
+    writer.flush()
+}
+
+/// 長さが127以下なら1バイト、それ以上なら最上位ビットを立てた4バイトで表す。
+fn encode_length(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// レコードに分けて書き、最後に空のレコードでストリームの終わりを示す。
+fn write_stream(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    for chunk in data.chunks(MAX_CONTENT) {
+        write_record(out, kind, chunk);
+    }
+    write_record(out, kind, &[]);
+}
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    // 8バイト境界にそろえる
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 7][..padding]);
+}
+
+/// 読み書きのタイムアウトはLinuxでは`WouldBlock`になるので、`TimedOut`にそろえる。
+fn timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut.into(),
+        _ => e,
+    }
+}
+
+/// レスポンダからのレコードを読み、`FCGI_STDOUT`の中身だけを返すリーダー。
+/// `FCGI_STDERR`はサーバーのログへ出します。
+struct Stdout {
+    responder: Stream,
+    deadline: Instant,
+    /// 読みかけの`FCGI_STDOUT`レコードの残り
+    remaining: usize,
+    padding: usize,
+    ended: bool,
+}
+
+impl Stdout {
+    /// 期限までの残り時間を読み込みのタイムアウトにする。
+    fn arm(&mut self) -> io::Result<()> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.responder.set_read_timeout(Some(left))
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.arm()?;
+        self.responder.read_exact(buf).map_err(timed_out)
+    }
+
+    /// 次の`FCGI_STDOUT`の中身が来るまでレコードを読む。
+    /// `FCGI_END_REQUEST`に達したら`false`を返す。
+    fn next_stdout(&mut self) -> io::Result<bool> {
+        loop {
+            let mut skip = vec![0; self.padding];
+            self.read_exact(&mut skip)?;
+            self.padding = 0;
+
+            let mut header = [0; 8];
+            self.read_exact(&mut header)?;
+            let kind = header[1];
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            self.padding = header[6] as usize;
+            match kind {
+                STDOUT if len > 0 => {
+                    self.remaining = len;
+                    return Ok(true);
+                }
+                STDOUT => {}
+                STDERR | END_REQUEST => {
+                    let mut content = vec![0; len];
+                    self.read_exact(&mut content)?;
+                    if kind == END_REQUEST {
+                        return Ok(false);
+                    }
+                    eprint!("fastcgi: {}", String::from_utf8_lossy(&content));
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected record type {}", kind),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Read for Stdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && (self.ended || !self.next_stdout()?) {
+            self.ended = true;
+            return Ok(0);
+        }
+        let n = buf.len().min(self.remaining);
+        self.arm()?;
+        let n = self.responder.read(&mut buf[..n]).map_err(timed_out)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// レコードを1つ読み、種類と中身を返す。
+    fn read_record(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).unwrap();
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; len + header[6] as usize];
+        stream.read_exact(&mut content).unwrap();
+        content.truncate(len);
+        (header[1], content)
+    }
+
+    fn decode_params(mut data: &[u8]) -> Vec<(String, String)> {
+        let length = |data: &mut &[u8]| {
+            if data[0] < 0x80 {
+                let len = data[0] as usize;
+                *data = &data[1..];
+                len
+            } else {
+                let len = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]);
+                *data = &data[4..];
+                len as usize
+            }
+        };
+        let mut params = Vec::new();
+        while !data.is_empty() {
+            let name_len = length(&mut data);
+            let value_len = length(&mut data);
+            let name = String::from_utf8(data[..name_len].to_vec()).unwrap();
+            let value = String::from_utf8(data[name_len..name_len + value_len].to_vec()).unwrap();
+            data = &data[name_len + value_len..];
+            params.push((name, value));
+        }
+        params
+    }
+
+    fn exchange(handler: &Handler, raw: &str) -> String {
+        let request = Request::parse(raw.as_bytes()).unwrap().unwrap().0;
+        let upgrade = handler(&request).upgrade.take().expect("not an upgrade");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        upgrade(server.into());
+        let mut got = String::new();
+        client.read_to_string(&mut got).unwrap();
+        got
+    }
+
+    fn not_found() -> Handler {
+        Arc::new(|_: &Request| Response::new(404))
+    }
+
+    #[test]
+    fn forwards_requests_and_relays_stdout() {
+        let responder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = Address::Tcp(responder.local_addr().unwrap());
+        let fake = thread::spawn(move || {
+            let (mut stream, _) = responder.accept().unwrap();
+            let (kind, begin) = read_record(&mut stream);
+            assert_eq!((kind, &begin[..2]), (BEGIN_REQUEST, &[0, 1][..]));
+            let mut params = Vec::new();
+            loop {
+                let (kind, content) = read_record(&mut stream);
+                assert_eq!(kind, PARAMS);
+                if content.is_empty() {
+                    break;
+                }
+                params.extend(content);
+            }
+            let (kind, stdin) = read_record(&mut stream);
+            assert_eq!((kind, &stdin[..]), (STDIN, &b"name=x"[..]));
+            assert_eq!(read_record(&mut stream), (STDIN, Vec::new()));
+
+            let mut out = Vec::new();
+            write_record(
+                &mut out,
+                STDOUT,
+                b"Status: 404 Not Found\r\nContent-Type: text/pl",
+            );
+            write_record(&mut out, STDERR, b"warning\n");
+            write_record(&mut out, STDOUT, b"ain\r\n\r\nno such page");
+            write_record(&mut out, STDOUT, b"");
+            write_record(&mut out, END_REQUEST, &[0; 8]);
+            stream.write_all(&out).unwrap();
+            decode_params(&params)
+        });
+
+        let handler = FastCgi::new(address)
+            .mount("/php", "/srv/php")
+            .wrap(not_found());
+        let got = exchange(
+            &handler,
+            "POST /php/index.php?a=1 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 6\r\n\r\nname=x",
+        );
+        assert!(got.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", got);
+        assert!(got.contains("Content-Type: text/plain\r\n"));
+        assert!(got.ends_with("\r\n\r\nno such page"));
+
+        let params = fake.join().unwrap();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(param("SCRIPT_FILENAME"), Some("/srv/php/index.php"));
+        assert_eq!(param("SCRIPT_NAME"), Some("/php/index.php"));
+        assert_eq!(param("QUERY_STRING"), Some("a=1"));
+        assert_eq!(param("SERVER_PORT"), Some("8080"));
+        assert_eq!(param("CONTENT_LENGTH"), Some("6"));
+    }
+
+    #[test]
+    fn times_out_slow_responders() {
+        let responder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = Address::Tcp(responder.local_addr().unwrap());
+        let fake = thread::spawn(move || {
+            let (stream, _) = responder.accept().unwrap();
+            thread::sleep(Duration::from_millis(500));
+            drop(stream);
+        });
+        let handler = FastCgi::new(address)
+            .mount("/php", "/srv/php")
+            .timeout(Duration::from_millis(100))
+            .wrap(not_found());
+        let got = exchange(&handler, "GET /php/slow.php HTTP/1.1\r\n\r\n");
+        assert!(
+            got.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            got
+        );
+        fake.join().unwrap();
+
+        let request = Request::parse(b"GET /other HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap()
+            .0;
+        assert_eq!(handler(&request).status, 404);
+    }
+}
@@ -0,0 +1,625 @@
+//! 最小限のHTTP/1.1リクエストとレスポンス。
+//!
+//! Minimal HTTP/1.1 request and response types.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::cookie::{self, Cookie};
+use crate::listener::Stream;
+use crate::sendfile::FileBody;
+
+/// リクエストヘッダ部の上限バイト数。
+const MAX_HEAD: usize = 8 * 1024;
+
+/// サーバーがメモリに読み込むリクエストボディの上限バイト数。
+///
+/// The largest request body the servers buffer in memory.
+pub const MAX_BODY: usize = 1024 * 1024;
+
+/// リクエストを受け取りレスポンスを返すハンドラ。どのI/O方式からも共有されます。
+///
+/// A request handler, shared by every I/O mode of the server.
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// レスポンスの代わりに接続そのものを引き取る処理。
+///
+/// Takes over the raw connection instead of writing a response.
+pub type Upgrade = Box<dyn FnOnce(Stream) + Send>;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// 接続元のアドレス。解析しただけのリクエストでは`None`です。
+    ///
+    /// The client's address, filled in by the server; `None` for requests
+    /// that were only parsed.
+    pub peer: Option<SocketAddr>,
+    pub extensions: Extensions,
+    body_reader: Mutex<Option<BodyReader>>,
+}
+
+/// サーバーがメモリに読み込まずに残したリクエストボディ。`Content-Length`の
+/// 分だけ読め、その前に接続が閉じたら`UnexpectedEof`になります。
+///
+/// A request body the server left unread. It yields exactly
+/// `Content-Length` bytes, failing with `UnexpectedEof` if the connection
+/// ends first.
+pub struct BodyReader {
+    inner: io::Chain<io::Cursor<Vec<u8>>, Box<dyn Read + Send>>,
+    remaining: u64,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the body was complete",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// ミドルウェアがリクエストに付け足し、内側のハンドラが取り出す値。
+/// 型ごとに1つ持てます。
+///
+/// Values that middleware attaches to a request for the handlers it wraps,
+/// one per type.
+#[derive(Default)]
+pub struct Extensions {
+    map: Mutex<HashMap<TypeId, Box<dyn Any + Send>>>,
+}
+
+impl Extensions {
+    /// `value`を付ける。同じ型の値があれば置き換えます。
+    ///
+    /// Attach `value`, replacing any earlier value of the same type.
+    pub fn insert<T: Clone + Send + 'static>(&self, value: T) {
+        self.map
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    pub fn get<T: Clone + Send + 'static>(&self) -> Option<T> {
+        self.map
+            .lock()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+}
+
+impl Request {
+    /// バッファの先頭からリクエストを1つ解析する。
+    /// まだ全体が届いていなければ`Ok(None)`を返します。
+    ///
+    /// Parse one request from the start of `buf`, returning the request and
+    /// the number of bytes it used, or `Ok(None)` if it is still incomplete.
+    pub fn parse(buf: &[u8]) -> io::Result<Option<(Request, usize)>> {
+        Request::parse_with(buf, |_| false)
+    }
+
+    /// `parse`と同じだが、`stream_body`が真を返すリクエストはボディを待たずに
+    /// 返す。そのボディは`MAX_BODY`で制限せず、使ったバイト数はヘッダ部だけです。
+    /// サーバーは`set_body_reader`で続きを読めるようにします。
+    ///
+    /// Like `parse`, but requests for which `stream_body` returns true are
+    /// returned as soon as their head is complete. Their body is not bounded
+    /// by `MAX_BODY` and is not counted in the bytes used; servers hand it
+    /// over with `set_body_reader`.
+    pub fn parse_with(
+        buf: &[u8],
+        stream_body: fn(&Request) -> bool,
+    ) -> io::Result<Option<(Request, usize)>> {
+        let head_end = match find(buf, b"\r\n\r\n") {
+            Some(i) => i,
+            None if buf.len() > MAX_HEAD => return Err(invalid("request head too large")),
+            None => return Ok(None),
+        };
+        let head = std::str::from_utf8(&buf[..head_end])
+            .map_err(|_| invalid("request head is not UTF-8"))?;
+        let mut lines = head.split("\r\n");
+
+        let mut parts = lines.next().unwrap_or("").split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return Err(invalid("malformed request line")),
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p, Some(q.to_string())),
+            None => (target, None),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("malformed header line"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            peer: None,
+            extensions: Extensions::default(),
+            body_reader: Mutex::new(None),
+        };
+
+        // チャンク形式は読めないので、ボディの長さを取り違えないよう拒否する
+        if request.header("Transfer-Encoding").is_some() {
+            return Err(rejected(501, "Transfer-Encoding is not supported"));
+        }
+        let body_start = head_end + 4;
+        let length = match request.header("Content-Length") {
+            Some(v) => v
+                .parse::<usize>()
+                .map_err(|_| invalid("invalid Content-Length"))?,
+            None => 0,
+        };
+        if stream_body(&request) {
+            return Ok(Some((request, body_start)));
+        }
+        // ボディが届く前に、ヘッダだけで大きすぎるものを断る
+        let end = match body_start.checked_add(length) {
+            Some(end) if length <= MAX_BODY => end,
+            _ => return Err(rejected(413, "request body too large")),
+        };
+        if buf.len() < end {
+            return Ok(None);
+        }
+        request.body = buf[body_start..end].to_vec();
+        Ok(Some((request, end)))
+    }
+
+    /// ストリームから1つのリクエストを読み込む。
+    ///
+    /// Read a single request from a blocking reader. Errors are turned into
+    /// a response by `Response::rejecting`.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Request> {
+        Request::read_with(reader, |_| false).map(|(request, _)| request)
+    }
+
+    /// `read_from`と同じだが、`stream_body`が真を返すリクエストはボディを
+    /// 待たずに返す。ヘッダ部の後に読み込んでしまった分も返します。
+    ///
+    /// Like `read_from`, but requests for which `stream_body` returns true
+    /// are returned once their head is read, together with whatever was read
+    /// past it.
+    pub fn read_with<R: Read>(
+        reader: &mut R,
+        stream_body: fn(&Request) -> bool,
+    ) -> io::Result<(Request, Vec<u8>)> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before request was complete",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some((request, used)) = Request::parse_with(&buf, stream_body)? {
+                buf.drain(..used);
+                return Ok((request, buf));
+            }
+        }
+    }
+
+    /// 名前が一致する最初のヘッダ値を返す(大文字小文字は区別しない)。
+    ///
+    /// Return the first header value whose name matches, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// `Content-Length`の値。無ければ0です。
+    ///
+    /// The declared body length, zero without a `Content-Length`.
+    pub fn content_length(&self) -> usize {
+        self.header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// ボディを`reader`から読ませる。`prefix`はヘッダ部と一緒に読み込んでいた
+    /// ボディの先頭です。
+    ///
+    /// Let handlers read the body from `reader`, after `prefix`, the part of
+    /// it already read along with the head.
+    pub fn set_body_reader<R: Read + Send + 'static>(&mut self, prefix: Vec<u8>, reader: R) {
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        *self.body_reader.get_mut().unwrap() = Some(BodyReader {
+            inner: io::Cursor::new(prefix).chain(reader),
+            remaining: self.content_length() as u64,
+        });
+    }
+
+    /// サーバーが読まずに残したボディを受け取る。`parse_with`でボディを
+    /// 待たなかったリクエストだけにあり、受け取れるのは1度だけです。
+    ///
+    /// Take the body the server left unread. Only requests whose body was
+    /// not awaited have one, and it can be taken once.
+    pub fn take_body_reader(&self) -> Option<BodyReader> {
+        self.body_reader.lock().unwrap().take()
+    }
+
+    /// `Cookie`ヘッダから`name`の値を返す。
+    ///
+    /// The value of the cookie `name`, if the client sent one.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Cookie"))
+            .find_map(|(_, v)| cookie::find(v, name))
+    }
+
+    /// このリクエストの後も接続を維持すべきか。
+    ///
+    /// Whether the connection should stay open after this request.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// `body`の後に送るファイル。サーバーは可能なら`sendfile`で送ります。
+    ///
+    /// A file sent after `body`; servers use `sendfile` where they can.
+    pub file: Option<FileBody>,
+    /// `body`の後に送る共有のバイト列。キャッシュの内容をコピーせずに返せます。
+    ///
+    /// Shared bytes sent after `body`, so cached data is served without
+    /// copying it into every response.
+    pub shared: Option<Arc<Vec<u8>>>,
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            file: None,
+            shared: None,
+            upgrade: None,
+        }
+    }
+
+    /// 接続を`f`に引き渡すレスポンスを作る。SSEのように長く続く応答に使います。
+    ///
+    /// Build a response that hands the connection to `f`, for long-lived
+    /// replies such as event streams.
+    pub fn upgrade<F>(f: F) -> Response
+    where
+        F: FnOnce(Stream) + Send + 'static,
+    {
+        let mut response = Response::new(200);
+        response.upgrade = Some(Box::new(f));
+        response
+    }
+
+    /// `Request::parse`のエラーへの応答。大きすぎるボディは413、
+    /// 対応していない転送形式は501、ほかは400で、接続は閉じます。
+    ///
+    /// The reply to a `Request::parse` error: 413 for an oversized body, 501
+    /// for an unsupported transfer coding and 400 otherwise, closing the
+    /// connection.
+    pub fn rejecting(error: &io::Error) -> Response {
+        let status = error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Rejected>())
+            .map_or(400, |rejected| rejected.status);
+        Response::new(status).with_header("Connection", "close")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// `Set-Cookie`ヘッダを足す。
+    ///
+    /// Add a `Set-Cookie` header for `cookie`.
+    pub fn with_cookie(self, cookie: &Cookie) -> Response {
+        self.with_header("Set-Cookie", &cookie.to_string())
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// ファイルの先頭`len`バイトをボディにする。メモリには読み込まず、
+    /// 書き出すときにファイルから直接送ります。
+    ///
+    /// Use the first `len` bytes of `file` as the body. It is not read into
+    /// memory; servers send it straight from the file when writing.
+    pub fn with_file(self, file: File, len: u64) -> Response {
+        self.with_file_range(file, 0, len)
+    }
+
+    /// `with_file`と同じだが、ファイルの`offset`バイト目から送る。
+    ///
+    /// Like `with_file`, but starting `offset` bytes into the file.
+    pub fn with_file_range(mut self, file: File, offset: u64, len: u64) -> Response {
+        self.headers
+            .retain(|(n, _)| !n.eq_ignore_ascii_case("Content-Length"));
+        self.headers
+            .push(("Content-Length".to_string(), len.to_string()));
+        self.body.clear();
+        self.file = Some(FileBody::range(file, offset, len));
+        self
+    }
+
+    /// 共有のバイト列をボディにする。
+    ///
+    /// Use shared bytes, such as a cache entry, as the body without copying
+    /// them.
+    pub fn with_shared_body(mut self, data: Arc<Vec<u8>>) -> Response {
+        self.headers
+            .retain(|(n, _)| !n.eq_ignore_ascii_case("Content-Length"));
+        self.headers
+            .push(("Content-Length".to_string(), data.len().to_string()));
+        self.body.clear();
+        self.shared = Some(data);
+        self
+    }
+
+    /// ファイルや共有のボディをメモリに読み込み、`body`に移す。ボディを
+    /// 書き換える前に呼びます。
+    ///
+    /// Move a file or shared body into `body`, for code that needs to
+    /// rewrite it.
+    pub fn buffer_file(&mut self) -> io::Result<()> {
+        if let Some(shared) = self.shared.take() {
+            self.body.extend_from_slice(&shared);
+        }
+        if let Some(file) = self.file.take() {
+            let data = file.read_to_vec()?;
+            self.body.extend_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// ステータス行とヘッダだけを書き出す。ボディを自分で流す場合に使います。
+    ///
+    /// Write only the status line and headers, for handlers that stream the
+    /// body themselves.
+    pub fn write_head<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())
+    }
+
+    /// レスポンス全体を書き出す。`Content-Length`が無ければ付与します。
+    /// ファイルのボディはバッファ経由でコピーします。
+    ///
+    /// Write the whole response, adding `Content-Length` when missing. A
+    /// file body is copied through a buffer; servers that can use
+    /// `sendfile` take `file` out before calling this.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.header("Content-Length").is_none() {
+            let mut head = Response::new(self.status);
+            head.headers = self.headers.clone();
+            head.headers
+                .push(("Content-Length".to_string(), self.body.len().to_string()));
+            head.write_head(writer)?;
+        } else {
+            self.write_head(writer)?;
+        }
+        writer.write_all(&self.body)?;
+        if let Some(shared) = &self.shared {
+            writer.write_all(shared)?;
+        }
+        if let Some(file) = &self.file {
+            file.copy_to(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+/// `path`が`prefix`そのものか、その配下にあるか。`/api`は`/api/x`に一致し、
+/// `/apix`には一致しません。
+///
+/// Whether `path` is `prefix` or lies below it: `/api` matches `/api/x` but
+/// not `/apix`.
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// 400以外のステータスで断るべき、解析できないリクエスト。
+#[derive(Debug)]
+struct Rejected {
+    status: u16,
+    message: &'static str,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message)
+    }
+}
+
+impl error::Error for Rejected {}
+
+fn rejected(status: u16, message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Rejected { status, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_with_body() {
+        let raw = b"POST /submit?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabcEXTRA";
+        let (request, used) = Request::parse(raw).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/submit");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.header("host"), Some("a"));
+        assert_eq!(request.body, b"abc");
+        assert_eq!(&raw[used..], b"EXTRA");
+    }
+
+    #[test]
+    fn parse_incomplete_request() {
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\n")
+            .unwrap()
+            .is_none());
+        let partial = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(Request::parse(partial).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bodies_it_cannot_frame() {
+        let status =
+            |raw: &str| Response::rejecting(&Request::parse(raw.as_bytes()).err().unwrap()).status;
+        let huge = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert_eq!(status(&huge), 413);
+        let big = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(status(&big), 413);
+        assert_eq!(
+            status("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"),
+            501
+        );
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn streamed_bodies_are_left_to_the_handler() {
+        let length = MAX_BODY + 3;
+        let raw = format!("PUT /f HTTP/1.1\r\nContent-Length: {}\r\n\r\nab", length);
+        let (mut request, used) = Request::parse_with(raw.as_bytes(), |_| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(used, raw.len() - 2);
+        assert!(request.body.is_empty());
+        let rest = vec![b'x'; length - 2 + 5];
+        request.set_body_reader(raw.as_bytes()[used..].to_vec(), io::Cursor::new(rest));
+        let mut body = Vec::new();
+        request
+            .take_body_reader()
+            .unwrap()
+            .read_to_end(&mut body)
+            .unwrap();
+        assert_eq!(body.len(), length);
+        assert!(body.starts_with(b"abx"));
+        assert!(request.take_body_reader().is_none());
+
+        // 長さに足りないまま閉じられたボディはエラーになる
+        request.set_body_reader(b"ab".to_vec(), io::empty());
+        let mut reader = request.take_body_reader().unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_response_adds_content_length() {
+        let mut out = Vec::new();
+        Response::new(404)
+            .with_body("nope")
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope"
+        );
+    }
+}
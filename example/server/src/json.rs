@@ -0,0 +1,269 @@
+//! JSONのAPI。
+//!
+//! リクエストのボディをserdeの型に読み込み、レスポンスをJSONで返します。
+//! エラーは`{"error": {"status": 415, "reason": "...", "message": "..."}}`の
+//! 形のボディになります。
+//!
+//! JSON APIs: request bodies are read into serde types and responses are
+//! serialized back. Errors get a structured body of the form
+//! `{"error": {"status": 415, "reason": "...", "message": "..."}}`.
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+
+use crate::http::{reason_phrase, Handler, Request, Response};
+
+/// `body`が受け付けるボディの上限バイト数。
+///
+/// The largest body `body` accepts.
+pub const DEFAULT_LIMIT: usize = 64 * 1024;
+
+/// JSONで返すエラー。
+///
+/// An error answered with a JSON body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub status: u16,
+    pub message: String,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    status: u16,
+    reason: &'a str,
+    message: &'a str,
+}
+
+impl Error {
+    pub fn new<M: Into<String>>(status: u16, message: M) -> Error {
+        Error {
+            status,
+            message: message.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn bad_request<M: Into<String>>(message: M) -> Error {
+        Error::new(400, message)
+    }
+
+    pub fn not_found() -> Error {
+        Error::new(404, "no such resource")
+    }
+
+    /// `Allow`ヘッダ付きの405。
+    ///
+    /// A 405 with an `Allow` header listing `allowed`.
+    pub fn method_not_allowed(allowed: &[&str]) -> Error {
+        Error::new(405, "method not allowed").with_header("Allow", &allowed.join(", "))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Error {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                status: self.status,
+                reason: reason_phrase(self.status),
+                message: &self.message,
+            },
+        };
+        let mut response = response(self.status, &body);
+        response.headers.extend(self.headers);
+        response
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for Response {
+    fn from(error: Error) -> Response {
+        error.into_response()
+    }
+}
+
+/// ボディを`T`として読む。上限は`DEFAULT_LIMIT`です。
+///
+/// Read the body as `T`, accepting at most `DEFAULT_LIMIT` bytes.
+pub fn body<T: DeserializeOwned>(request: &Request) -> Result<T, Error> {
+    body_with_limit(request, DEFAULT_LIMIT)
+}
+
+/// ボディを`T`として読む。`Content-Type`がJSONでなければ415、`limit`を
+/// 超えれば413、JSONとして読めなければ400、型に合わなければ422です。
+///
+/// Read the body as `T`. A non-JSON `Content-Type` is a 415, a body over
+/// `limit` a 413, malformed JSON a 400, and JSON of the wrong shape a 422.
+pub fn body_with_limit<T: DeserializeOwned>(request: &Request, limit: usize) -> Result<T, Error> {
+    match request.header("Content-Type") {
+        Some(content_type) if is_json(content_type) => {}
+        Some(content_type) => {
+            return Err(Error::new(
+                415,
+                format!("expected application/json, got {}", content_type),
+            ))
+        }
+        None => return Err(Error::new(415, "expected application/json")),
+    }
+    if request.body.len() > limit {
+        return Err(Error::new(
+            413,
+            format!("body is larger than {} bytes", limit),
+        ));
+    }
+    serde_json::from_slice(&request.body).map_err(|e| match e.classify() {
+        Category::Data => Error::new(422, e.to_string()),
+        _ => Error::bad_request(e.to_string()),
+    })
+}
+
+/// `application/json`か`application/*+json`で、文字コードがUTF-8か。
+fn is_json(content_type: &str) -> bool {
+    let mut parts = content_type.split(';');
+    let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let json =
+        mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"));
+    json && parts.all(|param| match param.split_once('=') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("charset") => {
+            value.trim().trim_matches('"').eq_ignore_ascii_case("utf-8")
+        }
+        _ => true,
+    })
+}
+
+/// `value`をJSONにしたレスポンス。
+///
+/// A response whose body is `value` as JSON.
+pub fn response<T: Serialize>(status: u16, value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::new(status)
+            .with_header("Content-Type", "application/json")
+            .with_body(body),
+        Err(e) => {
+            eprintln!("json: serializing failed: {}", e);
+            Response::new(500)
+        }
+    }
+}
+
+/// `Result`を返す関数をハンドラにする。`Err`はJSONのエラーになるので、
+/// 中では`?`が使えます。
+///
+/// Turn a function returning `Result` into a handler. `Err` becomes a JSON
+/// error response, so `?` can be used inside.
+pub fn handler<F>(f: F) -> Handler
+where
+    F: Fn(&Request) -> Result<Response, Error> + Send + Sync + 'static,
+{
+    Arc::new(move |request: &Request| f(request).unwrap_or_else(Error::into_response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    #[derive(Deserialize)]
+    struct Greeting {
+        name: String,
+        times: u8,
+    }
+
+    #[derive(Serialize)]
+    struct Reply {
+        message: String,
+    }
+
+    fn post(handler: &Handler, content_type: &str, body: &str) -> (u16, Value) {
+        let raw = format!(
+            "POST /greet HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let response = handler(&Request::parse(raw.as_bytes()).unwrap().unwrap().0);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        (
+            response.status,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    fn greeter() -> Handler {
+        handler(|request| {
+            let greeting: Greeting = body(request)?;
+            if greeting.times == 0 {
+                return Err(Error::bad_request("times must be positive"));
+            }
+            let message =
+                vec![format!("hello {}", greeting.name); greeting.times.into()].join(", ");
+            Ok(response(200, &Reply { message }))
+        })
+    }
+
+    #[test]
+    fn reads_and_writes_typed_bodies() {
+        let handler = greeter();
+        let (status, reply) = post(
+            &handler,
+            "application/json; charset=UTF-8",
+            r#"{"name": "Rex", "times": 2}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(reply, json!({"message": "hello Rex, hello Rex"}));
+
+        let (status, reply) = post(
+            &handler,
+            "application/json",
+            r#"{"name": "Rex", "times": 0}"#,
+        );
+        assert_eq!(status, 400);
+        assert_eq!(
+            reply,
+            json!({"error": {"status": 400, "reason": "Bad Request", "message": "times must be positive"}})
+        );
+    }
+
+    #[test]
+    fn rejects_bad_bodies_with_json_errors() {
+        let handler = greeter();
+        let status = |content_type: &str, body: &str| post(&handler, content_type, body).0;
+        assert_eq!(status("text/plain", "{}"), 415);
+        assert_eq!(status("application/json; charset=latin1", "{}"), 415);
+        assert_eq!(status("application/json", "{\"name\": "), 400);
+        assert_eq!(
+            status("application/json", r#"{"name": 1, "times": 1}"#),
+            422
+        );
+        assert_eq!(
+            status("application/vnd.api+json", r#"{"name": "a", "times": 1}"#),
+            200
+        );
+
+        let big = format!(r#"{{"name": "{}", "times": 1}}"#, "a".repeat(DEFAULT_LIMIT));
+        let (status, reply) = post(&handler, "application/json", &big);
+        assert_eq!(status, 413);
+        assert_eq!(reply["error"]["reason"], "Payload Too Large");
+    }
+}
@@ -0,0 +1,384 @@
+//! walk-the-dogのスコアランキング。
+//!
+//! `POST`でプレイ結果を登録し、`GET`で上位のスコアを返すJSONのAPIです。
+//! 結果は1行1件のJSON(JSON Lines)としてファイルに追記し、起動時に読み直します。
+//! 距離に対して高すぎるスコアや、同じリプレイの再登録は受け付けません。
+//!
+//! A leaderboard for walk-the-dog: a JSON API where `POST` submits a run
+//! and `GET` lists the top scores. Runs are appended to a JSON Lines file
+//! and read back on startup. Scores implausible for the distance run, and
+//! replays submitted twice, are rejected.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::{path_has_prefix, Handler, Request, Response};
+use crate::json;
+
+/// プレイヤー名の最大文字数。
+const MAX_PLAYER_CHARS: usize = 16;
+/// `GET`で返す件数の既定値と上限。
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+/// ゲームから送られるプレイ結果。
+///
+/// A run as submitted by the game.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submission {
+    pub player: String,
+    pub score: u64,
+    /// 走った距離(ピクセル)。
+    ///
+    /// Distance run, in pixels.
+    pub distance: u64,
+    /// リプレイのSHA-256(16進数64文字)。
+    ///
+    /// SHA-256 of the replay, as 64 hex digits.
+    pub checksum: String,
+}
+
+/// 登録済みのプレイ結果。
+///
+/// A recorded run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub player: String,
+    pub score: u64,
+    pub distance: u64,
+    pub checksum: String,
+    /// 登録したUNIX時刻(秒)。
+    ///
+    /// When the run was submitted, in seconds since the Unix epoch.
+    pub submitted: u64,
+}
+
+#[derive(Serialize)]
+struct Ranked<'a> {
+    rank: usize,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+#[derive(Serialize)]
+struct Scores<'a> {
+    scores: Vec<Ranked<'a>>,
+}
+
+struct State {
+    /// スコアの高い順。同点なら先に登録した方が上
+    entries: Vec<Entry>,
+    checksums: HashSet<String>,
+    file: File,
+}
+
+pub struct Leaderboard {
+    prefix: String,
+    max_distance: u64,
+    max_score_per_pixel: u64,
+    state: Mutex<State>,
+}
+
+impl Leaderboard {
+    /// `path`の記録を読み込み、以後の登録を追記する。ファイルが無ければ作ります。
+    /// 最後の行が書きかけなら読み飛ばします。
+    ///
+    /// Load the runs recorded in `path`, creating it if needed; new runs are
+    /// appended to it. A torn last line is skipped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Leaderboard> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut entries = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => eprintln!("leaderboard: {}:{}: {}", path.display(), i + 1, e),
+            }
+        }
+        // 書きかけの行に次の結果が続いてしまわないよう、行を終えておく
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        // 安定ソートなので、同点はファイルの順(登録順)のまま
+        entries.sort_by_key(|entry| Reverse(entry.score));
+        let checksums = entries.iter().map(|e| e.checksum.clone()).collect();
+        Ok(Leaderboard {
+            prefix: "/api/scores".to_string(),
+            max_distance: 1_000_000,
+            max_score_per_pixel: 1,
+            state: Mutex::new(State {
+                entries,
+                checksums,
+                file,
+            }),
+        })
+    }
+
+    /// APIのパス。既定は`/api/scores`です。
+    ///
+    /// Where the API is served. Defaults to `/api/scores`.
+    pub fn mount(mut self, prefix: &str) -> Leaderboard {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// 1回のプレイで走れる距離の上限。既定は1,000,000ピクセル
+    /// (60fpsで毎フレーム3ピクセル走って1時間半ほど)です。
+    ///
+    /// The longest distance a run may cover. Defaults to 1,000,000 pixels,
+    /// about an hour and a half at 3 pixels a frame and 60 frames a second.
+    pub fn max_distance(mut self, pixels: u64) -> Leaderboard {
+        self.max_distance = pixels;
+        self
+    }
+
+    /// 距離1ピクセルあたりに得られるスコアの上限。既定は1です。
+    ///
+    /// The most score a run may earn per pixel of distance. Defaults to 1.
+    pub fn max_score_per_pixel(mut self, score: u64) -> Leaderboard {
+        self.max_score_per_pixel = score;
+        self
+    }
+
+    /// 結果を検証して登録し、順位(1始まり)と登録内容を返す。
+    ///
+    /// Validate and record a run, returning its rank (from 1) and entry.
+    pub fn submit(&self, submission: Submission) -> Result<(usize, Entry), json::Error> {
+        let player = submission.player.trim();
+        if player.is_empty() || player.chars().count() > MAX_PLAYER_CHARS {
+            return Err(json::Error::new(
+                422,
+                format!("player must be 1 to {} characters", MAX_PLAYER_CHARS),
+            ));
+        }
+        if player.chars().any(char::is_control) {
+            return Err(json::Error::new(
+                422,
+                "player must not contain control characters",
+            ));
+        }
+        let checksum = submission.checksum.to_ascii_lowercase();
+        if checksum.len() != 64 || !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(json::Error::new(422, "checksum must be 64 hex digits"));
+        }
+        if submission.distance > self.max_distance {
+            return Err(json::Error::new(422, "distance is implausibly long"));
+        }
+        let max_score = submission.distance.saturating_mul(self.max_score_per_pixel);
+        if submission.score > max_score {
+            return Err(json::Error::new(
+                422,
+                "score is implausibly high for the distance",
+            ));
+        }
+
+        let entry = Entry {
+            player: player.to_string(),
+            score: submission.score,
+            distance: submission.distance,
+            checksum,
+            submitted: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.checksums.contains(&entry.checksum) {
+            return Err(json::Error::new(409, "this replay was already submitted"));
+        }
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        // 書き込めなかった結果は順位にも載せない
+        let written = state
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| state.file.sync_data());
+        if let Err(e) = written {
+            eprintln!("leaderboard: recording failed: {}", e);
+            return Err(json::Error::new(500, "could not record the run"));
+        }
+        let index = state.entries.partition_point(|e| e.score >= entry.score);
+        state.entries.insert(index, entry.clone());
+        state.checksums.insert(entry.checksum.clone());
+        Ok((index + 1, entry))
+    }
+
+    /// 上位`limit`件。
+    ///
+    /// The best `limit` runs.
+    pub fn top(&self, limit: usize) -> Vec<Entry> {
+        let state = self.state.lock().unwrap();
+        state.entries.iter().take(limit).cloned().collect()
+    }
+
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let board = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            if path_has_prefix(&request.path, &board.prefix) {
+                board
+                    .respond(request)
+                    .unwrap_or_else(json::Error::into_response)
+            } else {
+                handler(request)
+            }
+        })
+    }
+
+    fn respond(&self, request: &Request) -> Result<Response, json::Error> {
+        let rest = &request.path[self.prefix.len()..];
+        if !rest.is_empty() && rest != "/" {
+            return Err(json::Error::not_found());
+        }
+        match request.method.as_str() {
+            "GET" => {
+                let limit = limit(request.query.as_deref())?;
+                let entries = self.top(limit);
+                let scores = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| Ranked { rank: i + 1, entry })
+                    .collect();
+                Ok(json::response(200, &Scores { scores }))
+            }
+            "POST" => {
+                let (rank, entry) = self.submit(json::body(request)?)?;
+                Ok(json::response(
+                    201,
+                    &Ranked {
+                        rank,
+                        entry: &entry,
+                    },
+                ))
+            }
+            _ => Err(json::Error::method_not_allowed(&["GET", "POST"])),
+        }
+    }
+}
+
+/// `?limit=N`を読む。
+fn limit(query: Option<&str>) -> Result<usize, json::Error> {
+    for pair in query.unwrap_or("").split('&') {
+        if let Some(("limit", value)) = pair.split_once('=') {
+            return match value.parse() {
+                Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+                _ => Err(json::Error::bad_request(format!(
+                    "limit must be between 1 and {}",
+                    MAX_LIMIT
+                ))),
+            };
+        }
+    }
+    Ok(DEFAULT_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::env;
+    use std::process;
+
+    fn run(player: &str, score: u64, distance: u64, checksum: char) -> Submission {
+        Submission {
+            player: player.to_string(),
+            score,
+            distance,
+            checksum: checksum.to_string().repeat(64),
+        }
+    }
+
+    #[test]
+    fn ranks_runs_and_rejects_implausible_ones() {
+        let path = env::temp_dir().join(format!("leaderboard-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let board = Leaderboard::open(&path).unwrap();
+        assert_eq!(board.submit(run("Rex", 100, 500, 'a')).unwrap().0, 1);
+        assert_eq!(board.submit(run("Pochi", 300, 500, 'b')).unwrap().0, 1);
+        assert_eq!(board.submit(run(" Hachi ", 100, 900, 'c')).unwrap().0, 3);
+
+        let status = |submission| board.submit(submission).unwrap_err().status;
+        assert_eq!(status(run("Rex", 100, 500, 'A')), 409);
+        assert_eq!(status(run("Rex", 501, 500, 'd')), 422);
+        assert_eq!(status(run("Rex", 1, 2_000_000, 'd')), 422);
+        assert_eq!(status(run("", 1, 1, 'd')), 422);
+        assert_eq!(status(run("Rex", 1, 1, 'g')), 422);
+
+        // 書きかけの行があっても、読み直せば同じ順位になる
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"player\":")
+            .unwrap();
+        let reopened = Leaderboard::open(&path).unwrap();
+        assert_eq!(reopened.top(10), board.top(10));
+        reopened.submit(run("Shiro", 200, 500, 'd')).unwrap();
+        let players: Vec<String> = Leaderboard::open(&path)
+            .unwrap()
+            .top(10)
+            .into_iter()
+            .map(|e| e.player)
+            .collect();
+        assert_eq!(players, ["Pochi", "Shiro", "Rex", "Hachi"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn serves_the_json_api() {
+        let path = env::temp_dir().join(format!("leaderboard-api-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+        let handler = Leaderboard::open(&path).unwrap().wrap(not_found);
+        let send = |method: &str, target: &str, body: &str| {
+            let raw = format!(
+                "{} {} HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                target,
+                body.len(),
+                body
+            );
+            let response = handler(&Request::parse(raw.as_bytes()).unwrap().unwrap().0);
+            let body: Value = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+            (response.status, body)
+        };
+
+        let checksum = "0123456789abcdef".repeat(4);
+        let submission = format!(
+            r#"{{"player": "Rex", "score": 42, "distance": 420, "checksum": "{}"}}"#,
+            checksum
+        );
+        let (status, body) = send("POST", "/api/scores", &submission);
+        assert_eq!(status, 201);
+        assert_eq!(body["rank"], 1);
+        assert_eq!(body["player"], "Rex");
+
+        let (status, body) = send("GET", "/api/scores?limit=5", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["scores"][0]["score"], 42);
+        assert_eq!(body["scores"].as_array().unwrap().len(), 1);
+
+        assert_eq!(send("GET", "/api/scores?limit=0", "").0, 400);
+        assert_eq!(send("DELETE", "/api/scores", "").0, 405);
+        assert_eq!(send("GET", "/api/scores/1", "").0, 404);
+        assert_eq!(send("POST", "/api/scores", r#"{"player": "Rex"}"#).0, 422);
+        assert_eq!(send("GET", "/api/other", "").0, 404);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,28 +1,30 @@
-pub struct ThreadPool;
+pub mod auth;
+pub mod cache;
+pub mod cgi;
+pub mod cookie;
+pub mod cors;
+pub mod dev;
+pub mod error_pages;
+pub mod event_loop;
+pub mod fastcgi;
+pub mod http;
+pub mod json;
+pub mod leaderboard;
+pub mod listener;
+pub mod pool;
+pub mod rate_limit;
+pub mod sendfile;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod sse;
+pub mod static_files;
+pub mod task;
+pub mod template;
+pub mod upload;
+pub mod vhost;
 
-impl ThreadPool {
-    /// 新しいThreadPoolを生成する。
-    ///
-    /// sizeがプールのスレッド数です。
-    ///
-    /// # パニック
-    ///
-    /// sizeが0なら、`new`関数はパニックします。
-    ///
-    /// Create a new ThreadPool.
-    ///
-    /// The size is the number of threads in the pool.
-    ///
-    /// # Panics
-    ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-        ThreadPool
-    }
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-    }
-}
+pub use pool::{
+    Builder, CancellationToken, JobOptions, PoolStats, Priority, ScheduledTask, ThreadPool,
+};
+pub use task::{JoinError, TaskHandle};
@@ -0,0 +1,469 @@
+//! TCPとUnixドメインソケットのリスナー。
+//!
+//! 複数のアドレスで同時に待ち受けられるよう、どちらのソケットも
+//! `Listener`と`Stream`で同じように扱います。
+//!
+//! TCP and Unix domain socket listeners. Both kinds are wrapped in
+//! `Listener` and `Stream` so that a server can accept on several addresses
+//! at once and hand every connection to the same router. Listeners can also
+//! be inherited from systemd socket activation.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{self, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+
+/// systemdが渡す最初のファイルディスクリプタ(`SD_LISTEN_FDS_START`)。
+const LISTEN_FDS_START: RawFd = 3;
+
+/// 待ち受けるアドレス。
+///
+/// An address to listen on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// `127.0.0.1:7878`や`[::1]:7878`。IPv6のアドレスはIPv6だけを受け付けます。
+    ///
+    /// A TCP address. IPv6 addresses accept IPv6 only.
+    Tcp(SocketAddr),
+    /// `*:7878`。IPv4とIPv6の両方を1つのソケットで受け付けます。
+    ///
+    /// Every IPv4 and IPv6 address on a port, with one dual-stack socket.
+    DualStack(u16),
+    /// `unix:/run/example.sock`
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Address, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("empty socket path".to_string());
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        if let Some(port) = s.strip_prefix("*:") {
+            return port
+                .parse()
+                .map(Address::DualStack)
+                .map_err(|_| format!("invalid port: {}", port));
+        }
+        s.parse()
+            .map(Address::Tcp)
+            .map_err(|_| format!("invalid address: {}", s))
+    }
+}
+
+impl Address {
+    /// このアドレスに接続する。`0.0.0.0`や`*`のように全アドレスを表すものは
+    /// ループバックに接続します。
+    ///
+    /// Connect to this address, using loopback for unspecified addresses.
+    pub fn connect(&self) -> io::Result<Stream> {
+        self.open(None)
+    }
+
+    /// `connect`と同じだが、TCPでは`timeout`までしか待たない。Unixソケットは
+    /// ローカルなので待たずに繋がるか失敗します。
+    ///
+    /// Like `connect`, but TCP connects give up after `timeout`. Unix sockets
+    /// are local and connect or fail without waiting.
+    pub fn connect_timeout(&self, timeout: Duration) -> io::Result<Stream> {
+        self.open(Some(timeout))
+    }
+
+    fn open(&self, timeout: Option<Duration>) -> io::Result<Stream> {
+        let addr = match self {
+            Address::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                addr
+            }
+            Address::DualStack(port) => (Ipv6Addr::LOCALHOST, *port).into(),
+            Address::Unix(path) => return UnixStream::connect(path).map(Stream::Unix),
+        };
+        match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout).map(Stream::Tcp),
+            None => TcpStream::connect(addr).map(Stream::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::DualStack(port) => write!(f, "*:{}", port),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// 接続を受け付けるソケット。
+///
+/// A listening socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// `address`で待ち受ける。Unixドメインソケットは`unix`と同じく
+    /// 古いソケットファイルを片付け、パーミッションは`mode`にします。
+    ///
+    /// Listen on `address`. Unix sockets are bound as by `unix`, with
+    /// permissions `mode`.
+    pub fn bind(address: &Address, mode: u32) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(addr) => tcp(*addr, true).map(Listener::Tcp),
+            Address::DualStack(port) => {
+                let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), *port);
+                tcp(addr, false).map(Listener::Tcp)
+            }
+            Address::Unix(path) => Listener::unix(path, mode),
+        }
+    }
+
+    /// Unixドメインソケット`path`で待ち受け、パーミッションを`mode`にする。
+    ///
+    /// `path`に前回のソケットファイルが残っていて誰も待ち受けていなければ
+    /// 消してから作り直します。待ち受け中のプロセスがあれば`AddrInUse`、
+    /// ソケット以外のファイルがあれば`AlreadyExists`で失敗します。
+    ///
+    /// Listen on the Unix socket `path` with permissions `mode`. A stale
+    /// socket file left behind by a previous run is removed first; binding
+    /// fails with `AddrInUse` if another process is still listening there and
+    /// with `AlreadyExists` if the path is not a socket.
+    pub fn unix(path: &Path, mode: u32) -> io::Result<Listener> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ));
+                }
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        Ok(Listener::Unix(listener))
+    }
+
+    /// systemdのソケットアクティベーションで渡されたリスナーを引き取る。
+    ///
+    /// `LISTEN_PID`が自分のプロセスIDで`LISTEN_FDS`が1以上なら、fd 3から
+    /// 始まるソケットをリスナーとして返し、子プロセスに引き継がれないよう
+    /// 環境変数を消します。アクティベーションされていなければ`None`です。
+    ///
+    /// Take over listeners passed by systemd socket activation. When
+    /// `LISTEN_PID` names this process, the `LISTEN_FDS` sockets starting at
+    /// fd 3 are returned and the variables are removed so that children do
+    /// not inherit them. Returns `None` when the process was not activated.
+    pub fn from_env() -> io::Result<Option<Vec<Listener>>> {
+        let fds = activated_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            process::id(),
+        );
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        let fds = match fds {
+            Some(fds) => fds,
+            None => return Ok(None),
+        };
+        let mut listeners = Vec::with_capacity(fds.len());
+        for fd in fds {
+            // 以降このプロセスが起動する子には渡さない
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            if socket.r#type()? != Type::STREAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fd {} is not a stream socket", fd),
+                ));
+            }
+            socket.set_nonblocking(false)?;
+            let listener = if socket.local_addr()?.is_unix() {
+                Listener::Unix(UnixListener::from(OwnedFd::from(socket)))
+            } else {
+                Listener::Tcp(socket.into())
+            };
+            listeners.push(listener);
+        }
+        Ok(Some(listeners))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// 待ち受けているアドレス。
+    ///
+    /// The address this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket")
+                })?;
+                Ok(Address::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Listener {
+        Listener::Unix(listener)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// `LISTEN_PID`と`LISTEN_FDS`から、自分に渡されたファイルディスクリプタの範囲を求める。
+fn activated_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Option<Range<RawFd>> {
+    if pid?.parse::<u32>().ok()? != own_pid {
+        return None;
+    }
+    let count: RawFd = fds?.parse().ok()?;
+    if count <= 0 {
+        return None;
+    }
+    Some(LISTEN_FDS_START..LISTEN_FDS_START + count)
+}
+
+/// TCPソケットを作る。IPv6では`only_v6`でIPv4を受け付けるかを決めます。
+fn tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// 受け付けた接続。
+///
+/// An accepted connection.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// 相手のアドレス。Unixドメインソケットでは`None`です。
+    ///
+    /// The peer's address, or `None` on a Unix socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Stream {
+        Stream::Unix(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            "127.0.0.1:7878".parse(),
+            Ok(Address::Tcp("127.0.0.1:7878".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::1]:7878".parse(),
+            Ok(Address::Tcp("[::1]:7878".parse().unwrap()))
+        );
+        assert_eq!("*:7878".parse(), Ok(Address::DualStack(7878)));
+        assert_eq!(
+            "unix:/run/example.sock".parse(),
+            Ok(Address::Unix(PathBuf::from("/run/example.sock")))
+        );
+        assert!("unix:".parse::<Address>().is_err());
+        assert!("localhost".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn activation_requires_our_pid() {
+        assert_eq!(activated_fds(Some("42"), Some("2"), 42), Some(3..5));
+        assert_eq!(activated_fds(Some("41"), Some("2"), 42), None);
+        assert_eq!(activated_fds(None, Some("2"), 42), None);
+        assert_eq!(activated_fds(Some("42"), Some("0"), 42), None);
+        assert_eq!(activated_fds(Some("42"), Some("x"), 42), None);
+    }
+
+    #[test]
+    fn unix_socket_replaces_stale_file() {
+        let path = env::temp_dir().join(format!("listener-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let address = Address::Unix(path.clone());
+
+        let listener = Listener::bind(&address, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // 待ち受け中なら奪わない
+        let err = Listener::bind(&address, 0o600).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // ファイルだけ残った状態からは作り直せる
+        drop(listener);
+        assert!(path.exists());
+        let listener = Listener::bind(&address, 0o660).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, "not a socket").unwrap();
+        let err = Listener::bind(&address, 0o600).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+}
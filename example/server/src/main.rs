@@ -1,45 +1,357 @@
 extern crate example_server;
-use example_server::ThreadPool;
+use example_server::auth::{Auth, Htpasswd};
+use example_server::cache::FileCache;
+use example_server::cgi::Cgi;
+use example_server::cors::{Cors, Policy};
+use example_server::dev::{LiveReload, Watcher};
+use example_server::error_pages::ErrorPages;
+use example_server::fastcgi::FastCgi;
+use example_server::http::{path_has_prefix, Handler, Request, Response};
+use example_server::leaderboard::Leaderboard;
+use example_server::listener::{Address, Listener};
+use example_server::rate_limit::{Key, Limit, RateLimiter};
+use example_server::session::{DiskStore, MemoryStore, Session, Sessions};
+use example_server::shutdown::Shutdown;
+use example_server::sse::{Event, EventStream};
+use example_server::static_files::StaticFiles;
+use example_server::template::{Context, Templates};
+use example_server::upload::Uploads;
+use example_server::vhost::VirtualHosts;
+use example_server::{event_loop, server, Priority, ThreadPool};
 
-use std::fs::File;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+/// アップロードされたファイルの置き場所。
+const UPLOAD_DIR: &str = "example/server/data/uploads";
+
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream);
+    // `--listen`は複数指定できる: `127.0.0.1:7878`、`[::1]:7878`、`*:7878`(デュアルスタック)、
+    // `unix:/run/example.sock`
+    let mut addresses: Vec<Address> = flag_values("--listen")
+        .iter()
+        .map(|value| value.parse().unwrap_or_else(|e| panic!("--listen: {}", e)))
+        .collect();
+    if addresses.is_empty() {
+        addresses.push(Address::Tcp("127.0.0.1:7878".parse().unwrap()));
     }
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    // リクエストを読む
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
-    println!("request: {}", String::from_utf8_lossy(&buffer));
-    // GET を分岐させる
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "example/server/hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "example/server/hello.html")
+    let socket_mode = flag_values("--socket-mode")
+        .last()
+        .map(|mode| u32::from_str_radix(mode, 8).expect("--socket-mode must be octal"))
+        .unwrap_or(0o660);
+    // systemdのソケットアクティベーションで起動されたら、渡されたソケットを使う。
+    // ソケットファイルもsystemdのものなので、終了時に消さない
+    let listeners = match Listener::from_env().expect("cannot take over LISTEN_FDS") {
+        Some(listeners) => {
+            addresses.clear();
+            for listener in &listeners {
+                if let Ok(address) = listener.local_addr() {
+                    println!("listening on {} (socket activation)", address);
+                }
+            }
+            listeners
+        }
+        None => addresses
+            .iter()
+            .map(|address| {
+                let listener = Listener::bind(address, socket_mode)
+                    .unwrap_or_else(|e| panic!("cannot listen on {}: {}", address, e));
+                println!("listening on {}", address);
+                listener
+            })
+            .collect(),
+    };
+    // `/sleep`のような遅いリクエストが重なったらワーカーを増やす
+    let pool = Arc::new(
+        ThreadPool::builder()
+            .min_workers(4)
+            .max_workers(16)
+            .keep_alive(Duration::from_secs(30))
+            .thread_name("http")
+            .build(),
+    );
+    let events =
+        Arc::new(EventStream::new(64, Duration::from_secs(15)).with_retry(Duration::from_secs(3)));
+    // デモ用に`/events`へ一定間隔でイベントを配信する
+    let ticker_events = Arc::clone(&events);
+    let count = AtomicU64::new(0);
+    pool.schedule_repeating(Duration::from_secs(5), Duration::from_secs(5), move || {
+        let count = count.fetch_add(1, Ordering::SeqCst) + 1;
+        ticker_events.send(Event::new(count.to_string()).event("tick"));
+    });
+    // 配信するファイルは合計32MiBまでメモリに置く
+    let files = Arc::new(FileCache::new(32 * 1024 * 1024));
+    // ページとエラーページのテンプレート。書き換えれば再起動せずに反映される
+    let templates = Arc::new(Templates::new("example/server/templates"));
+    let app = App {
+        events,
+        pool: Arc::clone(&pool),
+        files: Arc::clone(&files),
+        templates: Arc::clone(&templates),
+    };
+    let handler: Handler = Arc::new(move |request: &Request| route(request, &app));
+    // walk-the-dogを`/game/`で配信する(`pkg/`はwasm-packでビルドしておく)。
+    // SPAなので、ディレクトリの一覧は出さない
+    let handler = StaticFiles::new()
+        .mount("/game", "game/walk-the-dog/static")
+        .mount("/game/pkg", "game/walk-the-dog/pkg")
+        .spa_fallback("/game/index.html")
+        .cache(Arc::clone(&files))
+        .cross_origin_isolation(env::args().any(|arg| arg == "--cross-origin-isolation"))
+        .wrap(handler);
+    // アップロードされたファイルは`/uploads/`で配信する。ゲームの
+    // フォールバックや一覧は共有せず、無いファイルは404になる
+    let handler = StaticFiles::new()
+        .mount("/uploads", UPLOAD_DIR)
+        .cache(Arc::clone(&files))
+        .wrap(handler);
+    // `/uploads/`への`PUT`と`multipart`の`POST`でファイルを受け付ける
+    let handler = Uploads::new(UPLOAD_DIR)
+        .mount("/uploads")
+        .max_size(8 * 1024 * 1024)
+        .extensions(&["png", "jpg", "jpeg", "gif", "txt", "json"])
+        .wrap(handler);
+    // walk-the-dogのスコアを`/api/scores`で受け付ける
+    let scores = env::var("LEADERBOARD_FILE")
+        .unwrap_or_else(|_| "example/server/data/leaderboard.jsonl".to_string());
+    let handler = Leaderboard::open(&scores)
+        .unwrap_or_else(|e| panic!("cannot open {}: {}", scores, e))
+        .wrap(handler);
+    // `cgi-bin/`の実行ファイルはCGIスクリプトとして動かす
+    let handler = Cgi::new()
+        .mount("/cgi-bin", "example/server/cgi-bin")
+        .timeout(Duration::from_secs(10))
+        .wrap(handler);
+    // `FASTCGI`(`127.0.0.1:9000`や`unix:/run/php-fpm.sock`)を与えたら、`/php/`を
+    // そのレスポンダへ転送する
+    let handler = match env::var("FASTCGI") {
+        Ok(address) => {
+            let root = env::var("FASTCGI_ROOT").unwrap_or_else(|_| "/var/www/php".to_string());
+            FastCgi::new(address.parse().expect("FASTCGI must be an address"))
+                .mount("/php", root)
+                .wrap(handler)
+        }
+        Err(_) => handler,
+    };
+    // 署名の鍵は`SESSION_KEY`で与える。無ければ起動ごとに作るので、再起動すると
+    // セッションは引き継がれない。`SESSION_DIR`を与えるとセッションをディスクに置く
+    let key = match env::var("SESSION_KEY") {
+        Ok(key) => key.into_bytes(),
+        Err(_) => {
+            let mut key = vec![0; 32];
+            getrandom::getrandom(&mut key).unwrap();
+            key
+        }
+    };
+    let sessions = match env::var("SESSION_DIR") {
+        Ok(dir) => Sessions::new(DiskStore::open(dir).unwrap(), &key),
+        Err(_) => Sessions::new(MemoryStore::new(), &key),
+    };
+    let handler = sessions.wrap(handler);
+    // `game.localhost`ではwalk-the-dogをルートから配信する
+    let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+    let game = StaticFiles::new()
+        .mount("/", "game/walk-the-dog/static")
+        .mount("/pkg", "game/walk-the-dog/pkg")
+        .spa_fallback("/index.html")
+        .cache(Arc::clone(&files))
+        .wrap(not_found);
+    let handler = VirtualHosts::new()
+        .host("game.localhost", game)
+        .wrap(handler);
+    // ボディの無いエラーにはテンプレートのページを付ける
+    let handler = ErrorPages::new(templates)
+        .page(404, "404.html")
+        .fallback("error.html")
+        .wrap(handler);
+    // `--dev`では配信するファイルを監視し、変更があればブラウザをリロードさせる
+    let handler = if env::args().any(|arg| arg == "--dev") {
+        let watcher = Arc::new(Watcher::new([
+            "example/server/templates",
+            "game/walk-the-dog/static",
+            "game/walk-the-dog/pkg",
+        ]));
+        let watched_files = Arc::clone(&files);
+        watcher.on_change(move |changed| {
+            eprintln!("changed: {:?}", changed);
+            for path in changed {
+                watched_files.invalidate(path);
+            }
+        });
+        let reload = LiveReload::new(&watcher);
+        watcher.start(&pool, Duration::from_millis(500));
+        reload.wrap(handler)
     } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "example/server/404.html")
+        handler
     };
-    let mut content = String::new();
-    File::open(filename)
+    // `/metrics`と`/uploads`は認証必須。資格情報は環境変数で与え、無ければ403になる
+    let mut auth = Auth::new("example_server")
+        .route("/metrics")
+        .route("/uploads");
+    if let Ok(path) = env::var("HTPASSWD") {
+        auth = auth.basic(Htpasswd::open(path).unwrap());
+    }
+    if let Ok(token) = env::var("METRICS_TOKEN") {
+        auth = auth.bearer_token(&token);
+    }
+    let handler = auth.wrap(handler);
+    // 重い`/sleep`は特に厳しく制限する。Unixドメインソケットでは
+    // リバースプロキシが付けたアドレスで数える
+    let handler = RateLimiter::new(Key::Ip)
+        .forwarded_for("X-Forwarded-For")
+        .route("/", Limit::per_second(20).burst(40))
+        .route("/sleep", Limit::per_minute(6).burst(2))
+        .route("/api/scores", Limit::per_minute(30).burst(10))
+        .wrap(handler);
+    // 開発中のwalk-the-dog(webpack dev server)から呼べるようにする。
+    // プリフライトが認証や流量制限に阻まれないよう、一番外側に被せる
+    let handler = Cors::new()
+        .route(
+            "/",
+            Policy::new()
+                .allow_origin("http://localhost:8080")
+                .allow_methods(&["GET", "POST"])
+                .allow_headers(&["Content-Type", "Authorization"])
+                .expose_headers(&["RateLimit-Limit", "RateLimit-Remaining", "RateLimit-Reset"])
+                .max_age(Duration::from_secs(600)),
+        )
+        .wrap(handler);
+
+    // SIGINT/SIGTERMを受けたら、処理中のリクエストを最大10秒待って終了する
+    let shutdown = Shutdown::new(Duration::from_secs(10));
+    shutdown.on_signals().unwrap();
+
+    // `--event-loop`を付けるとepollによるI/O方式で動く
+    let drained = if env::args().any(|arg| arg == "--event-loop") {
+        event_loop::serve_until(
+            listeners,
+            pool,
+            handler,
+            2,
+            priority,
+            streams_body,
+            &shutdown,
+        )
         .unwrap()
-        .read_to_string(&mut content)
-        .unwrap();
-    stream
-        .write(format!("{}{}", status_line, content).as_bytes())
-        .unwrap();
-    stream.flush().unwrap();
+    } else {
+        server::serve_until(listeners, pool, handler, priority, streams_body, &shutdown)
+    };
+    for address in &addresses {
+        if let Address::Unix(path) = address {
+            let _ = fs::remove_file(path);
+        }
+    }
+    // SSEの購読などプールに残った仕事は待たずに終わる
+    if drained {
+        eprintln!("drained, exiting");
+        process::exit(0);
+    }
+    eprintln!("drain timed out, exiting");
+    process::exit(1);
+}
+
+/// `--name value`の形で渡された値をすべて返す。
+fn flag_values(name: &str) -> Vec<String> {
+    let args: Vec<String> = env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// ルーティングで使う共有の状態。
+struct App {
+    events: Arc<EventStream>,
+    pool: Arc<ThreadPool>,
+    files: Arc<FileCache>,
+    templates: Arc<Templates>,
+}
+
+fn route(request: &Request, app: &App) -> Response {
+    println!("request: {} {}", request.method, request.path);
+    // SSE は接続を EventStream に引き渡し、ワーカーはすぐに解放する
+    if request.method == "GET" && request.path == "/events" {
+        let events = Arc::clone(&app.events);
+        let last_event_id = request.header("Last-Event-ID").map(str::to_string);
+        return Response::upgrade(move |stream| {
+            if let Err(e) = events.subscribe(stream, last_event_id.as_deref()) {
+                eprintln!("sse subscribe failed: {}", e);
+            }
+        });
+    }
+    if request.method == "GET" && request.path == "/health" {
+        return Response::new(200)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body("ok");
+    }
+    if request.method == "GET" && request.path == "/metrics" {
+        let stats = app.pool.stats();
+        let cache = app.files.stats();
+        return Response::new(200)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!(
+                "workers {}\nidle {}\nqueued {}\nsse_subscribers {}\n\
+                 cache_hits {}\ncache_misses {}\ncache_evictions {}\n\
+                 cache_entries {}\ncache_bytes {}\n",
+                stats.workers,
+                stats.idle,
+                stats.queued,
+                app.events.subscribers(),
+                cache.hits,
+                cache.misses,
+                cache.evictions,
+                cache.entries,
+                cache.bytes
+            ));
+    }
+    // セッションの動作確認用に、訪問回数を数える
+    if request.method == "GET" && request.path == "/visits" {
+        let session = Session::of(request).unwrap();
+        let visits = session
+            .get("visits")
+            .and_then(|visits| visits.parse::<u64>().ok())
+            .unwrap_or(0)
+            + 1;
+        session.insert("visits", &visits.to_string());
+        return Response::new(200)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("visits {}\n", visits));
+    }
+    // GET を分岐させる。見つからなければ`ErrorPages`が404のページを付ける
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => hello(request, app),
+        ("GET", "/sleep") => {
+            thread::sleep(Duration::from_secs(5));
+            hello(request, app)
+        }
+        _ => Response::new(404),
+    }
+}
+
+fn hello(request: &Request, app: &App) -> Response {
+    let mut context = Context::new().with("title", "Hello, Rust!");
+    if let Some(visits) = Session::of(request).and_then(|session| session.get("visits")) {
+        context.insert("visits", visits);
+    }
+    app.templates.page(200, "hello.html", &context)
+}
+
+/// アップロードのボディはメモリに置かず、`Uploads`が接続から直接読む。
+fn streams_body(request: &Request) -> bool {
+    let write = request.method == "PUT" || request.method == "POST";
+    write && path_has_prefix(&request.path, "/uploads")
+}
+
+/// ヘルスチェックは遅いリクエストの後ろに並ばないよう優先する。
+fn priority(request: &Request) -> Priority {
+    match request.path.as_str() {
+        "/health" => Priority::High,
+        "/sleep" => Priority::Low,
+        _ => Priority::Normal,
+    }
 }
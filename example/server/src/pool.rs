@@ -0,0 +1,610 @@
+//! 負荷に応じてワーカー数を増減するスレッドプール。
+//!
+//! `Builder::work_stealing`でワークスティーリング方式も選べます。
+//!
+//! A thread pool that grows between a minimum and maximum number of workers
+//! as its queue backs up, and retires workers that stay idle. A fixed-size
+//! work-stealing mode is available through `Builder::work_stealing`.
+
+mod job;
+mod stealing;
+mod timer;
+
+pub use self::job::{CancellationToken, JobOptions, Priority};
+pub use self::timer::ScheduledTask;
+
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::task::{self, JoinError, TaskHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// プールの現在の状態。
+///
+/// A snapshot of the pool's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// 生きているワーカー数。 / Live worker threads.
+    pub workers: usize,
+    /// ジョブを待っているワーカー数。 / Workers waiting for a job.
+    pub idle: usize,
+    /// 実行待ちのジョブ数。 / Jobs waiting to run.
+    pub queued: usize,
+}
+
+pub struct Builder {
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Duration,
+    thread_name: String,
+    stack_size: Option<usize>,
+    work_stealing: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    /// 既定値: 最小1、最大は利用可能なCPU数、アイドル60秒で回収。
+    ///
+    /// Defaults: one worker minimum, one per available CPU maximum, and idle
+    /// workers retired after 60 seconds.
+    pub fn new() -> Builder {
+        Builder {
+            min_workers: 1,
+            max_workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            keep_alive: Duration::from_secs(60),
+            thread_name: "worker".to_string(),
+            stack_size: None,
+            work_stealing: false,
+        }
+    }
+
+    /// 常に維持するワーカー数。 / Workers kept alive even when idle.
+    pub fn min_workers(mut self, n: usize) -> Builder {
+        self.min_workers = n;
+        self
+    }
+
+    /// キューが詰まったときに増やせる上限。 / Upper bound when the queue backs up.
+    pub fn max_workers(mut self, n: usize) -> Builder {
+        self.max_workers = n;
+        self
+    }
+
+    /// 最小数を超えたワーカーがこの時間アイドルなら終了する。
+    ///
+    /// How long a worker above the minimum may stay idle before retiring.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Builder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// スレッド名の接頭辞。`{name}-{id}`になります。
+    ///
+    /// Prefix for thread names, which become `{name}-{id}`.
+    pub fn thread_name(mut self, name: &str) -> Builder {
+        self.thread_name = name.to_string();
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> Builder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// 共有キューの代わりにワーカーごとの両端キューとスティーリングを使う。
+    /// この方式ではワーカー数は`max_workers`で固定され、回収は行いません。
+    ///
+    /// Use per-worker deques with stealing instead of one shared queue. In
+    /// this mode the pool runs exactly `max_workers` workers and never reaps.
+    pub fn work_stealing(mut self, enabled: bool) -> Builder {
+        self.work_stealing = enabled;
+        self
+    }
+
+    /// # パニック
+    ///
+    /// 最大数が0、または最小数が最大数を超える場合はパニックします。
+    ///
+    /// # Panics
+    ///
+    /// Panics if the maximum is zero or below the minimum.
+    pub fn build(self) -> ThreadPool {
+        assert!(self.max_workers > 0);
+        assert!(self.min_workers <= self.max_workers);
+
+        if self.work_stealing {
+            return ThreadPool {
+                scheduler: Scheduler::Stealing(stealing::Inner::start(&self)),
+                timer: Mutex::new(None),
+            };
+        }
+
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                lanes: Default::default(),
+                workers: 0,
+                idle: 0,
+                next_id: 0,
+                handles: HashMap::new(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            config: self,
+        });
+        {
+            let mut state = inner.state.lock().unwrap();
+            for _ in 0..inner.config.min_workers {
+                inner.spawn_worker(&mut state);
+            }
+        }
+        ThreadPool {
+            scheduler: Scheduler::Shared(inner),
+            timer: Mutex::new(None),
+        }
+    }
+}
+
+struct State {
+    /// 優先度ごとのレーン。添字は`Priority::lane`。
+    lanes: [VecDeque<Job>; Priority::LANES],
+    workers: usize,
+    idle: usize,
+    next_id: usize,
+    handles: HashMap<usize, thread::JoinHandle<()>>,
+    shutdown: bool,
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Job> {
+        self.lanes.iter_mut().find_map(|lane| lane.pop_front())
+    }
+
+    fn queued(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+}
+
+struct Inner {
+    state: Mutex<State>,
+    available: Condvar,
+    config: Builder,
+}
+
+impl Inner {
+    /// ロックを保持したまま起動するので、ワーカーはハンドル登録後に動き出す。
+    fn spawn_worker(self: &Arc<Inner>, state: &mut State) {
+        let id = state.next_id;
+        let mut builder =
+            thread::Builder::new().name(format!("{}-{}", self.config.thread_name, id));
+        if let Some(size) = self.config.stack_size {
+            builder = builder.stack_size(size);
+        }
+        let inner = Arc::clone(self);
+        match builder.spawn(move || inner.run(id)) {
+            Ok(handle) => {
+                state.next_id += 1;
+                state.workers += 1;
+                state.handles.insert(id, handle);
+            }
+            Err(e) => eprintln!("failed to spawn worker: {}", e),
+        }
+    }
+
+    fn run(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.pop() {
+                // ジョブ実行中はロックを手放す
+                drop(state);
+                // ジョブがパニックしてもワーカーは生き残る
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+            state.idle += 1;
+            let (guard, timeout) = self
+                .available
+                .wait_timeout(state, self.config.keep_alive)
+                .unwrap();
+            state = guard;
+            state.idle -= 1;
+            if timeout.timed_out() && state.queued() == 0 && state.workers > self.config.min_workers
+            {
+                // アイドルが続いたので引退する。ハンドルは切り離す
+                state.handles.remove(&id);
+                break;
+            }
+        }
+        state.workers -= 1;
+    }
+
+    fn push(self: &Arc<Self>, priority: Priority, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        state.lanes[priority.lane()].push_back(job);
+        // 待機中のワーカーで捌ききれないなら上限まで増やす
+        if state.queued() > state.idle && state.workers < self.config.max_workers {
+            self.spawn_worker(&mut state);
+        }
+        drop(state);
+        self.available.notify_one();
+    }
+}
+
+#[derive(Clone)]
+enum Scheduler {
+    Shared(Arc<Inner>),
+    Stealing(Arc<stealing::Inner>),
+}
+
+impl Scheduler {
+    fn push(&self, priority: Priority, job: Job) {
+        match self {
+            Scheduler::Shared(inner) => inner.push(priority, job),
+            Scheduler::Stealing(inner) => inner.push(priority, job),
+        }
+    }
+}
+
+pub struct ThreadPool {
+    scheduler: Scheduler,
+    /// 最初の予約で起動するタイマー。
+    timer: Mutex<Option<timer::Timer>>,
+}
+
+impl ThreadPool {
+    /// 新しいThreadPoolを生成する。
+    ///
+    /// sizeがプールのスレッド数です。
+    ///
+    /// # パニック
+    ///
+    /// sizeが0なら、`new`関数はパニックします。
+    ///
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+        Builder::new().min_workers(size).max_workers(size).build()
+    }
+
+    /// ワーカー数の範囲やスレッド名を指定してプールを作る。
+    ///
+    /// Configure a pool with a worker range, thread names and stack size.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.scheduler.push(Priority::Normal, Box::new(f));
+    }
+
+    /// 優先度や取り消しトークンを指定してジョブを投入する。
+    ///
+    /// Submit a job with a priority lane and, optionally, a cancellation
+    /// token that drops it if cancelled before a worker picks it up.
+    pub fn execute_with<O, F>(&self, options: O, f: F)
+    where
+        O: Into<JobOptions>,
+        F: FnOnce() + Send + 'static,
+    {
+        let options = options.into();
+        let job: Job = match options.token {
+            Some(token) => Box::new(move || {
+                if !token.is_cancelled() {
+                    f();
+                }
+            }),
+            None => Box::new(f),
+        };
+        self.scheduler.push(options.priority, job);
+    }
+
+    /// 値を返すジョブを投入し、結果を受け取るハンドルを返す。
+    ///
+    /// ジョブがパニックした場合、ハンドルは`JoinError::Panicked`を返します。
+    ///
+    /// Submit a job that returns a value, and get a handle to its result.
+    ///
+    /// A panic in the job is reported as `JoinError::Panicked` by the handle.
+    pub fn spawn<F, T>(&self, f: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn_with(JobOptions::new(), f)
+    }
+
+    /// `spawn`に優先度と取り消しを加えたもの。取り消されたジョブの
+    /// ハンドルは`JoinError::Cancelled`を返します。
+    ///
+    /// Like `spawn`, with submission options. A job dropped by cancellation
+    /// reports `JoinError::Cancelled` through its handle.
+    pub fn spawn_with<O, F, T>(&self, options: O, f: F) -> TaskHandle<T>
+    where
+        O: Into<JobOptions>,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (completer, handle) = task::pair();
+        self.execute_with(options, move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::from_panic);
+            completer.complete(result);
+        });
+        handle
+    }
+
+    /// `delay`後にジョブをプールで実行する。
+    ///
+    /// Run `f` on the pool once `delay` has elapsed.
+    pub fn schedule<F>(&self, delay: Duration, f: F) -> ScheduledTask
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.with_timer(|timer| timer.once(delay, Box::new(f)))
+    }
+
+    /// `delay`後から`interval`ごとにジョブを実行する。前回の実行が
+    /// 終わっていない周期は飛ばします。
+    ///
+    /// # パニック
+    ///
+    /// `interval`が0ならパニックします。
+    ///
+    /// Run `f` every `interval`, starting after `delay`. A tick is skipped
+    /// while the previous run is still in progress.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn schedule_repeating<F>(&self, delay: Duration, interval: Duration, f: F) -> ScheduledTask
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero());
+        self.with_timer(|timer| timer.repeat(delay, interval, Arc::new(f)))
+    }
+
+    fn with_timer<R>(&self, f: impl FnOnce(&timer::Timer) -> R) -> R {
+        let mut timer = self.timer.lock().unwrap();
+        let name = match &self.scheduler {
+            Scheduler::Shared(inner) => inner.config.thread_name.as_str(),
+            Scheduler::Stealing(inner) => inner.thread_name(),
+        };
+        let timer = timer.get_or_insert_with(|| timer::Timer::start(self.scheduler.clone(), name));
+        f(timer)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let inner = match &self.scheduler {
+            Scheduler::Shared(inner) => inner,
+            Scheduler::Stealing(inner) => return inner.stats(),
+        };
+        let state = inner.state.lock().unwrap();
+        PoolStats {
+            workers: state.workers,
+            idle: state.idle,
+            queued: state.queued(),
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // 先にタイマーを止め、期限前の予約は破棄する
+        if let Some(mut timer) = self.timer.lock().unwrap().take() {
+            timer.shutdown();
+        }
+        // 残っているジョブを実行し終えてからワーカーを終了させる
+        let handles = match &self.scheduler {
+            Scheduler::Shared(inner) => {
+                let mut state = inner.state.lock().unwrap();
+                state.shutdown = true;
+                inner.available.notify_all();
+                std::mem::take(&mut state.handles).into_values().collect()
+            }
+            Scheduler::Stealing(inner) => inner.shutdown(),
+        };
+        for handle in handles {
+            // ジョブの中で最後の参照が落とされた場合、自分自身は待たない
+            if handle.thread().id() != thread::current().id() {
+                handle.join().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn wait_for(pool: &ThreadPool, cond: impl Fn(PoolStats) -> bool) -> PoolStats {
+        for _ in 0..200 {
+            let stats = pool.stats();
+            if cond(stats) {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("condition not reached: {:?}", pool.stats());
+    }
+
+    #[test]
+    fn grows_under_load_and_reaps_idle_workers() {
+        let pool = ThreadPool::builder()
+            .min_workers(1)
+            .max_workers(4)
+            .keep_alive(Duration::from_millis(50))
+            .build();
+        assert_eq!(pool.stats().workers, 1);
+
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        for _ in 0..6 {
+            let gate = Arc::clone(&gate);
+            pool.execute(move || {
+                gate.lock().unwrap().recv().unwrap();
+            });
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.workers, 4);
+
+        for _ in 0..6 {
+            release.send(()).unwrap();
+        }
+        wait_for(&pool, |s| s.workers == 1 && s.queued == 0);
+    }
+
+    #[test]
+    fn work_stealing_runs_nested_jobs() {
+        let pool = Arc::new(
+            ThreadPool::builder()
+                .max_workers(4)
+                .work_stealing(true)
+                .build(),
+        );
+        assert_eq!(pool.stats().workers, 4);
+
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..16 {
+            let inner_pool = Arc::clone(&pool);
+            let sender = sender.clone();
+            pool.execute(move || {
+                // ワーカー内からの投入は自分のキューに積まれ、他のワーカーに盗まれうる
+                for j in 0..16 {
+                    let sender = sender.clone();
+                    inner_pool.execute(move || sender.send(i * 16 + j).unwrap());
+                }
+            });
+        }
+        drop(sender);
+        let mut got: Vec<_> = receiver.iter().collect();
+        got.sort();
+        assert_eq!(got, (0..256).collect::<Vec<_>>());
+        assert_eq!(pool.spawn(|| 1 + 1).join(), Ok(2));
+    }
+
+    #[test]
+    fn scheduled_jobs_run_after_delay_and_can_be_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let start = std::time::Instant::now();
+        let once_sender = sender.clone();
+        pool.schedule(Duration::from_millis(200), move || {
+            once_sender.send("once").unwrap()
+        });
+        // 先に期限が来るはずだったジョブは、取り消せば決して届かない
+        let cancelled_sender = sender.clone();
+        let cancelled = pool.schedule(Duration::from_millis(50), move || {
+            cancelled_sender.send("cancelled").unwrap()
+        });
+        cancelled.cancel();
+
+        let ticks = pool.schedule_repeating(Duration::ZERO, Duration::from_millis(10), move || {
+            let _ = sender.send("tick");
+        });
+        let got: Vec<_> = receiver.iter().take_while(|&m| m != "once").collect();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(got.first(), Some(&"tick"));
+        assert!(got.iter().all(|&m| m == "tick"), "{:?}", got);
+
+        // 取り消し前に投入済みだった分を捨ててから、もう届かないことを確かめる
+        ticks.cancel();
+        thread::sleep(Duration::from_millis(100));
+        receiver.try_iter().count();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(receiver.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn drop_discards_pending_scheduled_jobs() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(Mutex::new(false));
+        let flag = Arc::clone(&ran);
+        pool.schedule(Duration::from_secs(60), move || {
+            *flag.lock().unwrap() = true
+        });
+        let start = std::time::Instant::now();
+        drop(pool);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!*ran.lock().unwrap());
+    }
+
+    #[test]
+    fn high_priority_jumps_the_queue_and_cancelled_jobs_are_dropped() {
+        for stealing in [false, true] {
+            let pool = ThreadPool::builder()
+                .max_workers(1)
+                .work_stealing(stealing)
+                .build();
+            let (release, gate) = mpsc::channel::<()>();
+            pool.execute(move || gate.recv().unwrap());
+
+            let (sender, receiver) = mpsc::channel();
+            let token = CancellationToken::new();
+            for (name, priority) in [("low", Priority::Low), ("normal", Priority::Normal)] {
+                let sender = sender.clone();
+                pool.execute_with(priority, move || sender.send(name).unwrap());
+            }
+            let cancelled = pool.spawn_with(
+                JobOptions::new()
+                    .priority(Priority::High)
+                    .cancel_token(&token),
+                || panic!("cancelled job ran"),
+            );
+            let high = sender.clone();
+            pool.execute_with(Priority::High, move || high.send("high").unwrap());
+            token.cancel();
+            drop(sender);
+
+            release.send(()).unwrap();
+            assert_eq!(
+                receiver.iter().collect::<Vec<_>>(),
+                ["high", "normal", "low"]
+            );
+            assert_eq!(cancelled.join(), Err(JoinError::Cancelled));
+        }
+    }
+
+    #[test]
+    fn names_threads_and_runs_queued_jobs_on_drop() {
+        let pool = ThreadPool::builder()
+            .max_workers(1)
+            .thread_name("batch")
+            .stack_size(256 * 1024)
+            .build();
+        let name = pool
+            .spawn(|| thread::current().name().unwrap().to_string())
+            .join()
+            .unwrap();
+        assert_eq!(name, "batch-0");
+
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..10 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        drop(pool);
+        assert_eq!(receiver.try_iter().count(), 10);
+    }
+}
@@ -0,0 +1,87 @@
+//! ジョブの優先度と取り消し。
+//!
+//! Job priorities and cancellation.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// ジョブの優先度。ワーカーは高い順にレーンからジョブを取ります。
+///
+/// A job's lane. Workers always take from the highest non-empty lane, so a
+/// steady stream of high-priority jobs can starve lower lanes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub(super) const LANES: usize = 3;
+
+    pub(super) fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// まだ始まっていないジョブを取り消すためのトークン。
+/// クローンはすべて同じ状態を共有します。
+///
+/// A token for dropping queued jobs before they start. Clones share state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// 取り消す。実行中のジョブは止まりませんが、`is_cancelled`で確認できます。
+    ///
+    /// Cancel. Jobs already running are not interrupted, but may check
+    /// `is_cancelled` themselves.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// `ThreadPool::execute_with`と`spawn_with`に渡す投入オプション。
+///
+/// Submission options for `ThreadPool::execute_with` and `spawn_with`.
+#[derive(Debug, Clone, Default)]
+pub struct JobOptions {
+    pub(super) priority: Priority,
+    pub(super) token: Option<CancellationToken>,
+}
+
+impl JobOptions {
+    pub fn new() -> JobOptions {
+        JobOptions::default()
+    }
+
+    pub fn priority(mut self, priority: Priority) -> JobOptions {
+        self.priority = priority;
+        self
+    }
+
+    /// `token`が取り消されたら、開始前のジョブは実行せずに捨てる。
+    ///
+    /// Drop the job without running it if `token` is cancelled first.
+    pub fn cancel_token(mut self, token: &CancellationToken) -> JobOptions {
+        self.token = Some(token.clone());
+        self
+    }
+}
+
+impl From<Priority> for JobOptions {
+    fn from(priority: Priority) -> JobOptions {
+        JobOptions::new().priority(priority)
+    }
+}
@@ -0,0 +1,192 @@
+//! ワークスティーリング方式のスケジューラ。
+//!
+//! 各ワーカーが自分の両端キューを持ち、ワーカー内から投入されたジョブは
+//! そこへ積まれます。手が空いたワーカーは共有キュー、次に他のワーカーから
+//! ジョブを盗みます。
+//!
+//! 優先度が通常以外のジョブは、優先度ごとの共有キューにだけ積まれます。
+//!
+//! Work-stealing scheduler: each worker owns a deque that receives jobs
+//! submitted from inside that worker; idle workers take from the global
+//! injector and then steal from their peers. High and low priority jobs
+//! always go through their own global lanes.
+
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+use super::{Builder, Job, PoolStats, Priority};
+
+thread_local! {
+    /// このスレッドが所属するプール(のアドレス)と、そのローカルキュー。
+    static LOCAL: RefCell<Option<(usize, Worker<Job>)>> = const { RefCell::new(None) };
+}
+
+struct State {
+    handles: Vec<thread::JoinHandle<()>>,
+    shutdown: bool,
+}
+
+pub(super) struct Inner {
+    /// 優先度ごとの共有キュー。添字は`Priority::lane`。
+    injectors: [Injector<Job>; Priority::LANES],
+    stealers: Vec<Stealer<Job>>,
+    /// 投入済みで未着手のジョブ数。
+    pending: AtomicUsize,
+    sleeping: AtomicUsize,
+    state: Mutex<State>,
+    wake: Condvar,
+    thread_name: String,
+}
+
+impl Inner {
+    /// ワーカー数は`max_workers`で固定です。
+    pub(super) fn start(config: &Builder) -> Arc<Inner> {
+        let deques: Vec<Worker<Job>> = (0..config.max_workers)
+            .map(|_| Worker::new_lifo())
+            .collect();
+        let inner = Arc::new(Inner {
+            injectors: Default::default(),
+            stealers: deques.iter().map(Worker::stealer).collect(),
+            pending: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            state: Mutex::new(State {
+                handles: Vec::new(),
+                shutdown: false,
+            }),
+            wake: Condvar::new(),
+            thread_name: config.thread_name.clone(),
+        });
+
+        let mut handles = Vec::with_capacity(deques.len());
+        for (id, deque) in deques.into_iter().enumerate() {
+            let mut builder = thread::Builder::new().name(format!("{}-{}", config.thread_name, id));
+            if let Some(size) = config.stack_size {
+                builder = builder.stack_size(size);
+            }
+            let worker_inner = Arc::clone(&inner);
+            match builder.spawn(move || worker_inner.run(id, deque)) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => eprintln!("failed to spawn worker: {}", e),
+            }
+        }
+        inner.state.lock().unwrap().handles = handles;
+        inner
+    }
+
+    pub(super) fn push(self: &Arc<Self>, priority: Priority, job: Job) {
+        // 先に数えてから積むので、pendingが実際より少なくなることはない
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let me = Arc::as_ptr(self) as usize;
+        let job = if priority == Priority::Normal {
+            LOCAL.with(|local| match &*local.borrow() {
+                Some((pool, deque)) if *pool == me => {
+                    deque.push(job);
+                    None
+                }
+                _ => Some(job),
+            })
+        } else {
+            Some(job)
+        };
+        if let Some(job) = job {
+            self.injectors[priority.lane()].push(job);
+        }
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _state = self.state.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    fn run(&self, id: usize, deque: Worker<Job>) {
+        LOCAL.with(|local| *local.borrow_mut() = Some((self as *const Inner as usize, deque)));
+        loop {
+            if let Some(job) = self.find_job(id) {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                // ジョブがパニックしてもワーカーは生き残る
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                continue;
+            }
+
+            let mut state = self.state.lock().unwrap();
+            if state.shutdown && self.pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            // sleepingを増やしてからpendingを見直すことで、起こし損ねを防ぐ
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            if self.pending.load(Ordering::SeqCst) == 0 && !state.shutdown {
+                state = self.wake.wait(state).unwrap();
+            }
+            drop(state);
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+        }
+        LOCAL.with(|local| *local.borrow_mut() = None);
+    }
+
+    /// 高優先度、自分のキュー、通常の共有キュー、他のワーカー、低優先度の
+    /// 順にジョブを探す。
+    fn find_job(&self, id: usize) -> Option<Job> {
+        if let Some(job) = steal_one(&self.injectors[Priority::High.lane()]) {
+            return Some(job);
+        }
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let (_, deque) = local.as_ref().unwrap();
+            if let Some(job) = deque.pop() {
+                return Some(job);
+            }
+            loop {
+                let mut retry = false;
+                match self.injectors[Priority::Normal.lane()].steal_batch_and_pop(deque) {
+                    Steal::Success(job) => return Some(job),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+                let n = self.stealers.len();
+                for i in 1..n {
+                    match self.stealers[(id + i) % n].steal() {
+                        Steal::Success(job) => return Some(job),
+                        Steal::Retry => retry = true,
+                        Steal::Empty => {}
+                    }
+                }
+                if !retry {
+                    return steal_one(&self.injectors[Priority::Low.lane()]);
+                }
+            }
+        })
+    }
+
+    pub(super) fn thread_name(&self) -> &str {
+        &self.thread_name
+    }
+
+    pub(super) fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: self.stealers.len(),
+            idle: self.sleeping.load(Ordering::SeqCst),
+            queued: self.pending.load(Ordering::SeqCst),
+        }
+    }
+
+    pub(super) fn shutdown(&self) -> Vec<thread::JoinHandle<()>> {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        self.wake.notify_all();
+        std::mem::take(&mut state.handles)
+    }
+}
+
+fn steal_one(injector: &Injector<Job>) -> Option<Job> {
+    loop {
+        match injector.steal() {
+            Steal::Success(job) => return Some(job),
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
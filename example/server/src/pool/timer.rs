@@ -0,0 +1,232 @@
+//! 遅延実行・定期実行のためのタイマー。
+//!
+//! 1本のタイマースレッドが期限順のヒープを見張り、期限が来たジョブを
+//! プールへ投入します。ジョブ自体はワーカーで実行されます。
+//!
+//! Timer for delayed and periodic jobs. One timer thread watches a heap of
+//! deadlines and submits due jobs to the pool, where workers run them.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Job, Priority, Scheduler};
+
+/// 予約したジョブの取り消し用ハンドル。ドロップしても取り消されません。
+///
+/// A handle for cancelling a scheduled job. Dropping it does not cancel.
+#[derive(Clone)]
+pub struct ScheduledTask {
+    state: Arc<TaskState>,
+}
+
+struct TaskState {
+    cancelled: AtomicBool,
+    running: AtomicBool,
+}
+
+impl ScheduledTask {
+    /// まだ始まっていない実行と、以降の定期実行をすべて取り消す。
+    ///
+    /// Cancel every run that has not started yet, including future
+    /// repetitions.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(atomic::Ordering::SeqCst)
+    }
+}
+
+enum Kind {
+    Once(Job),
+    Repeat(Arc<dyn Fn() + Send + Sync>, Duration),
+}
+
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    task: Arc<TaskState>,
+    kind: Kind,
+}
+
+// BinaryHeapは最大ヒープなので、期限が早いものほど大きいとみなす
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+struct State {
+    heap: BinaryHeap<Entry>,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub(super) struct Timer {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    pub(super) fn start(scheduler: Scheduler, name: &str) -> Timer {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let timer_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name(format!("{}-timer", name))
+            .spawn(move || run(&timer_shared, &scheduler))
+            .unwrap();
+        Timer {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    pub(super) fn once(&self, delay: Duration, job: Job) -> ScheduledTask {
+        self.insert(delay, Kind::Once(job))
+    }
+
+    pub(super) fn repeat(
+        &self,
+        delay: Duration,
+        interval: Duration,
+        f: Arc<dyn Fn() + Send + Sync>,
+    ) -> ScheduledTask {
+        self.insert(delay, Kind::Repeat(f, interval))
+    }
+
+    fn insert(&self, delay: Duration, kind: Kind) -> ScheduledTask {
+        let task = Arc::new(TaskState {
+            cancelled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+        });
+        let mut state = self.shared.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Entry {
+            deadline: Instant::now() + delay,
+            seq,
+            task: Arc::clone(&task),
+            kind,
+        });
+        drop(state);
+        self.shared.changed.notify_one();
+        ScheduledTask { state: task }
+    }
+
+    /// タイマーを止める。期限前の予約は実行されずに破棄されます。
+    ///
+    /// Stop the timer; jobs that are not yet due are discarded unrun.
+    pub(super) fn shutdown(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn run(shared: &Shared, scheduler: &Scheduler) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.shutdown {
+            break;
+        }
+        let now = Instant::now();
+        let deadline = match state.heap.peek() {
+            None => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+            Some(entry) => entry.deadline,
+        };
+        if deadline > now {
+            state = shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+            continue;
+        }
+
+        let mut entry = state.heap.pop().unwrap();
+        if entry.task.cancelled.load(atomic::Ordering::SeqCst) {
+            continue;
+        }
+        match entry.kind {
+            Kind::Once(job) => scheduler.push(Priority::Normal, guarded(&entry.task, job)),
+            Kind::Repeat(ref f, interval) => {
+                // 前回の実行がまだ終わっていなければ、重ねて実行しない
+                if !entry.task.running.swap(true, atomic::Ordering::SeqCst) {
+                    let f = Arc::clone(f);
+                    let running = Running(Arc::clone(&entry.task));
+                    scheduler.push(
+                        Priority::Normal,
+                        guarded(
+                            &entry.task,
+                            Box::new(move || {
+                                let _running = running;
+                                f();
+                            }),
+                        ),
+                    );
+                }
+                // 遅れた分はまとめて実行せず、次の周期に合わせる
+                entry.deadline += interval;
+                while entry.deadline <= now {
+                    entry.deadline += interval;
+                }
+                state.heap.push(entry);
+            }
+        }
+    }
+}
+
+/// 定期実行の1回分が終わった(パニックや取り消しを含む)ことを記録する。
+struct Running(Arc<TaskState>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.running.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+/// キューで待つ間に取り消されたら実行しない。
+fn guarded(task: &Arc<TaskState>, job: Job) -> Job {
+    let task = Arc::clone(task);
+    Box::new(move || {
+        if !task.cancelled.load(atomic::Ordering::SeqCst) {
+            job();
+        }
+    })
+}
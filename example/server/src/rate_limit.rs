@@ -0,0 +1,432 @@
+//! トークンバケットによるクライアントごとのレート制限。
+//!
+//! 経路(パスの接頭辞)ごとに速度とバースト量を設定し、クライアントごとに
+//! バケットを持ちます。使われなくなったバケットは定期的に捨て、数にも上限が
+//! あるので、メモリ使用量は抑えられます。
+//!
+//! Per-client rate limiting with token buckets. Each route (a path prefix)
+//! has its own rate and burst, and each client gets a bucket per route. Idle
+//! buckets are swept periodically and their number is capped, so memory
+//! stays bounded.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::http::{path_has_prefix, Handler, Request, Response};
+
+/// クライアントを識別する方法。
+///
+/// How clients are told apart.
+#[derive(Debug, Clone)]
+pub enum Key {
+    /// 接続元のIPアドレス。TCPの接続にしかありません。Unixドメインソケットでは
+    /// `RateLimiter::forwarded_for`のヘッダを使い、それも無ければ制限しません。
+    ///
+    /// The peer's IP address, which only TCP connections have. Requests on
+    /// a Unix domain socket use the `RateLimiter::forwarded_for` header
+    /// instead, and are not limited without one.
+    Ip,
+    /// APIキーなどのヘッダ値。ヘッダが無ければIPアドレスで識別します。
+    ///
+    /// A header value such as an API key, falling back to the IP address
+    /// when the header is missing.
+    Header(String),
+}
+
+impl Key {
+    /// 識別できなければ`None`。
+    fn of(&self, request: &Request, forwarded_for: Option<&str>) -> Option<String> {
+        if let Key::Header(name) = self {
+            if let Some(value) = request.header(name) {
+                return Some(format!("{}:{}", name, value));
+            }
+        }
+        if let Some(peer) = request.peer {
+            return Some(peer.ip().to_string());
+        }
+        // 接続元の無いUnixドメインソケットの前には、信頼できるプロキシがいる。
+        // プロキシが最後に付け足した値がクライアントのアドレス
+        let value = request.header(forwarded_for?)?;
+        let client = value.rsplit(',').next()?.trim();
+        (!client.is_empty()).then(|| client.to_string())
+    }
+}
+
+/// 1経路あたりの制限。平均速度と、溜めておけるリクエスト数(バースト)。
+///
+/// A route's limit: the sustained rate and how many requests may burst.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    /// 1秒あたりに補充されるトークン数
+    rate: f64,
+    burst: u32,
+}
+
+impl Limit {
+    /// 1秒あたり`n`リクエスト。バーストの初期値も`n`です。
+    ///
+    /// # パニック
+    ///
+    /// `n`が0のときパニックします。
+    ///
+    /// `n` requests per second, with a burst of `n` until changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn per_second(n: u32) -> Limit {
+        assert!(n > 0);
+        Limit {
+            rate: n as f64,
+            burst: n,
+        }
+    }
+
+    /// 1分あたり`n`リクエスト。バーストの初期値も`n`です。
+    ///
+    /// # パニック
+    ///
+    /// `n`が0のときパニックします。
+    ///
+    /// `n` requests per minute, with a burst of `n` until changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn per_minute(n: u32) -> Limit {
+        assert!(n > 0);
+        Limit {
+            rate: n as f64 / 60.0,
+            burst: n,
+        }
+    }
+
+    /// # パニック
+    ///
+    /// `burst`が0のときパニックします。
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero.
+    pub fn burst(mut self, burst: u32) -> Limit {
+        assert!(burst > 0);
+        self.burst = burst;
+        self
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, limit: &Limit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * limit.rate).min(limit.burst as f64)
+    }
+}
+
+struct State {
+    /// (経路の番号, クライアントのキー) ごとのバケット
+    buckets: HashMap<(usize, String), Bucket>,
+    last_sweep: Instant,
+}
+
+/// 1リクエストを判定した結果。
+struct Quota {
+    limit: Limit,
+    tokens: f64,
+    allowed: bool,
+}
+
+impl Quota {
+    /// 次のトークンが補充されるまでの秒数。
+    fn retry_after(&self) -> u64 {
+        secs_until(1.0 - self.tokens, self.limit.rate).max(1)
+    }
+
+    /// `RateLimit-*`ヘッダを付ける。
+    fn annotate(&self, response: Response) -> Response {
+        let reset = secs_until(self.limit.burst as f64 - self.tokens, self.limit.rate);
+        response
+            .with_header("RateLimit-Limit", &self.limit.burst.to_string())
+            .with_header("RateLimit-Remaining", &(self.tokens as u32).to_string())
+            .with_header("RateLimit-Reset", &reset.to_string())
+    }
+}
+
+fn secs_until(missing: f64, rate: f64) -> u64 {
+    if missing <= 0.0 {
+        0
+    } else {
+        (missing / rate).ceil() as u64
+    }
+}
+
+/// 経路ごとのレート制限。`wrap`でハンドラに被せて使います。
+///
+/// Per-route rate limits, applied by wrapping a handler with `wrap`.
+pub struct RateLimiter {
+    key: Key,
+    routes: Vec<(String, Limit)>,
+    idle_timeout: Duration,
+    max_buckets: usize,
+    forwarded_for: Option<String>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(key: Key) -> RateLimiter {
+        RateLimiter {
+            key,
+            routes: Vec::new(),
+            idle_timeout: Duration::from_secs(60),
+            max_buckets: 100_000,
+            forwarded_for: None,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// `prefix`配下のパスに制限をかける。複数の経路に一致する場合は
+    /// 最も長い接頭辞のものを使い、どれにも一致しなければ制限しません。
+    ///
+    /// Limit paths under `prefix`. When several routes match, the longest
+    /// prefix wins; paths matching none are not limited.
+    pub fn route(mut self, prefix: &str, limit: Limit) -> RateLimiter {
+        self.routes.push((prefix.to_string(), limit));
+        self
+    }
+
+    /// この時間使われなかったバケットを捨てる。満杯まで補充されたバケットは
+    /// 無いのと同じなので、時間に関わらず捨てます。既定は60秒です。
+    ///
+    /// Drop buckets unused for this long. Buckets that have refilled are
+    /// dropped regardless, since they behave exactly like new ones. Defaults
+    /// to 60 seconds.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> RateLimiter {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// 保持するバケット数の上限。上限に達したら、新しいクライアントのために
+    /// 既存のバケットを1つ捨てます。既定は100000です。
+    ///
+    /// # パニック
+    ///
+    /// `max`が0のときパニックします。
+    ///
+    /// Cap the number of buckets held. At the cap, an existing bucket is
+    /// dropped to make room for a new client. Defaults to 100000.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_buckets(mut self, max: usize) -> RateLimiter {
+        assert!(max > 0);
+        self.max_buckets = max;
+        self
+    }
+
+    /// Unixドメインソケットで受けたリクエストを、リバースプロキシが付けた
+    /// このヘッダ(`X-Forwarded-For`など)の最後のアドレスで識別する。
+    /// TCPの接続では偽装できるので使いません。
+    ///
+    /// Identify requests received on a Unix domain socket by the last
+    /// address in this header, such as `X-Forwarded-For`, set by the reverse
+    /// proxy in front. It is ignored on TCP connections, where clients
+    /// could forge it.
+    pub fn forwarded_for(mut self, header: &str) -> RateLimiter {
+        self.forwarded_for = Some(header.to_string());
+        self
+    }
+
+    /// 制限を超えたリクエストには`429 Too Many Requests`を返すハンドラにする。
+    ///
+    /// Wrap `handler` so requests over the limit get `429 Too Many Requests`
+    /// instead of reaching it.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let limiter = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let quota = match limiter.take(request, Instant::now()) {
+                Some(quota) => quota,
+                None => return handler(request),
+            };
+            let response = if quota.allowed {
+                handler(request)
+            } else {
+                Response::new(429)
+                    .with_header("Retry-After", &quota.retry_after().to_string())
+                    .with_header("Content-Type", "text/plain; charset=utf-8")
+                    .with_body("Too Many Requests\n")
+            };
+            quota.annotate(response)
+        })
+    }
+
+    /// 現在保持しているバケットの数。
+    ///
+    /// The number of buckets currently held.
+    pub fn buckets(&self) -> usize {
+        self.state.lock().unwrap().buckets.len()
+    }
+
+    fn take(&self, request: &Request, now: Instant) -> Option<Quota> {
+        let (route, (_, limit)) = self
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| path_has_prefix(&request.path, prefix))
+            .max_by_key(|(_, (prefix, _))| prefix.len())?;
+        let key = self.key.of(request, self.forwarded_for.as_deref())?;
+
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.last_sweep) >= self.idle_timeout {
+            state.last_sweep = now;
+            let routes = &self.routes;
+            let idle_timeout = self.idle_timeout;
+            state.buckets.retain(|(route, _), bucket| {
+                let limit = &routes[*route].1;
+                now.saturating_duration_since(bucket.updated) < idle_timeout
+                    && bucket.refilled(limit, now) < limit.burst as f64
+            });
+        }
+
+        let key = (route, key);
+        if state.buckets.len() >= self.max_buckets && !state.buckets.contains_key(&key) {
+            // 大量のアドレスから来られても、メモリを使い切らないようにする
+            if let Some(evicted) = state.buckets.keys().next().cloned() {
+                state.buckets.remove(&evicted);
+            }
+        }
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(limit, now);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Some(Quota {
+            limit: *limit,
+            tokens: bucket.tokens,
+            allowed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `peer`が空ならUnixドメインソケットからのリクエスト。
+    fn request(path: &str, peer: &str, api_key: Option<&str>) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(key) = api_key {
+            raw.push_str(&format!("X-Api-Key: {}\r\n", key));
+        }
+        raw.push_str("\r\n");
+        let (mut request, _) = Request::parse(raw.as_bytes()).unwrap().unwrap();
+        request.peer = (!peer.is_empty()).then(|| peer.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn burst_then_429_with_headers() {
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200));
+        let handler = RateLimiter::new(Key::Ip)
+            .route("/api", Limit::per_minute(60).burst(2))
+            .wrap(handler);
+        let client = request("/api/items", "10.0.0.1:5000", None);
+
+        let first = handler(&client);
+        assert_eq!(first.status, 200);
+        assert_eq!(first.header("RateLimit-Limit"), Some("2"));
+        assert_eq!(first.header("RateLimit-Remaining"), Some("1"));
+        assert_eq!(handler(&client).status, 200);
+        let limited = handler(&client);
+        assert_eq!(limited.status, 429);
+        assert_eq!(limited.header("Retry-After"), Some("1"));
+        assert_eq!(limited.header("RateLimit-Remaining"), Some("0"));
+
+        // 別のクライアントや制限の無い経路には影響しない
+        assert_eq!(handler(&request("/api", "10.0.0.2:5000", None)).status, 200);
+        let open = handler(&request("/", "10.0.0.1:5000", None));
+        assert_eq!(open.status, 200);
+        assert!(open.header("RateLimit-Limit").is_none());
+    }
+
+    #[test]
+    fn refills_over_time_per_route_and_key() {
+        let limiter = RateLimiter::new(Key::Header("X-Api-Key".to_string()))
+            .route("/", Limit::per_second(10))
+            .route("/slow", Limit::per_second(1));
+        let start = Instant::now();
+        let slow = request("/slow", "10.0.0.1:5000", Some("abc"));
+        assert!(limiter.take(&slow, start).unwrap().allowed);
+        assert!(!limiter.take(&slow, start).unwrap().allowed);
+        assert_eq!(limiter.take(&slow, start).unwrap().retry_after(), 1);
+        assert!(
+            limiter
+                .take(&slow, start + Duration::from_secs(1))
+                .unwrap()
+                .allowed
+        );
+
+        // 同じIPでもキーが違えば別のバケット
+        let other = request("/slow", "10.0.0.1:5000", Some("xyz"));
+        assert!(limiter.take(&other, start).unwrap().allowed);
+        // 経路が違えば別のバケット
+        let fast = request("/fast", "10.0.0.1:5000", Some("abc"));
+        assert!(limiter.take(&fast, start).unwrap().allowed);
+        assert_eq!(limiter.buckets(), 3);
+    }
+
+    #[test]
+    fn idle_buckets_are_swept() {
+        let limiter = RateLimiter::new(Key::Ip)
+            .route("/", Limit::per_minute(1))
+            .idle_timeout(Duration::from_secs(10));
+        let start = Instant::now();
+        for i in 0..5 {
+            let client = request("/", &format!("10.0.0.{}:80", i), None);
+            limiter.take(&client, start);
+        }
+        assert_eq!(limiter.buckets(), 5);
+        limiter.take(
+            &request("/", "10.0.0.9:80", None),
+            start + Duration::from_secs(11),
+        );
+        assert_eq!(limiter.buckets(), 1);
+    }
+
+    #[test]
+    fn unix_clients_use_the_forwarded_header_and_buckets_are_capped() {
+        let limiter = RateLimiter::new(Key::Ip)
+            .route("/", Limit::per_minute(1))
+            .max_buckets(2);
+        let start = Instant::now();
+        assert!(limiter.take(&request("/", "", None), start).is_none());
+
+        let limiter = limiter.forwarded_for("X-Forwarded-For");
+        let mut proxied = request("/", "", None);
+        proxied.headers.push((
+            "X-Forwarded-For".to_string(),
+            "1.2.3.4, 10.0.0.7".to_string(),
+        ));
+        assert!(limiter.take(&proxied, start).unwrap().allowed);
+        assert!(!limiter.take(&proxied, start).unwrap().allowed);
+
+        for i in 0..5 {
+            let client = request("/", &format!("10.0.0.{}:80", i), None);
+            assert!(limiter.take(&client, start).unwrap().allowed);
+        }
+        assert_eq!(limiter.buckets(), 2);
+    }
+}
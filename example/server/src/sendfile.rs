@@ -0,0 +1,227 @@
+//! ファイルをユーザー空間を経由せずにソケットへ送る。
+//!
+//! Linuxでは`sendfile(2)`でカーネル内だけでコピーします。他のOSでは
+//! 小さなバッファを介して読み書きします。
+//!
+//! Zero-copy file bodies. On Linux the kernel copies file pages straight to
+//! the socket with `sendfile(2)`; elsewhere a small buffer is used instead.
+
+use std::fs::File;
+use std::io::{self, Write};
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// バッファ経由で送るときの1回あたりのバイト数。
+const CHUNK: usize = 64 * 1024;
+
+/// `sendfile`で送れるソケット。
+///
+/// A socket a `FileBody` can be sent to.
+#[cfg(target_os = "linux")]
+pub trait Socket: Write + AsRawFd {}
+#[cfg(target_os = "linux")]
+impl<T: Write + AsRawFd> Socket for T {}
+
+/// `sendfile`で送れるソケット。
+///
+/// A socket a `FileBody` can be sent to.
+#[cfg(not(target_os = "linux"))]
+pub trait Socket: Write {}
+#[cfg(not(target_os = "linux"))]
+impl<T: Write> Socket for T {}
+
+/// レスポンスのボディとして送るファイルの一部分。
+///
+/// A byte range of a file, sent as a response body.
+pub struct FileBody {
+    file: File,
+    offset: u64,
+    remaining: u64,
+}
+
+impl FileBody {
+    /// `file`の先頭から`len`バイトを送る。
+    ///
+    /// Send the first `len` bytes of `file`.
+    pub fn new(file: File, len: u64) -> FileBody {
+        FileBody::range(file, 0, len)
+    }
+
+    /// `file`の`offset`バイト目から`len`バイトを送る。
+    ///
+    /// Send `len` bytes of `file` starting at `offset`.
+    pub fn range(file: File, offset: u64, len: u64) -> FileBody {
+        FileBody {
+            file,
+            offset,
+            remaining: len,
+        }
+    }
+
+    /// まだ送っていないバイト数。
+    ///
+    /// Bytes not yet sent.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// 1回だけ送り、送ったバイト数を返す。ノンブロッキングのソケットでは
+    /// `WouldBlock`を返すことがあります。
+    ///
+    /// Make one send and return how many bytes went out. Non-blocking
+    /// sockets may report `WouldBlock`.
+    #[cfg(target_os = "linux")]
+    pub fn send_to<S: Socket>(&mut self, socket: &mut S) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let count = self.remaining.min(0x7fff_f000) as usize;
+        let mut offset = self.offset as libc::off_t;
+        let sent = unsafe {
+            libc::sendfile(
+                socket.as_raw_fd(),
+                self.file.as_raw_fd(),
+                &mut offset,
+                count,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sent == 0 {
+            // 送信中にファイルが切り詰められた
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.advance(sent as usize);
+        Ok(sent as usize)
+    }
+
+    /// 1回だけ送り、送ったバイト数を返す。ノンブロッキングのソケットでは
+    /// `WouldBlock`を返すことがあります。
+    ///
+    /// Make one send and return how many bytes went out. Non-blocking
+    /// sockets may report `WouldBlock`.
+    #[cfg(not(target_os = "linux"))]
+    pub fn send_to<S: Socket>(&mut self, socket: &mut S) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let mut chunk = vec![0; CHUNK.min(self.remaining as usize)];
+        let n = self.read_at(&mut chunk)?;
+        let sent = socket.write(&chunk[..n])?;
+        self.advance(sent);
+        Ok(sent)
+    }
+
+    /// 残りをすべて送る。ブロッキングのソケット用です。
+    ///
+    /// Send everything that is left, for blocking sockets.
+    pub fn send_all<S: Socket>(&mut self, socket: &mut S) -> io::Result<()> {
+        while !self.is_done() {
+            match self.send_to(socket) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// 残りをバッファ経由で書き出す。任意の`Write`に使えます。
+    ///
+    /// Copy what is left through a buffer, for writers that are not sockets.
+    pub fn copy_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK];
+        let mut offset = self.offset;
+        let end = self.offset + self.remaining;
+        while offset < end {
+            let want = CHUNK.min((end - offset) as usize);
+            let n = read_at(&self.file, &mut chunk[..want], offset)?;
+            writer.write_all(&chunk[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// 残りをメモリに読み込む。ボディを書き換えるミドルウェア用です。
+    ///
+    /// Read what is left into memory, for middleware that rewrites bodies.
+    pub fn read_to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.remaining as usize);
+        self.copy_to(&mut out)?;
+        Ok(out)
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read_at(&self, buf: &mut [u8]) -> io::Result<usize> {
+        read_at(&self.file, buf, self.offset)
+    }
+}
+
+/// ファイルの位置を動かさずに`offset`から読む。途中で終わっていればエラーです。
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    let n = file.read_at(buf, offset)?;
+    #[cfg(not(unix))]
+    let n = {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)?
+    };
+    if n == 0 && !buf.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::process;
+    use std::thread;
+
+    #[test]
+    fn sends_whole_file_over_a_socket() {
+        let path = env::temp_dir().join(format!("sendfile-{}", process::id()));
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            TcpStream::connect(addr)
+                .unwrap()
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut body = FileBody::new(File::open(&path).unwrap(), data.len() as u64);
+        body.send_all(&mut socket).unwrap();
+        assert!(body.is_done());
+        drop(socket);
+        assert_eq!(reader.join().unwrap(), data);
+
+        let body = FileBody::new(File::open(&path).unwrap(), 10);
+        assert_eq!(body.read_to_vec().unwrap(), &data[..10]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! 1接続を1ワーカーで処理する従来のI/O方式。
+//!
+//! Thread-per-connection serving: every accepted connection occupies a pool
+//! worker until its response has been written.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{self, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::{Handler, Request, Response};
+use crate::listener::{Listener, Stream};
+use crate::shutdown::{InFlight, Shutdown};
+use crate::{JobOptions, Priority, ThreadPool};
+
+/// リクエストを送り終えるまでの期限。少しずつ送り続けても延びません。
+/// ボディをハンドラに読ませるリクエストでは、ヘッダ部までの期限で、
+/// ボディは1回の読み込みごとにこの時間まで待ちます。
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 応答の書き込み1回ごとに待つ時間。
+pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 接続を受け付けるたびに`handle_connection`をプールで実行する。
+///
+/// Accept connections forever, running `handle_connection` on the pool for
+/// each of them.
+pub fn serve(listener: TcpListener, pool: Arc<ThreadPool>, handler: Handler) {
+    let shutdown = Shutdown::new(Duration::ZERO);
+    serve_until(
+        vec![listener.into()],
+        pool,
+        handler,
+        |_| Priority::Normal,
+        |_| false,
+        &shutdown,
+    );
+}
+
+/// `shutdown`の合図まで、すべての`listeners`で接続を受け付ける。合図の後は
+/// 受け付けをやめ、まだリクエストを送り終えていない接続は閉じ、処理中の接続を
+/// 猶予時間まで待って、すべて終わったかを返します。
+/// リクエストを読むまでは通常のレーンで、ハンドラは`priority`が選んだレーンで
+/// 実行します。`stream_body`が選んだリクエストはボディを読まずにハンドラへ
+/// 渡し、ハンドラが`Request::take_body_reader`で読みます。
+///
+/// Accept connections on every listener until `shutdown` is triggered, then
+/// stop accepting, close connections that have not sent a complete request
+/// and wait for in-flight ones up to the drain timeout. Returns whether they
+/// all finished in time. Requests are read on the normal lane and handled on
+/// the lane `priority` picks for them. Requests `stream_body` selects reach
+/// the handler before their body is read, and the handler reads it with
+/// `Request::take_body_reader`.
+pub fn serve_until(
+    listeners: Vec<Listener>,
+    pool: Arc<ThreadPool>,
+    handler: Handler,
+    priority: fn(&Request) -> Priority,
+    stream_body: fn(&Request) -> bool,
+    shutdown: &Shutdown,
+) -> bool {
+    let routes = Arc::new(Routes {
+        handler,
+        priority,
+        stream_body,
+    });
+    let in_flight = Arc::new(InFlight::new());
+    let reading = Arc::new(Reading::default());
+    {
+        let reading = Arc::clone(&reading);
+        shutdown.on_trigger(move || reading.close_all());
+    }
+    thread::scope(|scope| {
+        for listener in listeners {
+            // acceptでブロックしているので、自分に接続して起こす
+            if let Ok(addr) = listener.local_addr() {
+                shutdown.on_trigger(move || {
+                    let _ = addr.connect();
+                });
+            }
+            let pool = &pool;
+            let routes = &routes;
+            let in_flight = &in_flight;
+            let reading = &reading;
+            scope.spawn(move || accept_until(listener, pool, routes, in_flight, reading, shutdown));
+        }
+    });
+    match shutdown.deadline() {
+        Some(deadline) => in_flight.wait_idle(deadline),
+        None => true,
+    }
+}
+
+/// ハンドラと、リクエストごとの扱いを選ぶ関数。
+struct Routes {
+    handler: Handler,
+    priority: fn(&Request) -> Priority,
+    stream_body: fn(&Request) -> bool,
+}
+
+/// まだリクエストを読んでいる接続。停止の合図で閉じる。
+#[derive(Default)]
+struct Reading {
+    state: Mutex<ReadingState>,
+}
+
+#[derive(Default)]
+struct ReadingState {
+    streams: HashMap<u64, Stream>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl Reading {
+    /// `stream`を登録して番号を返す。合図の後ならすぐに閉じる。
+    fn enter(&self, stream: &Stream) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            let _ = stream.shutdown(net::Shutdown::Both);
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        if let Ok(clone) = stream.try_clone() {
+            state.streams.insert(id, clone);
+        }
+        Some(id)
+    }
+
+    fn leave(&self, id: u64) {
+        self.state.lock().unwrap().streams.remove(&id);
+    }
+
+    fn close_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for (_, stream) in state.streams.drain() {
+            let _ = stream.shutdown(net::Shutdown::Both);
+        }
+    }
+}
+
+/// 合図まで`listener`で受け付け、接続をプールに渡す。
+fn accept_until(
+    listener: Listener,
+    pool: &Arc<ThreadPool>,
+    routes: &Arc<Routes>,
+    in_flight: &Arc<InFlight>,
+    reading: &Arc<Reading>,
+    shutdown: &Shutdown,
+) {
+    while !shutdown.is_triggered() {
+        let mut stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        if shutdown.is_triggered() {
+            break;
+        }
+        let routes = Arc::clone(routes);
+        let guard = in_flight.enter();
+        let reading = Arc::clone(reading);
+        let lanes = Arc::clone(pool);
+        pool.execute(move || {
+            let id = match reading.enter(&stream) {
+                Some(id) => id,
+                None => return,
+            };
+            // 送り終えないクライアントがワーカーを占有し続けないようにする
+            let deadline = Instant::now() + READ_TIMEOUT;
+            let request = read_request(&mut stream, deadline, routes.stream_body);
+            reading.leave(id);
+            let request = match request {
+                Some(request) => request,
+                None => return,
+            };
+            let lane = (routes.priority)(&request);
+            if lane == Priority::Normal {
+                respond(stream, &routes.handler, &request);
+                drop(guard);
+                return;
+            }
+            // 通常以外のレーンのハンドラは、そのレーンに入れ直す
+            lanes.execute_with(JobOptions::new().priority(lane), move || {
+                respond(stream, &routes.handler, &request);
+                drop(guard);
+            });
+        });
+    }
+}
+
+/// リクエストを1つ読み、ハンドラの結果を書いて接続を閉じる。
+///
+/// Read one request, write the handler's response and close the connection.
+pub fn handle_connection(mut stream: Stream, handler: &Handler) {
+    let deadline = Instant::now() + READ_TIMEOUT;
+    if let Some(request) = read_request(&mut stream, deadline, |_| false) {
+        respond(stream, handler, &request);
+    }
+}
+
+/// `deadline`までにリクエストを読む。読めなければ断りの応答を書いて`None`を返す。
+fn read_request(
+    stream: &mut Stream,
+    deadline: Instant,
+    stream_body: fn(&Request) -> bool,
+) -> Option<Request> {
+    let read = Request::read_with(&mut Deadline { stream, deadline }, stream_body);
+    match read {
+        Ok((mut request, rest)) => {
+            request.peer = stream.peer_addr();
+            if stream_body(&request) {
+                // ボディは長くかかってよいが、止まったままなら諦める
+                match stream
+                    .set_read_timeout(Some(READ_TIMEOUT))
+                    .and_then(|()| stream.try_clone())
+                {
+                    Ok(reader) => request.set_body_reader(rest, reader),
+                    Err(e) => {
+                        eprintln!("try_clone failed: {}", e);
+                        return None;
+                    }
+                }
+            }
+            Some(request)
+        }
+        // 時間切れや停止で閉じられた接続には何も返さない
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            None
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(e) => {
+            eprintln!("bad request: {}", e);
+            let _ = Response::rejecting(&e).write_to(stream);
+            None
+        }
+    }
+}
+
+/// 読み込みのたびに、期限までの残り時間をタイムアウトにする。
+struct Deadline<'a> {
+    stream: &'a mut Stream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// ハンドラの結果を書いて接続を閉じる。ハンドラがパニックしたら500を返す。
+pub(crate) fn respond(mut stream: Stream, handler: &Handler, request: &Request) {
+    let mut response =
+        panic::catch_unwind(AssertUnwindSafe(|| handler(request))).unwrap_or_else(|_| {
+            eprintln!("handler panicked on {} {}", request.method, request.path);
+            Response::new(500)
+        });
+    if let Some(upgrade) = response.upgrade.take() {
+        upgrade(stream);
+        return;
+    }
+    // 受け取らないクライアントのために、書き込みもいつまでも待たない
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        eprintln!("set_write_timeout failed: {}", e);
+        return;
+    }
+    // この方式ではワーカーを占有し続けないよう、毎回接続を閉じる
+    let mut response = response.with_header("Connection", "close");
+    let file = response.file.take();
+    let written = response.write_to(&mut stream).and_then(|()| match file {
+        Some(mut file) => file.send_all(&mut stream),
+        None => Ok(()),
+    });
+    if let Err(e) = written {
+        eprintln!("write failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    #[test]
+    fn drains_in_flight_connections_until_the_deadline() {
+        for (sleep, drained) in [(100, true), (2000, false)] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let shutdown = Shutdown::new(Duration::from_millis(500));
+            let (started, wait_started) = mpsc::channel();
+            let started = std::sync::Mutex::new(started);
+            let handler: Handler = Arc::new(move |_: &Request| {
+                started.lock().unwrap().send(()).unwrap();
+                thread::sleep(Duration::from_millis(sleep));
+                Response::new(200).with_body("done")
+            });
+            let server = {
+                let shutdown = shutdown.clone();
+                thread::spawn(move || {
+                    let pool = Arc::new(ThreadPool::new(2));
+                    let drained = serve_until(
+                        vec![listener.into()],
+                        Arc::clone(&pool),
+                        handler,
+                        |_| Priority::Normal,
+                        |_| false,
+                        &shutdown,
+                    );
+                    // 待ちきれなかったワーカーを待たずに戻る
+                    std::mem::forget(pool);
+                    drained
+                })
+            };
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            wait_started.recv().unwrap();
+            shutdown.trigger();
+            assert_eq!(server.join().unwrap(), drained);
+            assert!(TcpStream::connect(addr).is_err());
+            if drained {
+                let mut got = String::new();
+                stream.read_to_string(&mut got).unwrap();
+                assert!(got.ends_with("done"));
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_closes_connections_still_sending_their_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new(Duration::from_secs(5));
+        let handler: Handler = Arc::new(|_: &Request| Response::new(200));
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                let pool = Arc::new(ThreadPool::new(2));
+                serve_until(
+                    vec![listener.into()],
+                    pool,
+                    handler,
+                    |_| Priority::Normal,
+                    |_| false,
+                    &shutdown,
+                )
+            })
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        shutdown.trigger();
+        assert!(server.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let mut got = Vec::new();
+        assert_eq!(stream.read_to_end(&mut got).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn trickling_clients_hit_the_whole_request_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Stream::Tcp(listener.accept().unwrap().0);
+        // 1回ごとのタイムアウトには掛からない間隔で、1バイトずつ送り続ける
+        thread::spawn(move || {
+            for &byte in b"GET /".iter().chain([b'a'; 100].iter()) {
+                if client.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(300);
+        assert!(read_request(&mut stream, deadline, |_| false).is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
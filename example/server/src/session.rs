@@ -0,0 +1,636 @@
+//! サーバー側のセッション。
+//!
+//! セッションのデータはメモリかディスクの`Store`に置き、クライアントには
+//! 署名したランダムなIDだけをクッキーで渡します。ハンドラは
+//! `Session::of(request)`でセッションを読み書きします。
+//!
+//! Server-side sessions. Session data lives in a `Store`, in memory or on
+//! disk; the client only holds a signed random id in a cookie. Handlers
+//! read and write their session through `Session::of(request)`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::cookie::{Cookie, SameSite};
+use crate::http::{Handler, Request, Response};
+
+/// 期限切れのセッションを掃除する間隔。
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 保存されたセッション。
+///
+/// A stored session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub data: HashMap<String, String>,
+    pub expires: SystemTime,
+}
+
+/// セッションの保存先。
+///
+/// Where sessions are kept.
+pub trait Store: Send + Sync {
+    fn load(&self, id: &str) -> io::Result<Option<Record>>;
+    fn save(&self, id: &str, record: &Record) -> io::Result<()>;
+    fn remove(&self, id: &str) -> io::Result<()>;
+    /// `now`までに期限が切れたセッションを消し、その数を返す。
+    ///
+    /// Remove sessions that expired by `now` and return how many there were.
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize>;
+}
+
+/// メモリ上の保存先。再起動するとセッションは消えます。
+///
+/// Keeps sessions in memory; they are lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<HashMap<String, Record>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<Record>> {
+        Ok(self.records.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, record: &Record) -> io::Result<()> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.records.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+        records.retain(|_, record| record.expires > now);
+        Ok(before - records.len())
+    }
+}
+
+/// セッションごとに1ファイルを置く保存先。再起動してもセッションが残ります。
+///
+/// Keeps one file per session in a directory, so sessions survive restarts.
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    /// `dir`を作り、本人だけが読めるようにする。
+    ///
+    /// Create `dir` if needed, readable by this user only.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<DiskStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        Ok(DiskStore { dir })
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid session id",
+            ));
+        }
+        Ok(self.dir.join(id))
+    }
+}
+
+impl Store for DiskStore {
+    fn load(&self, id: &str) -> io::Result<Option<Record>> {
+        match fs::File::open(self.path(id)?) {
+            Ok(file) => read_record(BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, id: &str, record: &Record) -> io::Result<()> {
+        let path = self.path(id)?;
+        let expires = record
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut out = format!("expires {}\n", expires.as_secs());
+        for (key, value) in &record.data {
+            out.push_str(&format!("{}\t{}\n", escape(key), escape(value)));
+        }
+        // 読み手が書きかけのファイルを見ないよう、書き終えてから置き換える。
+        // 同じセッションを同時に保存しても混ざらないよう、一時ファイルは毎回別の名前にする
+        let mut random = [0; 8];
+        getrandom::getrandom(&mut random).expect("no system randomness");
+        let suffix: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+        let tmp = self.dir.join(format!("{}.{}.tmp", id, suffix));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            file.write_all(out.as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|()| fs::rename(&tmp, path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            let expired = match fs::File::open(&path) {
+                Ok(file) => read_record(BufReader::new(file))
+                    .map(|record| record.expires <= now)
+                    // 壊れたファイルも片付ける
+                    .unwrap_or(true),
+                Err(_) => continue,
+            };
+            if expired && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn read_record<R: BufRead>(reader: R) -> io::Result<Record> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt session file");
+    let mut lines = reader.lines();
+    let first = lines.next().ok_or_else(invalid)??;
+    let secs: u64 = first
+        .strip_prefix("expires ")
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut data = HashMap::new();
+    for line in lines {
+        let line = line?;
+        let (key, value) = line.split_once('\t').ok_or_else(invalid)?;
+        data.insert(unescape(key), unescape(value));
+    }
+    Ok(Record {
+        data,
+        expires: UNIX_EPOCH + Duration::from_secs(secs),
+    })
+}
+
+/// タブと改行を行の区切りと区別できるようにする。
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[derive(Default)]
+struct State {
+    data: HashMap<String, String>,
+    changed: bool,
+    rotate: bool,
+    destroy: bool,
+}
+
+/// 1リクエストの間のセッション。クローンは同じセッションを指します。
+///
+/// The session of the current request. Clones refer to the same session.
+#[derive(Clone, Default)]
+pub struct Session {
+    state: Arc<Mutex<State>>,
+}
+
+impl Session {
+    /// `Sessions`が`request`に付けたセッションを返す。
+    ///
+    /// The session `Sessions` attached to `request`, if it is wrapped in one.
+    pub fn of(request: &Request) -> Option<Session> {
+        request.extensions.get()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(key.to_string(), value.to_string());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        state.changed = true;
+        state.data.remove(key)
+    }
+
+    /// データはそのままにIDを振り直す。ログインの直後に呼び、ログイン前に
+    /// 知られたIDを使えなくします(セッション固定攻撃の対策)。
+    ///
+    /// Keep the data but issue a new id. Call this right after login so
+    /// that an id known before login stops working (session fixation).
+    pub fn rotate(&self) {
+        self.state.lock().unwrap().rotate = true;
+    }
+
+    /// セッションを消し、クッキーも削除させる。ログアウトに使います。
+    ///
+    /// Delete the session and its cookie, e.g. on logout.
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.destroy = true;
+    }
+}
+
+/// セッションを読み込み、ハンドラの後で保存するミドルウェア。
+///
+/// データを書き込むまではセッションを作らず、クッキーも送りません。
+/// 同じセッションへの同時のリクエストは、後から終わった方の内容が残ります。
+///
+/// Middleware that loads the session before the handler runs and saves it
+/// afterwards. No session or cookie is created until something is written;
+/// concurrent requests in one session race, and the last to finish wins.
+pub struct Sessions {
+    store: Box<dyn Store>,
+    key: Vec<u8>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+    last_sweep: Mutex<Instant>,
+}
+
+impl Sessions {
+    /// `store`に保存し、`key`でクッキーに署名する。
+    ///
+    /// # パニック
+    ///
+    /// `key`が32バイトより短いとパニックします。
+    ///
+    /// Store sessions in `store`, signing cookies with `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is shorter than 32 bytes.
+    pub fn new<S: Store + 'static>(store: S, key: &[u8]) -> Sessions {
+        assert!(key.len() >= 32, "session key must be at least 32 bytes");
+        Sessions {
+            store: Box::new(store),
+            key: key.to_vec(),
+            cookie_name: "session".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            same_site: SameSite::Lax,
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// クッキーの名前。既定は`session`です。
+    ///
+    /// The cookie name. Defaults to `session`.
+    pub fn cookie_name(mut self, name: &str) -> Sessions {
+        self.cookie_name = Cookie::new(name, "").name().to_string();
+        self
+    }
+
+    /// 最後に使われてからセッションが切れるまでの時間。既定は1日です。
+    ///
+    /// How long a session lives after its last use. Defaults to one day.
+    pub fn ttl(mut self, ttl: Duration) -> Sessions {
+        self.ttl = ttl;
+        self
+    }
+
+    /// クッキーに`Secure`を付ける。HTTPSで配信するときは有効にしてください。
+    ///
+    /// Mark the cookie `Secure`. Enable this when serving over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    /// クッキーの`SameSite`。既定は`Lax`です。
+    ///
+    /// The cookie's `SameSite`. Defaults to `Lax`.
+    pub fn same_site(mut self, same_site: SameSite) -> Sessions {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let sessions = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let now = SystemTime::now();
+            sessions.sweep();
+            let (id, record) = match sessions.load(request, now) {
+                Some((id, record)) => (Some(id), Some(record)),
+                None => (None, None),
+            };
+            let session = Session::default();
+            if let Some(record) = &record {
+                session.state.lock().unwrap().data = record.data.clone();
+            }
+            request.extensions.insert(session.clone());
+            let response = handler(request);
+
+            let state = session.state.lock().unwrap();
+            let had_cookie = request.cookie(&sessions.cookie_name).is_some();
+            sessions.finish(
+                &state,
+                id,
+                record.map(|r| r.expires),
+                had_cookie,
+                now,
+                response,
+            )
+        })
+    }
+
+    /// 署名が正しく、期限の切れていないセッションを読み込む。
+    fn load(&self, request: &Request, now: SystemTime) -> Option<(String, Record)> {
+        let id = self.verify(request.cookie(&self.cookie_name)?)?;
+        match self.store.load(id) {
+            Ok(Some(record)) if record.expires > now => Some((id.to_string(), record)),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("session: loading failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// ハンドラの後で、セッションの変更を保存してクッキーを送る。
+    fn finish(
+        &self,
+        state: &State,
+        mut id: Option<String>,
+        expires: Option<SystemTime>,
+        had_cookie: bool,
+        now: SystemTime,
+        response: Response,
+    ) -> Response {
+        if state.destroy || state.rotate {
+            if let Some(old) = id.take() {
+                if let Err(e) = self.store.remove(&old) {
+                    eprintln!("session: removing failed: {}", e);
+                }
+            }
+        }
+        if state.destroy {
+            return if had_cookie {
+                response.with_cookie(&self.cookie("").max_age(Duration::ZERO))
+            } else {
+                response
+            };
+        }
+        // 期限の半分を過ぎたら延長する。毎回は保存しない
+        let renew = expires
+            .and_then(|expires| expires.duration_since(now).ok())
+            .is_none_or(|left| left < self.ttl / 2);
+        let id = match id {
+            Some(id) if state.changed || renew => id,
+            Some(_) => return response,
+            None if state.data.is_empty() => return response,
+            None => new_id(),
+        };
+        let record = Record {
+            data: state.data.clone(),
+            expires: now + self.ttl,
+        };
+        if let Err(e) = self.store.save(&id, &record) {
+            eprintln!("session: saving failed: {}", e);
+            return response;
+        }
+        response.with_cookie(&self.cookie(&self.sign(&id)).max_age(self.ttl))
+    }
+
+    fn cookie(&self, value: &str) -> Cookie {
+        Cookie::new(&self.cookie_name, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site)
+    }
+
+    fn mac(&self, id: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    /// `id.署名`の形にする。
+    fn sign(&self, id: &str) -> String {
+        let tag = self.mac(id).finalize().into_bytes();
+        format!("{}.{}", id, URL_SAFE_NO_PAD.encode(tag))
+    }
+
+    /// 署名を確かめ、IDを返す。
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, tag) = value.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        // 比較は定数時間で行われる
+        self.mac(id).verify_slice(&tag).ok()?;
+        Some(id)
+    }
+
+    fn sweep(&self) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if last_sweep.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = Instant::now();
+        if let Err(e) = self.store.remove_expired(SystemTime::now()) {
+            eprintln!("session: sweeping failed: {}", e);
+        }
+    }
+}
+
+/// 推測できない256ビットのID。
+fn new_id() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("no system randomness");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn app(sessions: Sessions) -> Handler {
+        sessions.wrap(Arc::new(|request: &Request| {
+            let session = Session::of(request).unwrap();
+            match request.path.as_str() {
+                "/login" => {
+                    session.insert("user", "alice");
+                    session.rotate();
+                }
+                "/logout" => session.destroy(),
+                "/visit" => {
+                    let visits = session.get("visits").map_or(0, |v| v.parse().unwrap());
+                    session.insert("visits", &(visits + 1).to_string());
+                }
+                _ => {}
+            }
+            Response::new(200).with_body(session.get("user").unwrap_or_default())
+        }))
+    }
+
+    fn get(handler: &Handler, path: &str, cookie: Option<&str>) -> Response {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(cookie) = cookie {
+            raw.push_str(&format!("Cookie: session={}\r\n", cookie));
+        }
+        raw.push_str("\r\n");
+        handler(&Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+    }
+
+    /// `Set-Cookie`からクッキーの値を取り出す。
+    fn issued(response: &Response) -> Option<String> {
+        let header = response.header("Set-Cookie")?;
+        let value = header.split(';').next()?.strip_prefix("session=")?;
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn rotates_on_login_and_clears_on_logout() {
+        let handler = app(Sessions::new(MemoryStore::new(), KEY));
+
+        // 書き込むまではセッションを作らない
+        assert_eq!(issued(&get(&handler, "/", None)), None);
+
+        let before = issued(&get(&handler, "/visit", None)).unwrap();
+        let login = get(&handler, "/login", Some(&before));
+        let header = login.header("Set-Cookie").unwrap();
+        assert!(header.contains("; Path=/; Max-Age=86400; HttpOnly; SameSite=Lax"));
+        let after = issued(&login).unwrap();
+        assert_ne!(before, after);
+
+        assert_eq!(get(&handler, "/", Some(&after)).body, b"alice");
+        // ログイン前のIDはもう使えない
+        assert_eq!(get(&handler, "/", Some(&before)).body, b"");
+        // 署名を書き換えたクッキーは無視する
+        let forged = format!("{}x", &after[..after.len() - 1]);
+        assert_eq!(get(&handler, "/", Some(&forged)).body, b"");
+
+        let logout = get(&handler, "/logout", Some(&after));
+        assert!(logout.header("Set-Cookie").unwrap().contains("Max-Age=0"));
+        assert_eq!(get(&handler, "/", Some(&after)).body, b"");
+    }
+
+    #[test]
+    fn expired_sessions_are_ignored() {
+        let sessions = Sessions::new(MemoryStore::new(), KEY);
+        let cookie = sessions.sign("old");
+        sessions
+            .store
+            .save(
+                "old",
+                &Record {
+                    data: HashMap::from([("user".to_string(), "alice".to_string())]),
+                    expires: SystemTime::now() - Duration::from_secs(1),
+                },
+            )
+            .unwrap();
+        assert_eq!(sessions.store.remove_expired(SystemTime::now()).unwrap(), 1);
+        let handler = app(sessions);
+        assert_eq!(get(&handler, "/", Some(&cookie)).body, b"");
+    }
+
+    #[test]
+    fn disk_store_round_trips() {
+        let dir = env::temp_dir().join(format!("sessions-{}", process::id()));
+        let store = DiskStore::open(&dir).unwrap();
+        let record = Record {
+            data: HashMap::from([("note".to_string(), "a\tb\nc\\d".to_string())]),
+            expires: UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+        };
+        store.save("abc_-1", &record).unwrap();
+        assert_eq!(store.load("abc_-1").unwrap(), Some(record));
+        assert!(store.load("../etc").is_err());
+        assert_eq!(store.remove_expired(SystemTime::now()).unwrap(), 0);
+
+        // 同じセッションを同時に保存しても、どれか1つが丸ごと残る
+        let store = Arc::new(store);
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    let record = Record {
+                        data: HashMap::from([("writer".to_string(), i.to_string().repeat(4096))]),
+                        expires: UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+                    };
+                    for _ in 0..20 {
+                        store.save("abc_-1", &record).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let data = store.load("abc_-1").unwrap().unwrap().data;
+        let value = &data["writer"];
+        assert_eq!(value.len(), 4096);
+        assert!(value.chars().all(|c| value.starts_with(c)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        store.remove("abc_-1").unwrap();
+        assert_eq!(store.load("abc_-1").unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! シグナルによる穏やかな停止。
+//!
+//! `Shutdown`は停止の合図を共有するハンドルです。合図が出るとサーバーは
+//! 新しい接続の受け付けをやめ、処理中のリクエストを猶予時間まで待ってから
+//! 戻ります。
+//!
+//! Graceful shutdown. A `Shutdown` is a shared stop signal: once triggered,
+//! servers stop accepting, let in-flight requests finish within the drain
+//! timeout, and return whether they managed to.
+
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+type Listener = Box<dyn Fn() + Send + Sync>;
+
+struct Inner {
+    triggered: AtomicBool,
+    drain_timeout: Duration,
+    /// 合図が出た時刻。待っているスレッドもこのロックで起こす
+    at: Mutex<Option<Instant>>,
+    changed: Condvar,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+/// 停止の合図。クローンはすべて同じ状態を共有します。
+///
+/// A stop signal shared by every clone.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    /// 合図の後、処理中のリクエストを最大`drain_timeout`まで待つ。
+    ///
+    /// Once triggered, wait up to `drain_timeout` for in-flight requests.
+    pub fn new(drain_timeout: Duration) -> Shutdown {
+        Shutdown {
+            inner: Arc::new(Inner {
+                triggered: AtomicBool::new(false),
+                drain_timeout,
+                at: Mutex::new(None),
+                changed: Condvar::new(),
+                listeners: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// 停止の合図を出す。2回目以降は何もしません。
+    ///
+    /// Signal shutdown. Calls after the first do nothing.
+    pub fn trigger(&self) {
+        {
+            let mut at = self.inner.at.lock().unwrap();
+            if at.is_some() {
+                return;
+            }
+            *at = Some(Instant::now());
+            self.inner.triggered.store(true, Ordering::SeqCst);
+        }
+        self.inner.changed.notify_all();
+        for listener in self.inner.listeners.lock().unwrap().iter() {
+            listener();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// 処理中のリクエストを待つ期限。合図がまだなら`None`です。
+    ///
+    /// When draining must end, or `None` before shutdown is triggered.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner
+            .at
+            .lock()
+            .unwrap()
+            .map(|at| at + self.inner.drain_timeout)
+    }
+
+    /// 合図が出たら`f`を呼ぶ。既に出ていればすぐに呼びます。
+    /// ブロックしているI/Oを起こすのに使います。
+    ///
+    /// Call `f` on trigger, or right away if already triggered. Servers use
+    /// this to wake threads blocked in `accept` or `poll`.
+    pub fn on_trigger<F>(&self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let mut listeners = self.inner.listeners.lock().unwrap();
+        if self.is_triggered() {
+            drop(listeners);
+            f();
+        } else {
+            listeners.push(Box::new(f));
+        }
+    }
+
+    /// 合図が出るまでブロックする。
+    ///
+    /// Block until shutdown is triggered.
+    pub fn wait(&self) {
+        let mut at = self.inner.at.lock().unwrap();
+        while at.is_none() {
+            at = self.inner.changed.wait(at).unwrap();
+        }
+    }
+
+    /// SIGINTとSIGTERMで合図を出すようにする。猶予中にもう一度受け取ったら、
+    /// 待たずに終了ステータス130で終了します。
+    ///
+    /// Trigger on SIGINT or SIGTERM. A second signal while draining exits
+    /// immediately with status 130.
+    pub fn on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    if shutdown.is_triggered() {
+                        eprintln!("signal {} again, exiting now", signal);
+                        process::exit(130);
+                    }
+                    eprintln!("signal {}, draining", signal);
+                    shutdown.trigger();
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// 処理中の数を数え、0になるのを待てるカウンタ。
+pub(crate) struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl InFlight {
+    pub(crate) fn new() -> InFlight {
+        InFlight {
+            count: Mutex::new(0),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// 1つ増やし、ドロップで減らすガードを返す。
+    pub(crate) fn enter(self: &Arc<Self>) -> InFlightGuard {
+        *self.count.lock().unwrap() += 1;
+        InFlightGuard(Arc::clone(self))
+    }
+
+    /// 0になるか`deadline`を過ぎるまで待ち、0になったかを返す。
+    pub(crate) fn wait_idle(&self, deadline: Instant) -> bool {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            count = self.idle.wait_timeout(count, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+pub(crate) struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn trigger_runs_listeners_once_and_wakes_waiters() {
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        shutdown.on_trigger(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(shutdown.deadline().is_none());
+
+        let waiter = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.wait())
+        };
+        shutdown.trigger();
+        shutdown.trigger();
+        waiter.join().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(shutdown.deadline().unwrap() > Instant::now());
+
+        // 合図の後に登録したものはすぐ呼ばれる
+        let counter = Arc::clone(&calls);
+        shutdown.on_trigger(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn in_flight_waits_for_guards() {
+        let in_flight = Arc::new(InFlight::new());
+        let guard = in_flight.enter();
+        let soon = Instant::now() + Duration::from_millis(20);
+        assert!(!in_flight.wait_idle(soon));
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+        });
+        assert!(in_flight.wait_idle(Instant::now() + Duration::from_secs(5)));
+        release.join().unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+//! Server-Sent Events (`text/event-stream`) の配信。
+//!
+//! 購読したクライアントの接続は`EventStream`が保持し、単一の配信スレッドが
+//! ハートビートを送ります。ハンドラを実行したワーカーはすぐに解放されます。
+//! 接続はノンブロッキングで、送りきれない分はクライアントごとに溜め、溜まり
+//! すぎたクライアントは切り離すので、遅いクライアントが配信を止めることは
+//! ありません。
+//!
+//! Server-Sent Events broadcasting. Subscribed connections are owned by the
+//! `EventStream` rather than by a pool worker, and one background thread
+//! sends heartbeats to all of them. Connections are non-blocking: what a
+//! client cannot take yet is queued for it, and a client whose queue grows
+//! too long is dropped, so a slow reader never stalls the broadcast.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::http::Response;
+use crate::listener::Stream;
+
+/// 1クライアントに溜めておく未送信バイト数の上限。
+const MAX_PENDING: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(data: S) -> Event {
+        Event {
+            id: None,
+            event: None,
+            data: data.into(),
+            retry: None,
+        }
+    }
+
+    pub fn id<S: Into<String>>(mut self, id: S) -> Event {
+        self.id = Some(single_line(id.into()));
+        self
+    }
+
+    pub fn event<S: Into<String>>(mut self, name: S) -> Event {
+        self.event = Some(single_line(name.into()));
+        self
+    }
+
+    /// 再接続までの待ち時間をクライアントに伝える。
+    ///
+    /// Hint how long the client should wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.lines() {
+            writeln!(f, "data: {}", line)?;
+        }
+        if self.data.is_empty() {
+            writeln!(f, "data:")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// 購読者の接続と、まだ送れていないバイト列。
+struct Client {
+    stream: Stream,
+    pending: Vec<u8>,
+}
+
+impl Client {
+    /// `bytes`を送信待ちに加え、送れるだけ送る。接続が使えなくなったか
+    /// 溜まりすぎたら`false`を返す。
+    fn send(&mut self, bytes: &[u8]) -> bool {
+        self.pending.extend_from_slice(bytes);
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        self.pending.len() <= MAX_PENDING
+    }
+}
+
+struct Shared {
+    clients: Vec<Client>,
+    history: VecDeque<Event>,
+    capacity: usize,
+    next_id: u64,
+    retry: Option<Duration>,
+}
+
+impl Shared {
+    /// 全クライアントに送り、失敗したか溜まりすぎた接続は切り離す。
+    /// 書き込みはブロックしないので、ロックを持ったままでよい。
+    fn broadcast(&mut self, bytes: &[u8]) {
+        self.clients.retain_mut(|client| client.send(bytes));
+    }
+}
+
+pub struct EventStream {
+    shared: Arc<Mutex<Shared>>,
+    shutdown: Option<mpsc::Sender<()>>,
+    heartbeat: Option<thread::JoinHandle<()>>,
+}
+
+impl EventStream {
+    /// 新しいEventStreamを生成する。
+    ///
+    /// `history`件までのイベントを`Last-Event-ID`による再開用に保持し、
+    /// `heartbeat`ごとにコメント行を送って切断を検出します。
+    ///
+    /// Create a new EventStream that keeps the last `history` events for
+    /// `Last-Event-ID` resumption and sends a comment line every `heartbeat`.
+    pub fn new(history: usize, heartbeat: Duration) -> EventStream {
+        let shared = Arc::new(Mutex::new(Shared {
+            clients: Vec::new(),
+            history: VecDeque::with_capacity(history),
+            capacity: history,
+            next_id: 1,
+            retry: None,
+        }));
+
+        let (shutdown, receiver) = mpsc::channel::<()>();
+        let heartbeat_shared = Arc::clone(&shared);
+        let heartbeat = thread::Builder::new()
+            .name("sse-heartbeat".to_string())
+            .spawn(move || {
+                // 送信側が落とされるまで、タイムアウトごとにハートビートを送る
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(heartbeat) {
+                    heartbeat_shared.lock().unwrap().broadcast(b":\n\n");
+                }
+            })
+            .unwrap();
+
+        EventStream {
+            shared,
+            shutdown: Some(shutdown),
+            heartbeat: Some(heartbeat),
+        }
+    }
+
+    /// 購読開始時にクライアントへ送る再接続待ち時間を設定する。
+    ///
+    /// Set the reconnection delay sent to every new subscriber.
+    pub fn with_retry(self, retry: Duration) -> EventStream {
+        self.shared.lock().unwrap().retry = Some(retry);
+        self
+    }
+
+    /// 接続を購読者として登録する。
+    ///
+    /// レスポンスヘッダを書き、`last_event_id`より後の保持済みイベントを
+    /// 再送してから接続を引き取ります。呼び出し元はすぐに戻れます。
+    ///
+    /// Take over `stream` as a subscriber: write the response head, replay
+    /// retained events newer than `last_event_id`, then return immediately.
+    pub fn subscribe(&self, stream: Stream, last_event_id: Option<&str>) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let mut head = Vec::new();
+        Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_header("Connection", "keep-alive")
+            .write_head(&mut head)?;
+
+        // 再送と登録を同じロックの中で行い、その間のイベントを取りこぼさない
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(retry) = shared.retry {
+            write!(head, "retry: {}\n\n", retry.as_millis())?;
+        }
+        let skip = last_event_id
+            .and_then(|last| {
+                shared
+                    .history
+                    .iter()
+                    .position(|e| e.id.as_deref() == Some(last))
+            })
+            .map_or(0, |i| i + 1);
+        for event in shared.history.iter().skip(skip) {
+            write!(head, "{}", event)?;
+        }
+        let mut client = Client {
+            stream,
+            pending: Vec::new(),
+        };
+        if !client.send(&head) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "subscriber went away during replay",
+            ));
+        }
+        shared.clients.push(client);
+        Ok(())
+    }
+
+    /// イベントを全購読者へ配信し、付与されたIDを返す。
+    ///
+    /// IDが無いイベントには連番を振ります。
+    ///
+    /// Broadcast `event` to every subscriber and return its id, assigning a
+    /// sequential one when the event has none.
+    pub fn send(&self, mut event: Event) -> String {
+        let mut shared = self.shared.lock().unwrap();
+        let id = match &event.id {
+            Some(id) => id.clone(),
+            None => {
+                let id = shared.next_id.to_string();
+                shared.next_id += 1;
+                event.id = Some(id.clone());
+                id
+            }
+        };
+        shared.broadcast(event.to_string().as_bytes());
+        if shared.capacity > 0 {
+            if shared.history.len() == shared.capacity {
+                shared.history.pop_front();
+            }
+            shared.history.push_back(event);
+        }
+        id
+    }
+
+    /// 現在の購読者数。
+    ///
+    /// The number of connected subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.shared.lock().unwrap().clients.len()
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(thread) = self.heartbeat.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn single_line(s: String) -> String {
+    s.replace(['\r', '\n'], "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    fn connect(events: &EventStream, last_event_id: Option<&str>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        events.subscribe(server.into(), last_event_id).unwrap();
+        client
+    }
+
+    fn read_available(client: &mut TcpStream) -> String {
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(n) = client.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn event_wire_format() {
+        let event = Event::new("a\nb")
+            .id("7")
+            .event("score")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "id: 7\nevent: score\nretry: 3000\ndata: a\ndata: b\n\n"
+        );
+    }
+
+    #[test]
+    fn resume_from_last_event_id() {
+        let events =
+            EventStream::new(8, Duration::from_secs(60)).with_retry(Duration::from_secs(2));
+        events.send(Event::new("one"));
+        events.send(Event::new("two"));
+        events.send(Event::new("three"));
+
+        let mut client = connect(&events, Some("1"));
+        let got = read_available(&mut client);
+        assert!(got.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        assert!(got.contains("retry: 2000\n\n"));
+        assert!(!got.contains("data: one"));
+        assert!(got.contains("id: 2\ndata: two\n\nid: 3\ndata: three\n\n"));
+
+        events.send(Event::new("four").event("update"));
+        assert_eq!(
+            read_available(&mut client),
+            "id: 4\nevent: update\ndata: four\n\n"
+        );
+    }
+
+    #[test]
+    fn slow_clients_are_dropped_without_stalling_others() {
+        let events = EventStream::new(0, Duration::from_secs(60));
+        let _stalled = connect(&events, None);
+        let data = "x".repeat(64 * 1024);
+        let started = std::time::Instant::now();
+        for _ in 0..200 {
+            events.send(Event::new(data.as_str()));
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(events.subscribers(), 0);
+    }
+
+    #[test]
+    fn heartbeat_drops_closed_clients() {
+        let events = EventStream::new(0, Duration::from_millis(20));
+        let client = connect(&events, None);
+        assert_eq!(events.subscribers(), 1);
+        drop(client);
+        for _ in 0..100 {
+            if events.subscribers() == 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("closed subscriber was not dropped");
+    }
+}
@@ -0,0 +1,508 @@
+//! ディレクトリの静的ファイル配信。
+//!
+//! URLの接頭辞ごとにディレクトリを割り当てて配信します。walk-the-dogの
+//! `static/`と`pkg/`のように、複数のディレクトリを1つのアプリとして
+//! 見せることができます。
+//!
+//! Static file serving. Directories are mounted under URL prefixes, so
+//! several folders, such as walk-the-dog's `static/` and `pkg/`, can be
+//! served as a single app.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cache::FileCache;
+use crate::http::{path_has_prefix, Handler, Request, Response};
+
+mod listing;
+
+/// 拡張子から`Content-Type`を決める。
+///
+/// The `Content-Type` for a file, chosen by its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("webp") => "image/webp",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `%XX`をデコードする。不正なエスケープやUTF-8でない結果は`None`です。
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// URLパスの残りを`root`配下のファイルパスにする。`..`などで外へ出ようと
+/// するパスと、一覧に載せない`.`で始まる名前を含むパスは`None`です。
+fn join_safely(root: &Path, rest: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in percent_decode(rest)?.split('/') {
+        match segment {
+            "" | "." => {}
+            s if s.starts_with('.') => return None,
+            s if s.contains('\\') || s.contains('\0') => return None,
+            s => path.push(s),
+        }
+    }
+    Some(path)
+}
+
+/// `Range: bytes=...`の1つの区間を`[start, end)`にする。満たせない区間は
+/// `Err`、複数の区間や読めない値は`None`で、その場合は全体を返します。
+fn byte_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // `bytes=-500`は末尾の500バイト
+        let suffix: u64 = end.parse().ok()?;
+        (len.saturating_sub(suffix), len)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => len,
+            end => {
+                let last: u64 = end.parse().ok()?;
+                if last < start {
+                    return None;
+                }
+                last.saturating_add(1).min(len)
+            }
+        };
+        (start, end)
+    };
+    Some(if range.0 < range.1 {
+        Ok(range)
+    } else {
+        Err(())
+    })
+}
+
+/// 接頭辞ごとにディレクトリを割り当てた静的ファイル配信。
+/// `wrap`でハンドラに被せ、割り当ての無いパスはそのハンドラに任せます。
+///
+/// Directories mounted under URL prefixes. Wrap a handler with `wrap`;
+/// paths outside every mount, and missing files when there is no SPA
+/// fallback, are passed through to it.
+pub struct StaticFiles {
+    mounts: Vec<(String, PathBuf)>,
+    fallback: Option<String>,
+    isolation: bool,
+    cache: Option<Arc<FileCache>>,
+    sendfile_threshold: Option<u64>,
+    listing: bool,
+}
+
+/// URLパスが指すもの。
+enum Target {
+    File(PathBuf),
+    /// `index.html`の無いディレクトリ
+    Directory(PathBuf),
+}
+
+impl Default for StaticFiles {
+    fn default() -> StaticFiles {
+        StaticFiles {
+            mounts: Vec::new(),
+            fallback: None,
+            isolation: false,
+            cache: None,
+            sendfile_threshold: Some(1024 * 1024),
+            listing: false,
+        }
+    }
+}
+
+impl StaticFiles {
+    pub fn new() -> StaticFiles {
+        StaticFiles::default()
+    }
+
+    /// `prefix`配下のパスを`dir`から配信する。複数の割り当てに一致する場合は
+    /// 最も長い接頭辞のものを使います。
+    ///
+    /// Serve paths under `prefix` from `dir`. When several mounts match, the
+    /// longest prefix wins.
+    pub fn mount<P: Into<PathBuf>>(mut self, prefix: &str, dir: P) -> StaticFiles {
+        self.mounts
+            .push((prefix.trim_end_matches('/').to_string(), dir.into()));
+        self
+    }
+
+    /// 割り当て内で見つからず、拡張子の無いパスには`path`の内容を返す。
+    /// クライアント側でルーティングするシングルページアプリ用です。
+    ///
+    /// Answer missing, extension-less paths inside a mount with the file at
+    /// URL `path`, for single-page apps that route on the client. Missing
+    /// assets such as `/x.png` still fall through.
+    pub fn spa_fallback(mut self, path: &str) -> StaticFiles {
+        self.fallback = Some(path.to_string());
+        self
+    }
+
+    /// `Cross-Origin-Opener-Policy`と`Cross-Origin-Embedder-Policy`を付け、
+    /// ページをクロスオリジン分離する(`SharedArrayBuffer`を使う場合に必要)。
+    ///
+    /// Send COOP/COEP headers so pages are cross-origin isolated, as needed
+    /// for `SharedArrayBuffer` and threaded wasm.
+    pub fn cross_origin_isolation(mut self, enabled: bool) -> StaticFiles {
+        self.isolation = enabled;
+        self
+    }
+
+    /// ファイルの内容を`cache`にキャッシュする。
+    ///
+    /// Keep file contents in `cache`, which may be shared with other users.
+    pub fn cache(mut self, cache: Arc<FileCache>) -> StaticFiles {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// この大きさ以上のファイルはメモリに読まず、`sendfile`で送る。
+    /// `None`なら常にメモリ経由です。既定は1MiBです。
+    ///
+    /// Send files of at least this many bytes with `sendfile` instead of
+    /// reading them into memory (and the cache). `None` always buffers.
+    /// Defaults to 1 MiB.
+    pub fn sendfile_threshold(mut self, threshold: Option<u64>) -> StaticFiles {
+        self.sendfile_threshold = threshold;
+        self
+    }
+
+    /// `index.html`の無いディレクトリには一覧ページを返す。`Accept`に応じて
+    /// HTMLかJSONになります。`.`で始まる名前は一覧に載せず、一覧の有無に
+    /// かかわらず配信もしません。
+    ///
+    /// Answer directories without an `index.html` with a generated listing,
+    /// as HTML or JSON depending on `Accept`. Hidden names are left out, and
+    /// are never served either, listing or not.
+    pub fn directory_listing(mut self, enabled: bool) -> StaticFiles {
+        self.listing = enabled;
+        self
+    }
+
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let files = Arc::new(self);
+        Arc::new(move |request: &Request| match files.serve(request) {
+            Some(response) => response,
+            None => handler(request),
+        })
+    }
+
+    /// リクエストに対応するファイルを返す。GETとHEAD以外、割り当て外のパス、
+    /// 見つからないファイルは`None`です。
+    ///
+    /// Serve the file for `request`, or `None` if it is not a `GET` or
+    /// `HEAD`, the path is outside every mount, or the file does not exist.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        // 他のメソッドは同じパスのAPIに任せる
+        if request.method != "GET" && request.method != "HEAD" {
+            return None;
+        }
+        let (prefix, _) = self.mount_for(&request.path)?;
+        let file = match self.resolve(&request.path) {
+            Some(Target::File(file)) => file,
+            Some(Target::Directory(dir)) if self.listing => {
+                return listing::response(&dir, request).map(|r| self.isolate(r));
+            }
+            _ => {
+                let rest = &request.path[prefix.len()..];
+                let last = rest.rsplit('/').next().unwrap_or("");
+                if last.contains('.') {
+                    return None;
+                }
+                match self.resolve(self.fallback.as_deref()?)? {
+                    Target::File(file) => file,
+                    Target::Directory(_) => return None,
+                }
+            }
+        };
+        let len = fs::metadata(&file).ok()?.len();
+        let mut response = Response::new(200)
+            .with_header("Content-Type", content_type(&file))
+            .with_header("Accept-Ranges", "bytes");
+        match request.header("Range").and_then(|r| byte_range(r, len)) {
+            Some(Ok((start, end))) => {
+                // 範囲はファイルのその部分だけを、常にファイルのまま送る
+                response.status = 206;
+                response = response.with_header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end - 1, len),
+                );
+                response = if request.method == "HEAD" {
+                    response.with_header("Content-Length", &(end - start).to_string())
+                } else {
+                    response.with_file_range(File::open(&file).ok()?, start, end - start)
+                };
+                return Some(self.isolate(response));
+            }
+            Some(Err(())) => {
+                let response =
+                    Response::new(416).with_header("Content-Range", &format!("bytes */{}", len));
+                return Some(self.isolate(response));
+            }
+            None => {}
+        }
+        let zero_copy = self.sendfile_threshold.is_some_and(|t| len >= t);
+        if request.method == "HEAD" {
+            response = response.with_header("Content-Length", &len.to_string());
+        } else if zero_copy {
+            response = response.with_file(File::open(&file).ok()?, len);
+        } else {
+            // キャッシュの内容はコピーせずに共有する
+            let body = match &self.cache {
+                Some(cache) => cache.get(&file).ok()?,
+                None => Arc::new(fs::read(&file).ok()?),
+            };
+            response = response.with_shared_body(body);
+        }
+        Some(self.isolate(response))
+    }
+
+    fn isolate(&self, response: Response) -> Response {
+        if !self.isolation {
+            return response;
+        }
+        response
+            .with_header("Cross-Origin-Opener-Policy", "same-origin")
+            .with_header("Cross-Origin-Embedder-Policy", "require-corp")
+    }
+
+    fn mount_for(&self, path: &str) -> Option<&(String, PathBuf)> {
+        self.mounts
+            .iter()
+            .filter(|(prefix, _)| prefix.is_empty() || path_has_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+    }
+
+    /// URLパスを実在するファイルにする。ディレクトリなら`index.html`を探します。
+    fn resolve(&self, path: &str) -> Option<Target> {
+        let (prefix, dir) = self.mount_for(path)?;
+        let file = join_safely(dir, &path[prefix.len()..])?;
+        if file.is_dir() {
+            let index = file.join("index.html");
+            return if index.is_file() {
+                Some(Target::File(index))
+            } else {
+                Some(Target::Directory(file))
+            };
+        }
+        if file.is_file() {
+            Some(Target::File(file))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    /// 共有やファイルのボディも含めた中身。
+    fn body(mut response: Response) -> Vec<u8> {
+        response.buffer_file().unwrap();
+        response.body
+    }
+
+    /// テストごとに別の一時ディレクトリを作る。
+    fn site(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("static-files-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("static")).unwrap();
+        fs::create_dir_all(root.join("pkg")).unwrap();
+        fs::write(root.join("static/index.html"), "<canvas></canvas>").unwrap();
+        fs::write(
+            root.join("static/Idle (1).png"),
+            [0x89, b'P', b'N', b'G', 0xff],
+        )
+        .unwrap();
+        fs::write(root.join("pkg/index_bg.wasm"), b"\0asm\x01\0\0\0").unwrap();
+        fs::write(root.join("secret.txt"), "secret").unwrap();
+        root
+    }
+
+    fn handler(root: &Path, isolation: bool) -> Handler {
+        let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+        StaticFiles::new()
+            .cache(Arc::new(FileCache::new(1024)))
+            .mount("/game", root.join("static"))
+            .mount("/game/pkg", root.join("pkg"))
+            .spa_fallback("/game/index.html")
+            .cross_origin_isolation(isolation)
+            .wrap(not_found)
+    }
+
+    #[test]
+    fn serves_binary_files_with_mime_types() {
+        let root = site("mime");
+        let handler = handler(&root, false);
+
+        let wasm = handler(&request("GET", "/game/pkg/index_bg.wasm"));
+        assert_eq!(wasm.status, 200);
+        assert_eq!(wasm.header("Content-Type"), Some("application/wasm"));
+        assert_eq!(body(wasm), b"\0asm\x01\0\0\0");
+
+        let png = handler(&request("GET", "/game/Idle%20(1).png"));
+        assert_eq!(png.header("Content-Type"), Some("image/png"));
+        // キャッシュに当たれば同じバッファを共有する
+        let again = handler(&request("GET", "/game/Idle%20(1).png"));
+        assert!(Arc::ptr_eq(
+            png.shared.as_ref().unwrap(),
+            again.shared.as_ref().unwrap()
+        ));
+        assert_eq!(body(png), [0x89, b'P', b'N', b'G', 0xff]);
+
+        let head = handler(&request("HEAD", "/game/"));
+        assert_eq!(head.header("Content-Length"), Some("17"));
+        assert!(head.body.is_empty() && head.shared.is_none());
+        assert!(head.header("Cross-Origin-Opener-Policy").is_none());
+
+        assert_eq!(handler(&request("POST", "/game/")).status, 404);
+
+        // 大きなファイルはメモリに読まずにファイルのまま返す
+        let big = vec![7u8; 2 * 1024 * 1024];
+        fs::write(root.join("pkg/big.wasm"), &big).unwrap();
+        let mut response = handler(&request("GET", "/game/pkg/big.wasm"));
+        assert!(response.body.is_empty());
+        assert_eq!(response.header("Content-Length"), Some("2097152"));
+        response.buffer_file().unwrap();
+        assert_eq!(response.body, big);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn spa_fallback_and_pass_through() {
+        let root = site("spa");
+        let handler = handler(&root, true);
+
+        let deep = handler(&request("GET", "/game/levels/2"));
+        assert_eq!(deep.status, 200);
+        assert_eq!(
+            deep.header("Cross-Origin-Embedder-Policy"),
+            Some("require-corp")
+        );
+        assert_eq!(body(deep), b"<canvas></canvas>");
+        // 見つからない素材や割り当て外のパスは内側のハンドラへ
+        assert_eq!(handler(&request("GET", "/game/missing.png")).status, 404);
+        assert_eq!(handler(&request("GET", "/other")).status, 404);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn lists_directories_without_index() {
+        let root = site("listing");
+        fs::write(root.join("pkg/.hidden"), "x").unwrap();
+        let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+        let handler = StaticFiles::new()
+            .mount("/files", &root)
+            .directory_listing(true)
+            .wrap(not_found);
+
+        let page = handler(&request("GET", "/files/pkg/"));
+        assert_eq!(
+            page.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let page = String::from_utf8(page.body).unwrap();
+        assert!(page.contains("href=\"/files/pkg/index_bg.wasm\""));
+        assert!(!page.contains(".hidden"));
+        assert_eq!(handler(&request("GET", "/files/pkg/.hidden")).status, 404);
+        assert_eq!(handler(&request("GET", "/files/pkg/%2Ehidden")).status, 404);
+
+        let raw = b"GET /files/pkg HTTP/1.1\r\nAccept: application/json\r\n\r\n";
+        let json = handler(&Request::parse(raw).unwrap().unwrap().0);
+        assert_eq!(json.header("Content-Type"), Some("application/json"));
+        assert!(String::from_utf8(json.body)
+            .unwrap()
+            .contains("\"name\":\"index_bg.wasm\",\"type\":\"file\",\"size\":8"));
+        // indexのあるディレクトリはそのまま配信する
+        assert_eq!(
+            body(handler(&request("GET", "/files/static/"))),
+            b"<canvas></canvas>"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn serves_byte_ranges() {
+        let root = site("range");
+        let handler = handler(&root, false);
+        let ranged = |range: &str| {
+            let raw = format!("GET /game/index.html HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            handler(&Request::parse(raw.as_bytes()).unwrap().unwrap().0)
+        };
+
+        let first = ranged("bytes=1-6");
+        assert_eq!(first.status, 206);
+        assert_eq!(first.header("Content-Range"), Some("bytes 1-6/17"));
+        assert_eq!(first.header("Content-Length"), Some("6"));
+        assert_eq!(body(first), b"canvas");
+        assert_eq!(body(ranged("bytes=-9")), b"</canvas>");
+        assert_eq!(body(ranged("bytes=8-")), b"</canvas>");
+
+        let unsatisfiable = ranged("bytes=17-");
+        assert_eq!(unsatisfiable.status, 416);
+        assert_eq!(unsatisfiable.header("Content-Range"), Some("bytes */17"));
+        // 複数の区間や読めない値は無視して全体を返す
+        assert_eq!(ranged("bytes=0-1,3-4").status, 200);
+        assert_eq!(ranged("lines=1-2").status, 200);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_root() {
+        let root = Path::new("/srv/site");
+        assert_eq!(
+            join_safely(root, "/a/./b%2Ec"),
+            Some(PathBuf::from("/srv/site/a/b.c"))
+        );
+        assert_eq!(join_safely(root, "/../secret.txt"), None);
+        assert_eq!(join_safely(root, "/.git/config"), None);
+        assert_eq!(join_safely(root, "/%2e%2e/secret.txt"), None);
+        assert_eq!(join_safely(root, "/a%5C..%5Csecret.txt"), None);
+        assert_eq!(join_safely(root, "/bad%zz"), None);
+    }
+}
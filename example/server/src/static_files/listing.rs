@@ -0,0 +1,278 @@
+//! `index.html`の無いディレクトリの一覧ページ。
+//!
+//! Generated listings for directories without an `index.html`, as HTML or
+//! JSON depending on `Accept`.
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+use crate::http::{Request, Response};
+use crate::template::escape_html;
+
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+    /// UNIX時刻(秒)
+    modified: Option<u64>,
+}
+
+/// `dir`の一覧を返す。`.`で始まる名前は含めません。
+fn read(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            // UTF-8でない名前はURLにできないので載せない
+            Err(_) => continue,
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        });
+    }
+    Ok(entries)
+}
+
+/// `?sort=name|size|modified&order=asc|desc`で並べ替える。既定は名前の昇順で、
+/// どの並びでもディレクトリを先に置きます。
+fn sort(entries: &mut [Entry], query: Option<&str>) {
+    let mut key = "name";
+    let mut descending = false;
+    for pair in query.unwrap_or("").split('&') {
+        match pair.split_once('=') {
+            Some(("sort", value)) => key = value,
+            Some(("order", value)) => descending = value == "desc",
+            _ => {}
+        }
+    }
+    entries.sort_by(|a, b| {
+        let order = match key {
+            "size" => a.size.cmp(&b.size),
+            "modified" => a.modified.cmp(&b.modified),
+            _ => std::cmp::Ordering::Equal,
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.dir.cmp(&a.dir).then(order)
+    });
+}
+
+/// URLのパス部分に使えない文字を`%XX`にする。
+fn encode_segment(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+/// UNIX時刻をUTCのISO 8601形式にする。
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rest = secs % 86_400;
+    // 1970-03-01を起点にした暦計算(Howard Hinnantのcivil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+fn html(path: &str, entries: &[Entry]) -> String {
+    let base = path.trim_end_matches('/');
+    let mut title = String::new();
+    escape_html(&format!("{}/", base), &mut title);
+    let mut href_base = String::new();
+    escape_html(base, &mut href_base);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+         <body>\n<h1>Index of {0}</h1>\n<table>\n\
+         <tr><th><a href=\"?sort=name\">Name</a></th>\
+         <th><a href=\"?sort=size&amp;order=desc\">Size</a></th>\
+         <th><a href=\"?sort=modified&amp;order=desc\">Modified</a></th></tr>\n",
+        title
+    );
+    if !base.is_empty() {
+        out.push_str("<tr><td><a href=\"");
+        escape_html(&base[..base.rfind('/').unwrap_or(0)], &mut out);
+        out.push_str("/\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.dir { "/" } else { "" };
+        let _ = write!(
+            out,
+            "<tr><td><a href=\"{}/{}{}\">",
+            href_base,
+            encode_segment(&entry.name),
+            slash
+        );
+        escape_html(&entry.name, &mut out);
+        let size = if entry.dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let _ = writeln!(
+            out,
+            "{}</a></td><td>{}</td><td>{}</td></tr>",
+            slash,
+            size,
+            entry.modified.map(format_time).unwrap_or_default()
+        );
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+#[derive(Serialize)]
+struct Listing<'a> {
+    path: &'a str,
+    entries: Vec<JsonEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: u64,
+    modified: Option<String>,
+}
+
+fn json(path: &str, entries: &[Entry]) -> Vec<u8> {
+    let listing = Listing {
+        path,
+        entries: entries
+            .iter()
+            .map(|entry| JsonEntry {
+                name: &entry.name,
+                kind: if entry.dir { "directory" } else { "file" },
+                size: entry.size,
+                modified: entry.modified.map(format_time),
+            })
+            .collect(),
+    };
+    // 文字列と数値だけなので失敗しない
+    serde_json::to_vec(&listing).unwrap()
+}
+
+/// `Accept`がJSONを求め、HTMLを求めていなければJSONで返す。
+fn wants_json(request: &Request) -> bool {
+    match request.header("Accept") {
+        Some(accept) => accept.contains("application/json") && !accept.contains("text/html"),
+        None => false,
+    }
+}
+
+/// ディレクトリ`dir`の一覧ページを作る。
+pub(super) fn response(dir: &Path, request: &Request) -> Option<Response> {
+    let mut entries = read(dir).ok()?;
+    sort(&mut entries, request.query.as_deref());
+    let (content_type, body) = if wants_json(request) {
+        ("application/json", json(&request.path, &entries))
+    } else {
+        let page = html(&request.path, &entries).into_bytes();
+        ("text/html; charset=utf-8", page)
+    };
+    Some(
+        Response::new(200)
+            .with_header("Content-Type", content_type)
+            .with_header("Vary", "Accept")
+            .with_body(body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, dir: bool, size: u64, modified: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            dir,
+            size,
+            modified: Some(modified),
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_by_query_with_directories_first() {
+        let mut entries = vec![
+            entry("b.png", false, 10, 300),
+            entry("a.wasm", false, 500, 100),
+            entry("pkg", true, 0, 200),
+            entry("c.js", false, 50, 200),
+        ];
+        sort(&mut entries, None);
+        assert_eq!(names(&entries), ["pkg", "a.wasm", "b.png", "c.js"]);
+        sort(&mut entries, Some("sort=size&order=desc"));
+        assert_eq!(names(&entries), ["pkg", "a.wasm", "c.js", "b.png"]);
+        sort(&mut entries, Some("sort=modified"));
+        assert_eq!(names(&entries), ["pkg", "a.wasm", "c.js", "b.png"]);
+    }
+
+    #[test]
+    fn escapes_names() {
+        let entries = vec![entry("<x> & \"y\".txt", false, 1, 0)];
+        let page = html("/files/", &entries);
+        assert!(page.contains(
+            "<a href=\"/files/%3Cx%3E%20%26%20%22y%22.txt\">&lt;x&gt; &amp; &quot;y&quot;.txt</a>"
+        ));
+        assert!(page.contains("1970-01-01T00:00:00Z"));
+        assert_eq!(
+            String::from_utf8(json("/files/", &entries)).unwrap(),
+            "{\"path\":\"/files/\",\"entries\":[{\"name\":\"<x> & \\\"y\\\".txt\",\
+             \"type\":\"file\",\"size\":1,\"modified\":\"1970-01-01T00:00:00Z\"}]}"
+        );
+    }
+
+    #[test]
+    fn formats_times_as_utc() {
+        assert_eq!(format_time(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(1_792_321_445), "2026-10-18T11:04:05Z");
+    }
+}
@@ -0,0 +1,237 @@
+//! `ThreadPool::spawn`で投入したジョブの結果を受け取るハンドル。
+//!
+//! Handles for jobs submitted with `ThreadPool::spawn`.
+
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// ジョブがパニックした。パニックのメッセージを保持します。
+    ///
+    /// The job panicked; holds the panic message.
+    Panicked(String),
+    /// ジョブが実行される前に取り消された。
+    ///
+    /// The job was dropped before it ran, e.g. by a cancellation token.
+    Cancelled,
+}
+
+impl JoinError {
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> JoinError {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        JoinError::Panicked(message)
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "task panicked: {}", message),
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+impl error::Error for JoinError {}
+
+enum Slot<T> {
+    Pending(Option<Waker>),
+    Done(Result<T, JoinError>),
+    Taken,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    done: Condvar,
+}
+
+/// ワーカー側で結果を書き込むための片割れ。
+///
+/// The worker's half of a `TaskHandle`.
+pub(crate) struct Completer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(self, result: Result<T, JoinError>) {
+        self.set(result);
+    }
+
+    fn set(&self, result: Result<T, JoinError>) {
+        let mut slot = self.shared.slot.lock().unwrap();
+        if !matches!(*slot, Slot::Pending(_)) {
+            return;
+        }
+        let previous = std::mem::replace(&mut *slot, Slot::Done(result));
+        drop(slot);
+        self.shared.done.notify_all();
+        if let Slot::Pending(Some(waker)) = previous {
+            waker.wake();
+        }
+    }
+}
+
+/// 結果を書かずに捨てられた(実行されなかった)ジョブは取り消し扱いにする。
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.set(Err(JoinError::Cancelled));
+    }
+}
+
+/// ジョブの結果を待つハンドル。ブロックして待つ、完了を確認する、
+/// `Future`として`.await`する、のいずれかで使います。
+///
+/// A handle to a spawned job's result. It can be joined (blocking), polled
+/// without blocking, or awaited as a `Future`.
+pub struct TaskHandle<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub(crate) fn pair<T>() -> (Completer<T>, TaskHandle<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot::Pending(None)),
+        done: Condvar::new(),
+    });
+    (
+        Completer {
+            shared: Arc::clone(&shared),
+        },
+        TaskHandle { shared },
+    )
+}
+
+impl<T> TaskHandle<T> {
+    /// ジョブが終わるまでブロックし、結果を返す。
+    ///
+    /// Block until the job finishes and return its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        while let Slot::Pending(_) = *slot {
+            slot = self.shared.done.wait(slot).unwrap();
+        }
+        take(&mut slot)
+    }
+
+    /// ジョブが終わっていれば結果を返し、まだなら`None`を返す。
+    ///
+    /// # パニック
+    ///
+    /// 結果を受け取った後に再び呼ぶとパニックします。
+    ///
+    /// Return the result if the job has finished, or `None` if not yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result has already been taken.
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        match *slot {
+            Slot::Pending(_) => None,
+            _ => Some(take(&mut slot)),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.shared.slot.lock().unwrap(), Slot::Pending(_))
+    }
+}
+
+impl<T> Future for TaskHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        match &mut *slot {
+            Slot::Pending(waker) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(take(&mut slot)),
+        }
+    }
+}
+
+fn take<T>(slot: &mut Slot<T>) -> Result<T, JoinError> {
+    match std::mem::replace(slot, Slot::Taken) {
+        Slot::Done(result) => result,
+        _ => panic!("task result already taken"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread;
+    use std::time::Duration;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn join_returns_result() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<_> = (0..8).map(|i| pool.spawn(move || i * i)).collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 1, 4, 9, 16, 25, 36, 49]);
+    }
+
+    #[test]
+    fn panic_becomes_error_and_worker_survives() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.spawn(|| -> u32 { panic!("boom") });
+        assert_eq!(failed.join(), Err(JoinError::Panicked("boom".to_string())));
+        assert_eq!(pool.spawn(|| 7).join(), Ok(7));
+    }
+
+    #[test]
+    fn try_join_and_await() {
+        let pool = ThreadPool::new(1);
+        let (release, gate) = mpsc::channel::<()>();
+        let mut handle = pool.spawn(move || {
+            gate.recv().unwrap();
+            "done"
+        });
+        assert!(handle.try_join().is_none());
+        assert!(!handle.is_finished());
+        release.send(()).unwrap();
+        assert_eq!(block_on(handle), Ok("done"));
+
+        let slow = pool.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            1
+        });
+        assert_eq!(block_on(slow), Ok(1));
+    }
+}
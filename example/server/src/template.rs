@@ -0,0 +1,558 @@
+//! HTMLテンプレート。
+//!
+//! 構文は次のとおりです。
+//!
+//! - `{{ name }}`、`{{ user.name }}`: 値をHTMLエスケープして埋め込む
+//! - `{{ name | raw }}`: エスケープせずに埋め込む
+//! - `{% if name %}…{% else %}…{% endif %}`: 空でない値のときだけ出力する
+//! - `{% for item in items %}…{% endfor %}`: リストの要素ごとに繰り返す
+//! - `{% include "header.html" %}`: 同じディレクトリの別のテンプレートを埋め込む
+//!
+//! HTML templates. `{{ name }}` inserts an escaped value (`| raw` skips
+//! escaping), `{% if %}`/`{% else %}`/`{% endif %}` and
+//! `{% for x in xs %}`/`{% endfor %}` branch and loop, and
+//! `{% include "file.html" %}` inserts another template from the same
+//! directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::http::Response;
+
+/// インクルードの深さの上限。自分自身を含むテンプレートで止まるように。
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// テンプレートに渡す値。
+///
+/// A value passed to a template.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(Context),
+}
+
+impl Value {
+    /// `if`で真になるか。空の文字列やリストは偽です。
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(s) => !s.is_empty(),
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(_) => true,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Text(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Value {
+                Value::Text(n.to_string())
+            }
+        })*
+    };
+}
+
+from_number!(u16, u32, u64, usize, i32, i64, f64);
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context)
+    }
+}
+
+/// 名前と値の組。テンプレートの変数になります。
+///
+/// Named values that become a template's variables.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Context {
+    values: HashMap<String, Value>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    pub fn with<V: Into<Value>>(mut self, name: &str, value: V) -> Context {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.values.insert(name.to_string(), value.into());
+    }
+}
+
+enum Node {
+    Text(String),
+    Var {
+        path: Vec<String>,
+        raw: bool,
+    },
+    If {
+        path: Vec<String>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+    },
+    Include(String),
+}
+
+/// 解析済みのテンプレート。
+///
+/// A parsed template.
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    /// 構文の誤りは、行番号の付いた`InvalidData`のエラーになります。
+    ///
+    /// Syntax errors are `InvalidData` errors that carry the line number.
+    pub fn parse(source: &str) -> io::Result<Template> {
+        let mut parser = Parser { source, pos: 0 };
+        match parser.nodes()? {
+            (nodes, None) => Ok(Template { nodes }),
+            (_, Some(tag)) => Err(parser.error(parser.pos, &format!("unexpected {{% {} %}}", tag))),
+        }
+    }
+
+    /// `include`は使えません。インクルードするなら`Templates`から描画してください。
+    ///
+    /// Render without includes; use `Templates` for templates that include
+    /// others.
+    pub fn render(&self, context: &Context) -> io::Result<String> {
+        let no_includes = |name: &str| Err(invalid(&format!("cannot include {:?} here", name)));
+        let mut out = String::new();
+        let mut scope = Scope {
+            context,
+            locals: Vec::new(),
+        };
+        render(&self.nodes, &mut scope, &mut out, &no_includes, 0)?;
+        Ok(out)
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// 終端か、`else`/`endif`/`endfor`まで読む。止まったタグも返す。
+    fn nodes(&mut self) -> io::Result<(Vec<Node>, Option<String>)> {
+        let mut nodes = Vec::new();
+        loop {
+            let rest = &self.source[self.pos..];
+            let start = match (rest.find("{{"), rest.find("{%")) {
+                (Some(a), Some(b)) => a.min(b),
+                (Some(a), None) | (None, Some(a)) => a,
+                (None, None) => {
+                    if !rest.is_empty() {
+                        nodes.push(Node::Text(rest.to_string()));
+                    }
+                    self.pos = self.source.len();
+                    return Ok((nodes, None));
+                }
+            };
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            let tag = self.pos + start;
+            let is_var = rest[start..].starts_with("{{");
+            let close = if is_var { "}}" } else { "%}" };
+            let len = self.source[tag + 2..]
+                .find(close)
+                .ok_or_else(|| self.error(tag, "unclosed tag"))?;
+            let inner = self.source[tag + 2..tag + 2 + len].trim();
+            self.pos = tag + 2 + len + 2;
+
+            if is_var {
+                let (expr, raw) = match inner.split_once('|') {
+                    Some((expr, filter)) if filter.trim() == "raw" => (expr.trim(), true),
+                    Some((_, filter)) => {
+                        return Err(self.error(tag, &format!("unknown filter {:?}", filter.trim())))
+                    }
+                    None => (inner, false),
+                };
+                let path = self.path(tag, expr)?;
+                nodes.push(Node::Var { path, raw });
+                continue;
+            }
+            let words: Vec<&str> = inner.split_whitespace().collect();
+            match words.as_slice() {
+                ["if", expr] => {
+                    let path = self.path(tag, expr)?;
+                    let (then, end) = self.nodes()?;
+                    let (otherwise, end) = match end.as_deref() {
+                        Some("else") => self.nodes()?,
+                        _ => (Vec::new(), end),
+                    };
+                    self.expect(tag, end, "endif")?;
+                    nodes.push(Node::If {
+                        path,
+                        then,
+                        otherwise,
+                    });
+                }
+                ["for", name, "in", expr] => {
+                    let path = self.path(tag, expr)?;
+                    let name = self.path(tag, name)?;
+                    if name.len() != 1 {
+                        return Err(self.error(tag, "loop variable must be a plain name"));
+                    }
+                    let (body, end) = self.nodes()?;
+                    self.expect(tag, end, "endfor")?;
+                    nodes.push(Node::For {
+                        name: name.into_iter().next().unwrap(),
+                        path,
+                        body,
+                    });
+                }
+                ["include", name] => {
+                    let name = name
+                        .strip_prefix('"')
+                        .and_then(|name| name.strip_suffix('"'))
+                        .ok_or_else(|| self.error(tag, "include needs a quoted name"))?;
+                    nodes.push(Node::Include(name.to_string()));
+                }
+                ["else"] | ["endif"] | ["endfor"] => {
+                    return Ok((nodes, Some(words[0].to_string())))
+                }
+                _ => return Err(self.error(tag, &format!("unknown tag {{% {} %}}", inner))),
+            }
+        }
+    }
+
+    fn expect(&self, tag: usize, end: Option<String>, expected: &str) -> io::Result<()> {
+        match end {
+            Some(end) if end == expected => Ok(()),
+            Some(end) => Err(self.error(
+                tag,
+                &format!("expected {{% {} %}}, found {{% {} %}}", expected, end),
+            )),
+            None => Err(self.error(tag, &format!("missing {{% {} %}}", expected))),
+        }
+    }
+
+    /// `user.name`を`["user", "name"]`にする。
+    fn path(&self, tag: usize, expr: &str) -> io::Result<Vec<String>> {
+        let path: Vec<String> = expr.split('.').map(str::to_string).collect();
+        let valid = path.iter().all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        });
+        if !valid {
+            return Err(self.error(tag, &format!("invalid name {:?}", expr)));
+        }
+        Ok(path)
+    }
+
+    fn error(&self, at: usize, message: &str) -> io::Error {
+        let line = self.source[..at].matches('\n').count() + 1;
+        invalid(&format!("line {}: {}", line, message))
+    }
+}
+
+/// 描画中の変数。ループ変数は外側の同名の変数を隠します。
+struct Scope<'a> {
+    context: &'a Context,
+    locals: Vec<(String, &'a Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(&self, path: &[String]) -> Option<&'a Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match self.locals.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => *value,
+            None => self.context.values.get(first)?,
+        };
+        for segment in rest {
+            value = match value {
+                Value::Map(map) => map.values.get(segment)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+fn render<'a>(
+    nodes: &[Node],
+    scope: &mut Scope<'a>,
+    out: &mut String,
+    include: &dyn Fn(&str) -> io::Result<Arc<Template>>,
+    depth: usize,
+) -> io::Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { path, raw } => {
+                let text = match scope.lookup(path) {
+                    Some(Value::Text(s)) => s.clone(),
+                    Some(Value::Bool(b)) => b.to_string(),
+                    Some(_) => {
+                        return Err(invalid(&format!("{} is not printable", path.join("."))))
+                    }
+                    None => return Err(invalid(&format!("{} is undefined", path.join(".")))),
+                };
+                if *raw {
+                    out.push_str(&text);
+                } else {
+                    escape_html(&text, out);
+                }
+            }
+            Node::If {
+                path,
+                then,
+                otherwise,
+            } => {
+                // 未定義の変数は偽として扱う
+                let branch = if scope.lookup(path).is_some_and(Value::is_truthy) {
+                    then
+                } else {
+                    otherwise
+                };
+                render(branch, scope, out, include, depth)?;
+            }
+            Node::For { name, path, body } => {
+                let items = match scope.lookup(path) {
+                    Some(Value::List(items)) => items,
+                    Some(_) => return Err(invalid(&format!("{} is not a list", path.join(".")))),
+                    None => return Err(invalid(&format!("{} is undefined", path.join(".")))),
+                };
+                for item in items {
+                    scope.locals.push((name.clone(), item));
+                    let result = render(body, scope, out, include, depth);
+                    scope.locals.pop();
+                    result?;
+                }
+            }
+            Node::Include(name) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(invalid(&format!("includes of {:?} nest too deeply", name)));
+                }
+                let template = include(name)?;
+                render(&template.nodes, scope, out, include, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// HTMLの本文や属性値に埋め込めるよう`s`をエスケープして`out`に足す。
+///
+/// Append `s` to `out`, escaped for HTML text and attribute values.
+pub(crate) fn escape_html(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// ディレクトリのテンプレート。解析結果を保持し、ファイルの更新時刻が
+/// 変わったら解析し直します。
+///
+/// The templates in a directory. Parsed templates are kept and re-parsed
+/// when the file's modification time changes, so edits show up without a
+/// restart.
+pub struct Templates {
+    dir: PathBuf,
+    /// 名前 → 解析したときの更新時刻とテンプレート
+    parsed: Mutex<HashMap<String, Parsed>>,
+}
+
+type Parsed = (Option<SystemTime>, Arc<Template>);
+
+impl Templates {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Templates {
+        Templates {
+            dir: dir.into(),
+            parsed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `name`のテンプレート。`..`や絶対パスは拒否します。
+    ///
+    /// The template called `name`, relative to the directory; `..` and
+    /// absolute paths are rejected.
+    pub fn get(&self, name: &str) -> io::Result<Arc<Template>> {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid template name {:?}", name),
+            ));
+        }
+        let path = self.dir.join(relative);
+        let modified = fs::metadata(&path)?.modified().ok();
+        if let Some((parsed_at, template)) = self.parsed.lock().unwrap().get(name) {
+            if modified.is_some() && *parsed_at == modified {
+                return Ok(Arc::clone(template));
+            }
+        }
+        let source = fs::read_to_string(&path)?;
+        let template = Template::parse(&source)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+        let template = Arc::new(template);
+        self.parsed
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> io::Result<String> {
+        let template = self.get(name)?;
+        let include = |name: &str| self.get(name);
+        let mut out = String::new();
+        let mut scope = Scope {
+            context,
+            locals: Vec::new(),
+        };
+        render(&template.nodes, &mut scope, &mut out, &include, 0)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+        Ok(out)
+    }
+
+    /// 描画したHTMLのレスポンス。描画に失敗したら記録して500を返します。
+    ///
+    /// An HTML response rendered from `name`. Rendering errors are logged
+    /// and answered with a bare 500.
+    pub fn page(&self, status: u16, name: &str, context: &Context) -> Response {
+        match self.render(name, context) {
+            Ok(html) => Response::new(status)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(html),
+            Err(e) => {
+                eprintln!("template: {}", e);
+                Response::new(500)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn renders_variables_conditions_and_loops() {
+        let template = Template::parse(
+            "<h1>{{ title }}</h1>{{ note | raw }}\n\
+             {% for dog in dogs %}<li>{{ dog.name }}{% if dog.good %}!{% endif %}</li>{% endfor %}\
+             {% if missing %}yes{% else %}no{% endif %}",
+        )
+        .unwrap();
+        let context = Context::new()
+            .with("title", "<Dogs & \"cats\">")
+            .with("note", "<br>")
+            .with(
+                "dogs",
+                vec![
+                    Context::new().with("name", "Rex").with("good", true),
+                    Context::new().with("name", "Pochi").with("good", false),
+                ],
+            );
+        assert_eq!(
+            template.render(&context).unwrap(),
+            "<h1>&lt;Dogs &amp; &quot;cats&quot;&gt;</h1><br>\n\
+             <li>Rex!</li><li>Pochi</li>no"
+        );
+        // 未定義の変数を出力しようとするのは誤り
+        assert!(template.render(&Context::new()).is_err());
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_line_numbers() {
+        let error = Template::parse("a\n{% for x in xs %}\n{{ x }}")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "line 2: missing {% endfor %}");
+        let error = Template::parse("{{ a b }}").err().unwrap();
+        assert_eq!(error.to_string(), "line 1: invalid name \"a b\"");
+        assert!(Template::parse("{% endif %}").is_err());
+    }
+
+    #[test]
+    fn includes_and_reloads_templates() {
+        let dir = env::temp_dir().join(format!("templates-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("page.html"), "[{% include \"name.html\" %}]").unwrap();
+        fs::write(dir.join("name.html"), "{{ name }}").unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+        let templates = Templates::new(&dir);
+        let context = Context::new().with("name", "Rex");
+        assert_eq!(templates.render("page.html", &context).unwrap(), "[Rex]");
+
+        // 更新時刻が変われば読み直す
+        fs::write(dir.join("name.html"), "<b>{{ name }}</b>").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(dir.join("name.html"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "[<b>Rex</b>]"
+        );
+
+        assert!(templates.render("loop.html", &context).is_err());
+        assert!(templates.get("../page.html").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
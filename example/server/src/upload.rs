@@ -0,0 +1,653 @@
+//! ファイルのアップロード。
+//!
+//! `PUT /uploads/<name>`でボディをそのまま、`POST /uploads`で
+//! `multipart/form-data`のファイルを保存します。内容はまず同じディレクトリの
+//! 一時ファイルに書き、書き終えてから名前を付けるので、読み手が書きかけの
+//! ファイルを見ることはありません。認証は`Auth`で同じ経路を保護してください。
+//!
+//! サーバーの`stream_body`でこの経路を選ぶと、ボディはメモリに置かず
+//! 接続から一時ファイルへ直接書きます。`max_size`は`Content-Length`で
+//! 読む前に確かめます。
+//!
+//! File uploads: `PUT /uploads/<name>` stores the body as is, and
+//! `POST /uploads` stores the files of a `multipart/form-data` body. Data is
+//! written to a temporary file in the same directory and linked into place
+//! once complete, so readers never see a partial file. Protect the route
+//! with `Auth`.
+//!
+//! When the server's `stream_body` selects these routes, bodies go from the
+//! connection straight to the temporary files instead of memory. `max_size`
+//! is checked against `Content-Length` before anything is read.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::http::{path_has_prefix, Handler, Request, Response};
+use crate::json;
+
+/// 一時ファイルへ書き込む単位。
+const CHUNK: usize = 64 * 1024;
+
+/// `multipart`の部分のヘッダの上限バイト数。
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+#[derive(Serialize)]
+struct Uploaded {
+    files: Vec<String>,
+}
+
+/// 書き終えた一時ファイル。`link`か`replace`で置かなければ消えます。
+struct Staged {
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl Staged {
+    /// 既存のファイルを置き換えずに置く。同じ名前があれば`AlreadyExists`です。
+    /// 確かめてから置くのと違い、同時のアップロードと競合しません。
+    fn link(&mut self) -> io::Result<()> {
+        fs::hard_link(&self.tmp, &self.path)?;
+        fs::remove_file(std::mem::take(&mut self.tmp))
+    }
+
+    /// 既存のファイルがあれば置き換えて置く。
+    fn replace(&mut self) -> io::Result<()> {
+        fs::rename(std::mem::take(&mut self.tmp), &self.path)
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.tmp.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+pub struct Uploads {
+    prefix: String,
+    dir: PathBuf,
+    max_size: usize,
+    extensions: Vec<String>,
+}
+
+impl Uploads {
+    /// `dir`に保存する。ディレクトリは最初のアップロードで作ります。
+    ///
+    /// Store uploads in `dir`, created on the first upload.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Uploads {
+        Uploads {
+            prefix: "/uploads".to_string(),
+            dir: dir.into(),
+            max_size: 10 * 1024 * 1024,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// アップロードを受け付けるパス。既定は`/uploads`です。
+    ///
+    /// Where uploads are accepted. Defaults to `/uploads`.
+    pub fn mount(mut self, prefix: &str) -> Uploads {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// 1リクエストのボディの上限バイト数。既定は10MiBです。
+    /// `Content-Length`が上限を超えていれば、ボディを読まずに413を返します。
+    ///
+    /// The largest request body accepted, checked against `Content-Length`
+    /// before the body is read. Defaults to 10 MiB.
+    pub fn max_size(mut self, bytes: usize) -> Uploads {
+        self.max_size = bytes;
+        self
+    }
+
+    /// 受け付ける拡張子(大文字小文字は区別しない)。指定しなければ何でも受け付けます。
+    ///
+    /// The file extensions accepted, ignoring case. Without any, every
+    /// extension is.
+    pub fn extensions(mut self, extensions: &[&str]) -> Uploads {
+        self.extensions = extensions.iter().map(|e| e.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// `PUT`と`POST`だけを受け持ち、ほかのメソッドは`handler`に渡す。
+    ///
+    /// Answer `PUT` and `POST` under the prefix; other methods, such as
+    /// `GET` for serving the files, go to `handler`.
+    pub fn wrap(self, handler: Handler) -> Handler {
+        let uploads = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let write = request.method == "PUT" || request.method == "POST";
+            if write && path_has_prefix(&request.path, &uploads.prefix) {
+                uploads
+                    .respond(request)
+                    .unwrap_or_else(json::Error::into_response)
+            } else {
+                handler(request)
+            }
+        })
+    }
+
+    fn respond(&self, request: &Request) -> Result<Response, json::Error> {
+        // 大きすぎるボディは読み始める前に断る
+        if request.content_length() > self.max_size {
+            return Err(json::Error::new(
+                413,
+                format!("uploads are limited to {} bytes", self.max_size),
+            ));
+        }
+        // サーバーが読まずに残したボディは接続から、ほかはメモリから読む
+        let body: Box<dyn Read + '_> = match request.take_body_reader() {
+            Some(reader) => Box::new(reader),
+            None => Box::new(&request.body[..]),
+        };
+        let name = request.path[self.prefix.len()..].trim_start_matches('/');
+        match (request.method.as_str(), name) {
+            ("PUT", "") => Err(json::Error::bad_request("missing file name")),
+            ("PUT", name) => self.put(name, body),
+            ("POST", "") => self.post(request, body),
+            _ => Err(json::Error::method_not_allowed(&["GET", "PUT"])),
+        }
+    }
+
+    /// ボディを`name`として保存する。新しく作れば201、置き換えれば204です。
+    fn put(&self, name: &str, mut body: impl Read) -> Result<Response, json::Error> {
+        self.check_name(name)?;
+        let mut staged = self.stage(name, |file| copy(&mut body, file))?;
+        // 先に新しい名前として置いてみれば、作ったか置き換えたかを取り違えない
+        let created = match staged.link() {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                staged.replace().map_err(failed)?;
+                false
+            }
+            Err(e) => return Err(failed(e)),
+        };
+        let location = self.location(name);
+        if created {
+            Ok(json::response(
+                201,
+                &Uploaded {
+                    files: vec![location.clone()],
+                },
+            )
+            .with_header("Location", &location))
+        } else {
+            Ok(Response::new(204))
+        }
+    }
+
+    /// フォームのファイルをすべて保存する。既にある名前とは衝突させず、
+    /// 1つでも受け付けられなければどれも保存しません。
+    fn post(&self, request: &Request, body: impl Read) -> Result<Response, json::Error> {
+        let content_type = request.header("Content-Type").unwrap_or("");
+        let boundary = boundary(content_type)
+            .ok_or_else(|| json::Error::new(415, "expected multipart/form-data"))?;
+        let mut form = Multipart::new(body, boundary);
+        // 最初の区切りより前は読み捨てる
+        form.copy_to_delimiter(&mut io::sink())?;
+        let mut names: Vec<String> = Vec::new();
+        let mut staged = Vec::new();
+        while let Some(head) = form.next_head()? {
+            let name = match filename(&head) {
+                Some(name) => name,
+                // ファイルでないフィールドは読み捨てる
+                None => {
+                    form.copy_to_delimiter(&mut io::sink())?;
+                    continue;
+                }
+            };
+            self.check_name(&name)?;
+            if names.contains(&name) {
+                return Err(json::Error::bad_request(format!("{} appears twice", name)));
+            }
+            staged.push(self.stage(&name, |file| form.copy_to_delimiter(file))?);
+            names.push(name);
+        }
+        if staged.is_empty() {
+            return Err(json::Error::bad_request("no files in the form"));
+        }
+        let mut linked = Vec::new();
+        for file in &mut staged {
+            if let Err(e) = file.link() {
+                // 置いた分を取り除き、どれも保存しなかったことにする
+                for path in linked {
+                    let _ = fs::remove_file(path);
+                }
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(failed(e));
+                }
+                let name = file.path.file_name().unwrap().to_string_lossy();
+                return Err(json::Error::new(409, format!("{} already exists", name)));
+            }
+            linked.push(&file.path);
+        }
+        let files: Vec<String> = names.iter().map(|name| self.location(name)).collect();
+        Ok(json::response(
+            201,
+            &Uploaded {
+                files: files.clone(),
+            },
+        )
+        .with_header("Location", &files[0]))
+    }
+
+    /// 保存してよい名前か。ディレクトリを含む名前や隠しファイルは拒否します。
+    fn check_name(&self, name: &str) -> Result<(), json::Error> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+        if !valid {
+            return Err(json::Error::bad_request(format!(
+                "invalid file name {:?}",
+                name
+            )));
+        }
+        let extension = Path::new(name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if !self.extensions.is_empty() && !self.extensions.contains(&extension) {
+            return Err(json::Error::new(
+                415,
+                format!("only {} files are accepted", self.extensions.join(", ")),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `write`で一時ファイルに書く。名前を付けるのは`Staged::link`か
+    /// `Staged::replace`です。
+    fn stage(
+        &self,
+        name: &str,
+        write: impl FnOnce(&mut File) -> Result<(), json::Error>,
+    ) -> Result<Staged, json::Error> {
+        fs::create_dir_all(&self.dir).map_err(failed)?;
+        let mut random = [0; 8];
+        getrandom::getrandom(&mut random).expect("no system randomness");
+        let suffix: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+        let staged = Staged {
+            tmp: self.dir.join(format!(".{}.{}.tmp", name, suffix)),
+            path: self.dir.join(name),
+        };
+        let mut file: File = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&staged.tmp)
+            .map_err(failed)?;
+        write(&mut file)?;
+        // 名前を付けた後で中身が失われないよう、先に書き出す
+        file.sync_all().map_err(failed)?;
+        Ok(staged)
+    }
+
+    fn location(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+}
+
+fn failed(e: io::Error) -> json::Error {
+    eprintln!("upload: {}", e);
+    json::Error::new(500, "could not store the upload")
+}
+
+/// ボディを読めなかった。クライアントが送り終えずに切断したか、時間切れです。
+fn incomplete(e: io::Error) -> json::Error {
+    eprintln!("upload: reading the body failed: {}", e);
+    json::Error::bad_request("the upload was incomplete")
+}
+
+fn malformed() -> json::Error {
+    json::Error::bad_request("malformed multipart body")
+}
+
+/// `body`を読み終えるまで`file`に書く。
+fn copy(body: &mut impl Read, file: &mut File) -> Result<(), json::Error> {
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        let n = match body.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(incomplete(e)),
+        };
+        file.write_all(&chunk[..n]).map_err(failed)?;
+    }
+}
+
+/// `multipart/form-data; boundary=...`の区切り文字列。
+fn boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+/// `multipart/form-data`のボディを少しずつ読む。中身は区切りが現れるまで
+/// 書き出していくので、手元に置くのは区切りの長さほどです。
+struct Multipart<R> {
+    body: R,
+    buf: Vec<u8>,
+    /// `\r\n--`と境界文字列。
+    delimiter: Vec<u8>,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(body: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            body,
+            // 最初の区切りはボディの先頭にあってもよいので、改行を補っておく
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+        }
+    }
+
+    /// さらに読み込む。ボディが終わっていれば`false`を返す。
+    fn fill(&mut self) -> Result<bool, json::Error> {
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            match self.body.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(incomplete(e)),
+            }
+        }
+    }
+
+    /// 次の区切りまでを`sink`に書き、区切りの後まで進む。
+    fn copy_to_delimiter(&mut self, sink: &mut impl Write) -> Result<(), json::Error> {
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                sink.write_all(&self.buf[..i]).map_err(failed)?;
+                self.buf.drain(..i + self.delimiter.len());
+                return Ok(());
+            }
+            // 区切りの途中かもしれない末尾だけを残して書き出す
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let n = self.buf.len() - keep;
+                sink.write_all(&self.buf[..n]).map_err(failed)?;
+                self.buf.drain(..n);
+            }
+            if !self.fill()? {
+                return Err(malformed());
+            }
+        }
+    }
+
+    /// 区切りの後を読み、次の部分のヘッダを返す。最後の区切りなら`None`です。
+    fn next_head(&mut self) -> Result<Option<String>, json::Error> {
+        loop {
+            if self.buf.starts_with(b"--") {
+                return Ok(None);
+            }
+            if let Some(rest) = self.buf.strip_prefix(b"\r\n") {
+                if let Some(end) = find(rest, b"\r\n\r\n") {
+                    let head = std::str::from_utf8(&rest[..end])
+                        .map_err(|_| malformed())?
+                        .to_string();
+                    self.buf.drain(..2 + end + 4);
+                    return Ok(Some(head));
+                }
+            } else if self.buf.len() >= 2 {
+                return Err(malformed());
+            }
+            if self.buf.len() > MAX_PART_HEAD || !self.fill()? {
+                return Err(malformed());
+            }
+        }
+    }
+}
+
+/// 部分のヘッダの`Content-Disposition`から`filename`を取り出す。
+fn filename(head: &str) -> Option<String> {
+    let disposition = head.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("Content-Disposition")
+            .then_some(value)
+    })?;
+    let filename = disposition.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        (name.trim() == "filename").then(|| value.trim().trim_matches('"'))
+    })?;
+    // ブラウザによってはクライアント側のパスを付けてくる
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    (!filename.is_empty()).then(|| filename.to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn setup(name: &str) -> (PathBuf, Handler) {
+        let dir = env::temp_dir().join(format!("uploads-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let not_found: Handler = Arc::new(|_: &Request| Response::new(404));
+        let handler = Uploads::new(&dir)
+            .max_size(512)
+            .extensions(&["txt", "PNG"])
+            .wrap(not_found);
+        (dir, handler)
+    }
+
+    fn send(
+        handler: &Handler,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Response {
+        let mut raw = format!(
+            "{} {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            method,
+            path,
+            content_type,
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(body);
+        handler(&Request::parse(&raw).unwrap().unwrap().0)
+    }
+
+    /// 1バイトずつしか読めない接続。区切りが読み込みをまたいでも扱えるか確かめる。
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    /// サーバーが`stream_body`で選んだときのように、ボディを読まずに渡す。
+    /// `sent`は`length`より短くてもよい。
+    fn stream(
+        handler: &Handler,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        length: usize,
+        sent: &[u8],
+    ) -> Response {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            method, path, content_type, length
+        );
+        let (mut request, _) = Request::parse_with(raw.as_bytes(), |_| true)
+            .unwrap()
+            .unwrap();
+        request.set_body_reader(Vec::new(), Trickle(io::Cursor::new(sent.to_vec())));
+        handler(&request)
+    }
+
+    /// 一時ファイルが残っていないことも確かめる。
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn put_creates_then_replaces() {
+        let (dir, handler) = setup("put");
+        let response = send(
+            &handler,
+            "PUT",
+            "/uploads/notes.txt",
+            "text/plain",
+            b"hello",
+        );
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/uploads/notes.txt"));
+        let response = send(
+            &handler,
+            "PUT",
+            "/uploads/notes.txt",
+            "text/plain",
+            b"again",
+        );
+        assert_eq!(response.status, 204);
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"again");
+
+        let status =
+            |path: &str, body: &[u8]| send(&handler, "PUT", path, "text/plain", body).status;
+        assert_eq!(status("/uploads/too-big.txt", &[b'x'; 513]), 413);
+        assert_eq!(status("/uploads/script.html", b"<script>"), 415);
+        assert_eq!(status("/uploads/.hidden.txt", b"x"), 400);
+        assert_eq!(status("/uploads/a/b.txt", b"x"), 400);
+        assert_eq!(status("/uploads/..%2Fb.txt", b"x"), 400);
+        assert_eq!(
+            send(&handler, "GET", "/uploads/notes.txt", "", b"").status,
+            404
+        );
+        assert_eq!(files(&dir), ["notes.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn post_stores_every_file_or_none() {
+        let (dir, handler) = setup("post");
+        let form = |files: &[(&str, &str)]| {
+            let mut body = String::from("preamble\r\n");
+            for (filename, data) in files {
+                body.push_str(&format!(
+                    "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                     Content-Type: application/octet-stream\r\n\r\n{}\r\n",
+                    filename, data
+                ));
+            }
+            body.push_str(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--XyZ--\r\n",
+            );
+            body
+        };
+        let post = |body: &str| {
+            send(
+                &handler,
+                "POST",
+                "/uploads",
+                "multipart/form-data; boundary=XyZ",
+                body.as_bytes(),
+            )
+        };
+
+        let response = post(&form(&[
+            ("C:\\Users\\rex\\a.txt", "one\r\n"),
+            ("b.png", "two"),
+        ]));
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/uploads/a.txt"));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"one\r\n");
+        assert_eq!(fs::read(dir.join("b.png")).unwrap(), b"two");
+
+        // 1つでも衝突すれば、ほかのファイルも保存しない
+        assert_eq!(post(&form(&[("c.txt", "3"), ("a.txt", "4")])).status, 409);
+        assert_eq!(post(&form(&[("d.txt", "5"), ("e.exe", "6")])).status, 415);
+        assert_eq!(post("--XyZ\r\nbroken").status, 400);
+        assert_eq!(
+            send(&handler, "POST", "/uploads", "text/plain", b"x").status,
+            415
+        );
+        assert_eq!(files(&dir), ["a.txt", "b.png"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_go_straight_to_disk() {
+        let (dir, handler) = setup("stream");
+        let response = stream(&handler, "PUT", "/uploads/s.txt", "text/plain", 5, b"hello");
+        assert_eq!(response.status, 201);
+        assert_eq!(fs::read(dir.join("s.txt")).unwrap(), b"hello");
+
+        // 上限を超える長さは、ボディを1バイトも送らなくても断る
+        let response = stream(
+            &handler,
+            "PUT",
+            "/uploads/big.txt",
+            "text/plain",
+            1 << 40,
+            b"",
+        );
+        assert_eq!(response.status, 413);
+        // 送り終えずに切断したアップロードは保存しない
+        let response = stream(
+            &handler,
+            "PUT",
+            "/uploads/cut.txt",
+            "text/plain",
+            10,
+            b"half",
+        );
+        assert_eq!(response.status, 400);
+
+        let form =
+            "--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"t.txt\"\r\n\r\n\
+                    a\r\n--Xy\r\nb\r\n--XyZ--\r\n";
+        let response = stream(
+            &handler,
+            "POST",
+            "/uploads",
+            "multipart/form-data; boundary=XyZ",
+            form.len(),
+            form.as_bytes(),
+        );
+        assert_eq!(response.status, 201);
+        assert_eq!(fs::read(dir.join("t.txt")).unwrap(), b"a\r\n--Xy\r\nb");
+        assert_eq!(files(&dir), ["s.txt", "t.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
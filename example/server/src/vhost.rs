@@ -0,0 +1,140 @@
+//! `Host`ヘッダによるバーチャルホスト。
+//!
+//! Virtual hosts: requests are dispatched to a per-site handler chosen by
+//! the `Host` header.
+
+use std::sync::Arc;
+
+use crate::http::{Handler, Request, Response};
+
+/// `Host`ヘッダからポートを除き、小文字にする。
+///
+/// The host name from a `Host` header value, without port and lowercased.
+pub fn host_name(value: &str) -> String {
+    let value = value.trim();
+    let host = if value.starts_with('[') {
+        // IPv6のリテラルは`[::1]:8080`の形
+        match value.find(']') {
+            Some(end) => &value[..=end],
+            None => value,
+        }
+    } else {
+        value.rsplit_once(':').map_or(value, |(host, _)| host)
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// ホスト名ごとのハンドラ。`wrap`で被せたハンドラが既定のホストになります。
+///
+/// Handlers per host name. The handler passed to `wrap` serves the default
+/// host, i.e. every request whose `Host` matches nothing else.
+#[derive(Default)]
+pub struct VirtualHosts {
+    exact: Vec<(String, Handler)>,
+    /// `*.example.com`の`.example.com`
+    wildcard: Vec<(String, Handler)>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// `pattern`に一致するホストを`handler`で扱う。`example.com`のような完全一致と、
+    /// サブドメインに一致する`*.example.com`が使えます。完全一致が優先され、
+    /// ワイルドカード同士では長いものが優先です。
+    ///
+    /// Serve hosts matching `pattern` with `handler`. Patterns are exact
+    /// (`example.com`) or match any subdomain (`*.example.com`, which does
+    /// not match `example.com` itself). Exact names win over wildcards, and
+    /// longer wildcards over shorter ones.
+    pub fn host(mut self, pattern: &str, handler: Handler) -> VirtualHosts {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) => self.wildcard.push((suffix.to_string(), handler)),
+            None => self.exact.push((pattern, handler)),
+        }
+        self
+    }
+
+    /// `Host`に応じてハンドラを選ぶハンドラにする。`Host`の無いHTTP/1.1の
+    /// リクエストには`400 Bad Request`を返します。
+    ///
+    /// Wrap `default` in a handler that dispatches on `Host`. HTTP/1.1
+    /// requests without a `Host` header get `400 Bad Request`; HTTP/1.0
+    /// ones go to the default host.
+    pub fn wrap(self, default: Handler) -> Handler {
+        let hosts = Arc::new(self);
+        Arc::new(move |request: &Request| {
+            let host = match request.header("Host") {
+                Some(value) => host_name(value),
+                None if request.version == "HTTP/1.1" => {
+                    return Response::new(400)
+                        .with_header("Content-Type", "text/plain; charset=utf-8")
+                        .with_body("missing Host header\n");
+                }
+                None => return default(request),
+            };
+            match hosts.find(&host) {
+                Some(handler) => handler(request),
+                None => default(request),
+            }
+        })
+    }
+
+    fn find(&self, host: &str) -> Option<&Handler> {
+        if let Some((_, handler)) = self.exact.iter().find(|(name, _)| name == host) {
+            return Some(handler);
+        }
+        self.wildcard
+            .iter()
+            .filter(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, handler)| handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &'static str) -> Handler {
+        Arc::new(move |_: &Request| Response::new(200).with_body(name))
+    }
+
+    fn request(version: &str, host: Option<&str>) -> Request {
+        let mut raw = format!("GET / {}\r\n", version);
+        if let Some(host) = host {
+            raw.push_str(&format!("Host: {}\r\n", host));
+        }
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn host_name_strips_port() {
+        assert_eq!(host_name("Example.COM:8080"), "example.com");
+        assert_eq!(host_name("example.com."), "example.com");
+        assert_eq!(host_name("[::1]:7878"), "[::1]");
+        assert_eq!(host_name("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn dispatches_by_host() {
+        let handler = VirtualHosts::new()
+            .host("game.example.com", site("game"))
+            .host("*.example.com", site("any"))
+            .host("*.api.example.com", site("api"))
+            .wrap(site("default"));
+        let body = |host| handler(&request("HTTP/1.1", Some(host))).body;
+
+        assert_eq!(body("game.example.com:7878"), b"game");
+        assert_eq!(body("www.example.com"), b"any");
+        assert_eq!(body("v1.api.example.com"), b"api");
+        assert_eq!(body("example.com"), b"default");
+        assert_eq!(body("localhost"), b"default");
+
+        assert_eq!(handler(&request("HTTP/1.1", None)).status, 400);
+        assert_eq!(handler(&request("HTTP/1.0", None)).body, b"default");
+    }
+}
@@ -0,0 +1,78 @@
+//! systemdのソケットアクティベーションと同じ形で、リスニングソケットを
+//! fd 3以降に継承させてサーバーを起動する。
+//!
+//! Spawns the server binary the way systemd socket activation does, with
+//! listening sockets inherited as fd 3 and up.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command, Stdio};
+
+fn health(mut stream: impl Read + Write) -> String {
+    stream.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_inherited_listeners() {
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let path = env::temp_dir().join(format!("activation-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let unix = UnixListener::bind(&path).unwrap();
+    let fds = [tcp.as_raw_fd(), unix.as_raw_fd()];
+    let scores = env::temp_dir().join(format!("activation-{}.jsonl", process::id()));
+
+    let mut command = Command::new("sh");
+    // `exec`してもプロセスIDは変わらないので、シェルの`$$`をLISTEN_PIDにできる。
+    // `--listen`のアドレスには割り当てられていないものを渡し、bindしないことを確かめる
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ LISTEN_FDS=2 exec \"$0\" --listen 192.0.2.1:80")
+        .arg(env!("CARGO_BIN_EXE_example_server"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("LEADERBOARD_FILE", &scores)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(move || {
+            // 移す先の3や4を元のfdが使っていても壊さないよう、一度上へ逃がす
+            let mut high = [0; 2];
+            for (i, &fd) in fds.iter().enumerate() {
+                high[i] = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10);
+                if high[i] < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for (i, &fd) in high.iter().enumerate() {
+                if libc::dup2(fd, 3 + i as i32) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    drop(tcp);
+    drop(unix);
+
+    // ソケットは既に待ち受けているので、起動を待たずに接続できる
+    let response = health(std::net::TcpStream::connect(addr).unwrap());
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let response = health(UnixStream::connect(&path).unwrap());
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    assert!(child.wait().unwrap().success());
+    fs::remove_file(path).unwrap();
+    fs::remove_file(scores).unwrap();
+}